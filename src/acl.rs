@@ -0,0 +1,94 @@
+use pattern::glob_matches;
+use rocket::config::Config;
+use std::fmt;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Publish,
+    Subscribe,
+    // Read - topic configuration and schemas
+    Read,
+    // Admin - changes of the topic configuration, schemas and retention, topic removal
+    Admin,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Action::Publish => "publish",
+            Action::Subscribe => "subscribe",
+            Action::Read => "read",
+            Action::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Grant - actions allowed on the topics matching a glob pattern
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    pub topics: String,
+    pub actions: Vec<Action>,
+}
+
+impl Grant {
+    pub fn allows(&self, action: Action, topic: &str) -> bool {
+        self.actions.contains(&action) && glob_matches(&self.topics, topic)
+    }
+}
+
+// AclRule - grant of the principals whose name matches a glob pattern
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclRule {
+    pub principal: String,
+    pub topics: String,
+    pub actions: Vec<Action>,
+}
+
+// Acl - rules granting topic access to principals, everything not granted is denied. Without rules
+// every principal may do everything
+pub struct Acl {
+    rules: Mutex<Vec<AclRule>>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Self {
+        Acl { rules: Mutex::new(rules) }
+    }
+
+    pub fn configure(&self, rules: Vec<AclRule>) {
+        *self.rules.lock().unwrap() = rules;
+    }
+
+    // from_config - reads acl key, an array of principal, topics and actions tables
+    pub fn from_config(config: &Config) -> Result<Acl, String> {
+        let rules = match config.get_slice("acl") {
+            Ok(rules) => rules.iter()
+                .map(|r| {
+                    let rule: Result<AclRule, _> = r.clone().try_into();
+                    rule.map_err(|e| format!("invalid ACL rule {}: {}", r, e))
+                })
+                .collect::<Result<Vec<AclRule>, String>>()?,
+            Err(_) => vec![]
+        };
+        Ok(Acl::new(rules))
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.rules.lock().unwrap().is_empty()
+    }
+
+    // grants - grants of the principal, None when the ACL is not enabled
+    pub fn grants(&self, principal: &str) -> Option<Vec<Grant>> {
+        let rules = self.rules.lock().unwrap();
+        if rules.is_empty() {
+            return None;
+        }
+        Some(rules.iter()
+            .filter(|r| glob_matches(&r.principal, principal))
+            .map(|r| Grant { topics: r.topics.clone(), actions: r.actions.clone() })
+            .collect())
+    }
+}
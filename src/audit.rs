@@ -0,0 +1,221 @@
+use chrono::prelude::*;
+use errors::PubSubError;
+use rocket::Config;
+use serde_json;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+// AUTH_FAILURE_SYNC_INTERVAL_MS - auth failures are synced at most this often, so unauthenticated
+// requests can't make every caller wait for the disk
+const AUTH_FAILURE_SYNC_INTERVAL_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Subscribe,
+    Unsubscribe,
+    AddPublisher,
+    RemovePublisher,
+    RemoveTopic,
+    CreateApiKey,
+    RevokeApiKey,
+    // AuthFailure - a request without valid credentials or with an invalid publisher token
+    AuthFailure,
+    // AccessDenied - an authenticated principal calling something it is not allowed to
+    AccessDenied,
+    // Eviction - a subscriber removed by the server after a failed delivery
+    Eviction,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            AuditAction::Subscribe => "subscribe",
+            AuditAction::Unsubscribe => "unsubscribe",
+            AuditAction::AddPublisher => "add_publisher",
+            AuditAction::RemovePublisher => "remove_publisher",
+            AuditAction::RemoveTopic => "remove_topic",
+            AuditAction::CreateApiKey => "create_api_key",
+            AuditAction::RevokeApiKey => "revoke_api_key",
+            AuditAction::AuthFailure => "auth_failure",
+            AuditAction::AccessDenied => "access_denied",
+            AuditAction::Eviction => "eviction",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AuditAction, String> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown audit action {}", s))
+    }
+}
+
+// Actor - who caused an event. Events of the server itself, like evictions, have neither a
+// principal nor a source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Actor {
+    pub principal: Option<String>,
+    // source - IP address of the caller
+    pub source: Option<String>,
+}
+
+impl Actor {
+    pub fn new(principal: Option<String>, source: Option<String>) -> Actor {
+        Actor { principal, source }
+    }
+
+    pub fn server() -> Actor {
+        Actor::new(None, None)
+    }
+}
+
+// AuditEvent - a single line of the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub at: DateTime<Local>,
+    pub action: AuditAction,
+    #[serde(default)]
+    pub principal: Option<String>,
+    #[serde(default)]
+    pub source: Option<String>,
+    // target - id of the subscriber or publisher, topic, API key name or the request of auth
+    // failures
+    pub target: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(at: DateTime<Local>, actor: &Actor, action: AuditAction, target: String,
+               detail: Option<String>) -> AuditEvent {
+        AuditEvent {
+            at,
+            action,
+            principal: actor.principal.clone(),
+            source: actor.source.clone(),
+            target,
+            detail,
+        }
+    }
+}
+
+// AuditQuery - filters of the audit log, the most recent limit events matching all of them are
+// returned
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub principal: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.action.map_or(true, |a| a == event.action)
+            && self.principal.as_ref().map_or(true, |p| event.principal.as_ref() == Some(p))
+            && self.since.map_or(true, |since| event.at >= since)
+    }
+}
+
+// AuditConfig - the audit log is written to a file by setting audit_path in Rocket.toml extras or
+// ROCKET_AUDIT_PATH, without it events are only printed
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+}
+
+impl AuditConfig {
+    pub fn from_config(config: &Config) -> Option<AuditConfig> {
+        config.get_str("audit_path").ok().map(|p| AuditConfig { path: PathBuf::from(p) })
+    }
+}
+
+// AuditLog - append-only log of security relevant events, one JSON object per line
+pub struct AuditLog {
+    file: Mutex<Option<AuditFile>>,
+}
+
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    // synced - time of the last sync
+    synced: Instant,
+}
+
+impl AuditFile {
+    // append - syncs every event but auth failures, which are synced with the next event or once
+    // the interval passed. A crash may lose the auth failures of the last interval
+    fn append(&mut self, line: &str, action: AuditAction) -> io::Result<()> {
+        writeln!(self.file, "{}", line)?;
+        let due = self.synced.elapsed() >= Duration::from_millis(AUTH_FAILURE_SYNC_INTERVAL_MS);
+        if action != AuditAction::AuthFailure || due {
+            self.file.sync_data()?;
+            self.synced = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        AuditLog { file: Mutex::new(None) }
+    }
+
+    // open - appends to an existing log, events are never rewritten
+    pub fn open(&self, config: AuditConfig) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        *self.file.lock().unwrap() = Some(AuditFile { path: config.path, file, synced: Instant::now() });
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => return println!("cannot serialize audit event {:?}: {}", event, e)
+        };
+        match *self.file.lock().unwrap() {
+            Some(ref mut file) => if let Err(e) = file.append(&line, event.action) {
+                println!("failed to append to audit log: {}", e);
+            },
+            None => println!("audit: {}", line)
+        }
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, PubSubError> {
+        let path = match *self.file.lock().unwrap() {
+            Some(ref file) => file.path.clone(),
+            None => return Err(PubSubError::AuditLogDisabled)
+        };
+        let failed = |e: io::Error| PubSubError::AuditLogFailed(format!("{}", e));
+
+        let mut events = vec![];
+        for (n, line) in BufReader::new(File::open(&path).map_err(failed)?).lines().enumerate() {
+            let line = line.map_err(failed)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditEvent>(&line) {
+                Ok(ref event) if query.matches(event) => events.push(event.clone()),
+                Ok(_) => (),
+                Err(e) => println!("skipping line {} of audit log: {}", n + 1, e)
+            }
+        }
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+        let skipped = events.len().saturating_sub(limit);
+        Ok(events.split_off(skipped))
+    }
+}
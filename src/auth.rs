@@ -0,0 +1,328 @@
+use acl::{Acl, Action, Grant};
+use audit::{Actor, AuditAction};
+use errors::PubSubError;
+use jwt::{is_jwt, JwtKeys};
+use rocket::config::Config;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use serde_json;
+use server::PubSubServer;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use super::headers::AUTHORIZATION_HEADER;
+use uuid::Uuid;
+
+const BEARER: &str = "Bearer ";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    // name - principal the key authenticates
+    pub name: String,
+    pub key: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+// StoredKey - an API key as kept in memory and in api_keys_file, with the hash of the secret only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKey {
+    name: String,
+    key_hash: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+impl StoredKey {
+    fn new(key: &ApiKey) -> StoredKey {
+        StoredKey { name: key.name.clone(), key_hash: hash_token(&key.key), admin: key.admin }
+    }
+}
+
+// KeyView - an API key without the secret
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyView {
+    pub name: String,
+    pub admin: bool,
+}
+
+// Principal - authenticated caller of a request
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub admin: bool,
+    // grants - topic access of a non-admin principal, None when the ACL is not enabled
+    pub grants: Option<Vec<Grant>>,
+    // source - IP address the request came from
+    pub source: Option<String>,
+    // authenticated - false for the anonymous caller when authentication is not enabled
+    #[serde(skip)]
+    pub authenticated: bool,
+}
+
+impl Principal {
+    pub fn new(name: String, admin: bool) -> Principal {
+        Principal { name, admin, grants: None, source: None, authenticated: true }
+    }
+
+    // anonymous - caller when no API keys are configured, all topics are allowed but no
+    // administrative endpoints
+    pub fn anonymous() -> Principal {
+        Principal { authenticated: false, ..Principal::new("anonymous".to_string(), false) }
+    }
+
+    pub fn allows(&self, action: Action, topic: &str) -> bool {
+        if self.admin {
+            return true;
+        }
+        match self.grants {
+            Some(ref grants) => grants.iter().any(|g| g.allows(action, topic)),
+            None => true
+        }
+    }
+
+    // may_bridge - whether the caller may deliver messages of the bridge, a remote hub
+    // authenticates with a key named after its instance
+    pub fn may_bridge(&self, bridge: &str) -> bool {
+        !self.authenticated || self.admin || self.name == bridge
+    }
+
+    pub fn actor(&self) -> Actor {
+        Actor::new(Some(self.name.clone()), self.source.clone())
+    }
+}
+
+// ApiKeys - hashed keys. Authentication is enabled as soon as there is a key
+pub struct ApiKeys {
+    keys: Mutex<Vec<StoredKey>>,
+    // configured - names of the api_keys keys, they are changed in the configuration only
+    configured: Vec<String>,
+    // file - where keys created through the API are kept, None keeps them in memory
+    file: Option<PathBuf>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        ApiKeys {
+            configured: keys.iter().map(|k| k.name.clone()).collect(),
+            keys: Mutex::new(keys.iter().map(StoredKey::new).collect()),
+            file: None,
+        }
+    }
+
+    // with_file - keeps keys created through the API in the file, loading the ones it has already
+    pub fn with_file(self, file: PathBuf) -> Result<ApiKeys, String> {
+        if file.exists() {
+            let content = fs::read(&file).map_err(|e| format!("cannot read {:?}: {}", file, e))?;
+            let managed: Vec<StoredKey> = serde_json::from_slice(&content)
+                .map_err(|e| format!("invalid API keys in {:?}: {}", file, e))?;
+            let mut keys = self.keys.lock().unwrap();
+            for key in managed {
+                if keys.iter().any(|k| k.name == key.name) {
+                    return Err(format!("API key {} of {:?} is configured in api_keys too", key.name, file));
+                }
+                keys.push(key);
+            }
+        }
+        Ok(ApiKeys { file: Some(file), ..self })
+    }
+
+    // from_config - reads api_keys key, an array of name, key and admin tables, and api_keys_file,
+    // the file keeping keys created through the API
+    pub fn from_config(config: &Config) -> Result<ApiKeys, String> {
+        let keys = match config.get_slice("api_keys") {
+            Ok(keys) => keys.iter()
+                .map(|k| {
+                    let key: Result<ApiKey, _> = k.clone().try_into();
+                    key.map_err(|e| format!("invalid API key: {}", e))
+                })
+                .collect::<Result<Vec<ApiKey>, String>>()?,
+            Err(_) => vec![]
+        };
+        match config.get_str("api_keys_file") {
+            Ok(file) => ApiKeys::new(keys).with_file(PathBuf::from(file)),
+            Err(_) => Ok(ApiKeys::new(keys))
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.lock().unwrap().is_empty()
+    }
+
+    // authenticate - principal of the API key. Its hash is compared with every key, so the time
+    // taken doesn't depend on which key matches
+    pub fn authenticate(&self, key: &str) -> Result<Principal, PubSubError> {
+        let hash = hash_token(key);
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .fold(None, |found, k| if constant_time_eq(k.key_hash.as_bytes(), hash.as_bytes()) { Some(k) } else { found })
+            .map(|k| Principal::new(k.name.clone(), k.admin))
+            .ok_or(PubSubError::Unauthorized("invalid API key".to_string()))
+    }
+
+    // create - generates a new key for the name. The first key enables authentication, so it must
+    // be an admin key
+    pub fn create(&self, name: String, admin: bool) -> Result<ApiKey, PubSubError> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.iter().any(|k| k.name == name) {
+            return Err(PubSubError::ApiKeyExists(name));
+        }
+        if keys.is_empty() && !admin {
+            return Err(PubSubError::Forbidden("the first API key must be an admin key".to_string()));
+        }
+        let key = ApiKey { name, key: format!("{}", Uuid::new_v4().simple()), admin };
+        keys.push(StoredKey::new(&key));
+        if let Err(e) = self.save(&keys) {
+            keys.pop();
+            return Err(e);
+        }
+        Ok(key)
+    }
+
+    // revoke - removes a key created through the API, keys of api_keys are removed from the
+    // configuration
+    pub fn revoke(&self, name: &str) -> Result<(), PubSubError> {
+        if self.configured.iter().any(|n| n == name) {
+            return Err(PubSubError::Forbidden(format!("API key {} is configured in api_keys", name)));
+        }
+        let mut keys = self.keys.lock().unwrap();
+        let revoked: Vec<StoredKey> = keys.iter().filter(|k| k.name == name).cloned().collect();
+        if revoked.is_empty() {
+            return Err(PubSubError::UnknownApiKey(name.to_string()));
+        }
+        keys.retain(|k| k.name != name);
+        if let Err(e) = self.save(&keys) {
+            keys.extend(revoked);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // save - writes the hashes of keys created through the API to the file, replacing it at once
+    fn save(&self, keys: &Vec<StoredKey>) -> Result<(), PubSubError> {
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return Ok(())
+        };
+        let mut managed: Vec<&StoredKey> = keys.iter()
+            .filter(|k| !self.configured.contains(&k.name))
+            .collect();
+        managed.sort_by(|a, b| a.name.cmp(&b.name));
+        let content = serde_json::to_vec_pretty(&managed).map_err(|e| PubSubError::StorageFailed(format!("{}", e)))?;
+        let tmp = file.with_extension("tmp");
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, file))
+            .map_err(|e| PubSubError::StorageFailed(format!("cannot write {:?}: {}", file, e)))
+    }
+
+    pub fn list(&self) -> Vec<KeyView> {
+        let mut keys: Vec<KeyView> = self.keys.lock().unwrap().iter()
+            .map(|k| KeyView { name: k.name.clone(), admin: k.admin })
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+}
+
+// constant_time_eq - compares secrets without leaking the length of the matching prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// hash_token - SHA-256 of a publisher token or API key in hex, only hashes are stored and
+// replicated. Generated secrets are random, so they need no salt
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// bearer_token - token of the Authorization header value
+fn bearer_token(authorization: Option<&str>) -> Result<&str, PubSubError> {
+    let authorization = authorization
+        .ok_or(PubSubError::Unauthorized("missing bearer token".to_string()))?;
+    match authorization.get(..BEARER.len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(BEARER) => Ok(authorization[BEARER.len()..].trim()),
+        _ => Err(PubSubError::Unauthorized("expected a bearer token".to_string()))
+    }
+}
+
+fn source(request: &Request) -> Option<String> {
+    request.remote().map(|a| format!("{}", a.ip()))
+}
+
+// audit - records an auth event of the request in the audit log of the server
+pub fn audit(request: &Request, actor: &Actor, action: AuditAction, detail: String) {
+    if let Outcome::Success(server) = request.guard::<State<PubSubServer>>() {
+        server.audit(actor, action, format!("{} {}", request.method(), request.uri()), Some(detail));
+    }
+}
+
+// authenticate - principal of the request, JWTs are verified by the key set and anything else is
+// looked up as an API key. Principals without topic claims get the grants of the ACL
+fn authenticate(request: &Request) -> Result<Principal, PubSubError> {
+    let keys = request.guard::<State<ApiKeys>>().succeeded();
+    let jwt_keys = request.guard::<State<JwtKeys>>().succeeded();
+    let keys_enabled = keys.as_ref().map_or(false, |k| k.enabled());
+    let jwt_enabled = jwt_keys.as_ref().map_or(false, |k| k.enabled());
+    if !keys_enabled && !jwt_enabled {
+        return Ok(Principal { source: source(request), ..Principal::anonymous() });
+    }
+
+    let token = bearer_token(request.headers().get_one(AUTHORIZATION_HEADER))?;
+    let mut principal = match (jwt_keys, keys) {
+        (Some(ref jwt_keys), _) if jwt_enabled && is_jwt(token) => jwt_keys.authenticate(token)?,
+        (_, Some(ref keys)) if keys_enabled => keys.authenticate(token)?,
+        _ => return Err(PubSubError::Unauthorized("expected a JSON web token".to_string()))
+    };
+    principal.source = source(request);
+    if principal.grants.is_none() {
+        if let Outcome::Success(acl) = request.guard::<State<Acl>>() {
+            principal.grants = acl.grants(&principal.name);
+        }
+    }
+    Ok(principal)
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Principal {
+    type Error = PubSubError;
+
+    // from_request - the result is cached in the request, so guards built on it like Admin don't
+    // authenticate again and a failure is audited once
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Principal, PubSubError> {
+        let authenticated = request.local_cache(|| {
+            authenticate(request).map_err(|e| {
+                audit(request, &Actor::new(None, source(request)), AuditAction::AuthFailure, format!("{}", e));
+                e
+            })
+        });
+        match *authenticated {
+            Ok(ref principal) => Outcome::Success(principal.clone()),
+            Err(ref e) => Outcome::Failure((Status::Unauthorized, e.clone()))
+        }
+    }
+}
+
+// Admin - principal allowed to call administrative endpoints
+pub struct Admin(pub Principal);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = PubSubError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, PubSubError> {
+        match request.guard::<Principal>() {
+            Outcome::Success(ref p) if !p.authenticated => {
+                audit(request, &p.actor(), AuditAction::AccessDenied, "authentication is not enabled".to_string());
+                Outcome::Failure((Status::Forbidden, PubSubError::Forbidden(
+                    "administrative endpoints need an admin key, configure api_keys or jwt_keys".to_string())))
+            }
+            Outcome::Success(ref p) if p.admin => Outcome::Success(Admin(p.clone())),
+            Outcome::Success(p) => {
+                audit(request, &p.actor(), AuditAction::AccessDenied, "not an admin".to_string());
+                Outcome::Failure((Status::Forbidden, PubSubError::Forbidden(format!("{} is not an admin", p.name))))
+            }
+            Outcome::Failure(f) => Outcome::Failure(f),
+            Outcome::Forward(f) => Outcome::Forward(f)
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use chrono::Duration;
+use chrono::prelude::*;
+use models::{Batch, BatchEntry, Subscriber};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+struct Pending {
+    subscriber: Subscriber,
+    batch: Batch,
+    started: DateTime<Local>,
+}
+
+impl Pending {
+    fn due(&self) -> DateTime<Local> {
+        let window = self.subscriber.batching.map_or(0, |b| b.window_ms);
+        self.started + Duration::milliseconds(window as i64)
+    }
+}
+
+// Batcher - accumulates deliveries of subscribers which opted in to batched delivery
+pub struct Batcher {
+    pending: Mutex<HashMap<Uuid, Pending>>,
+}
+
+impl Batcher {
+    pub fn new() -> Self {
+        Batcher { pending: Mutex::new(HashMap::new()) }
+    }
+
+    // add - appends an entry to the subscriber's batch and returns the batch once it is full
+    pub fn add(&self, sub: &Subscriber, entry: BatchEntry, now: DateTime<Local>) -> Option<Batch> {
+        let max_size = sub.batching.map_or(1, |b| b.max_size);
+        let mut pending = self.pending.lock().unwrap();
+        let full = {
+            let p = pending.entry(sub.id).or_insert_with(|| Pending {
+                subscriber: sub.clone(),
+                batch: Batch::new(sub.topic.clone()),
+                started: now,
+            });
+            p.batch.entries.push(entry);
+            p.batch.entries.len() >= max_size
+        };
+
+        if full { pending.remove(&sub.id).map(|p| p.batch) } else { None }
+    }
+
+    // next_due - time the earliest open batch has to be sent by
+    pub fn next_due(&self) -> Option<DateTime<Local>> {
+        self.pending.lock().unwrap().values().map(|p| p.due()).min()
+    }
+
+    // take_due - returns batches which have been open longer than their subscriber's window
+    pub fn take_due(&self, now: &DateTime<Local>) -> Vec<(Subscriber, Batch)> {
+        let mut pending = self.pending.lock().unwrap();
+        let ids: Vec<Uuid> = pending.iter()
+            .filter(|&(_, p)| p.due() <= *now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| pending.remove(id))
+            .map(|p| (p.subscriber, p.batch))
+            .collect()
+    }
+
+    pub fn take(&self, subscriber: &Uuid) -> Option<Batch> {
+        self.pending.lock().unwrap().remove(subscriber).map(|p| p.batch)
+    }
+
+    pub fn discard(&self, subscriber: &Uuid) {
+        self.pending.lock().unwrap().remove(subscriber);
+    }
+}
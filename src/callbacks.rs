@@ -0,0 +1,135 @@
+use pattern::glob_matches;
+use rocket::Config;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use url::{Host, Url};
+
+const DEFAULT_SCHEMES: &[&str] = &["http", "https"];
+
+// CallbackPolicy - subscriber callbacks the server is willing to call, checked on subscribe and
+// again before every delivery, as a host name may resolve to other addresses by then.
+// Configured via Rocket.toml extras or ROCKET_* environment variables like Limits
+#[derive(Debug, Clone)]
+pub struct CallbackPolicy {
+    pub schemes: Vec<String>,
+    // allowed_hosts - glob patterns, when set only matching hosts are accepted. Listed hosts are
+    // trusted, their addresses are not checked at all, loopback and link-local ones included
+    pub allowed_hosts: Vec<String>,
+    // denied_hosts - glob patterns of hosts never called
+    pub denied_hosts: Vec<String>,
+    // allow_private - accepts hosts with loopback, link-local or private addresses
+    pub allow_private: bool,
+}
+
+impl Default for CallbackPolicy {
+    fn default() -> Self {
+        CallbackPolicy {
+            schemes: DEFAULT_SCHEMES.iter().map(|s| s.to_string()).collect(),
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            allow_private: false,
+        }
+    }
+}
+
+impl CallbackPolicy {
+    // from_config - reads callback_schemes, callback_allowed_hosts, callback_denied_hosts and
+    // callback_allow_private keys
+    pub fn from_config(config: &Config) -> CallbackPolicy {
+        let default = CallbackPolicy::default();
+        CallbackPolicy {
+            schemes: strings(config, "callback_schemes").unwrap_or(default.schemes),
+            allowed_hosts: strings(config, "callback_allowed_hosts").unwrap_or(default.allowed_hosts),
+            denied_hosts: strings(config, "callback_denied_hosts").unwrap_or(default.denied_hosts),
+            allow_private: config.get_bool("callback_allow_private").unwrap_or(default.allow_private),
+        }
+    }
+
+    // check - reason the callback is rejected. Host names are resolved, a name with any internal
+    // address is rejected, so is one that doesn't resolve
+    pub fn check(&self, callback: &str) -> Result<(), String> {
+        self.resolve(callback).map(|_| ())
+    }
+
+    // resolve - address that passed the check, the callback is to be called there rather than
+    // resolving its host again. None when the host is called by name, its addresses aren't checked
+    pub fn resolve(&self, callback: &str) -> Result<Option<IpAddr>, String> {
+        let url = Url::parse(callback).map_err(|e| format!("not an absolute URL: {}", e))?;
+        if !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+        let host = match url.host() {
+            Some(host) => host,
+            None => return Err("host is missing".to_string())
+        };
+        let name = url.host_str().unwrap_or("").trim_matches(|c| c == '[' || c == ']').to_lowercase();
+        if self.denied_hosts.iter().any(|p| glob_matches(p, &name)) {
+            return Err(format!("host {} is denied", name));
+        }
+        if !self.allowed_hosts.is_empty() {
+            return if self.allowed_hosts.iter().any(|p| glob_matches(p, &name)) {
+                Ok(None)
+            } else {
+                Err(format!("host {} is not allowed", name))
+            };
+        }
+        if self.allow_private {
+            return Ok(None);
+        }
+
+        let addresses = match host {
+            Host::Ipv4(ip) => vec![IpAddr::V4(ip)],
+            Host::Ipv6(ip) => vec![IpAddr::V6(ip)],
+            Host::Domain(domain) => {
+                if domain == "localhost" || domain.ends_with(".localhost") {
+                    return Err(format!("host {} is a loopback address", name));
+                }
+                resolve(domain, url.port_or_known_default().unwrap_or(80))
+                    .map_err(|e| format!("host {} cannot be resolved: {}", name, e))?
+            }
+        };
+        match addresses.iter().find(|ip| is_internal(ip)) {
+            Some(ip) => Err(format!("host {} has the internal address {}", name, ip)),
+            None => Ok(Some(addresses[0]))
+        }
+    }
+}
+
+fn strings(config: &Config, key: &str) -> Option<Vec<String>> {
+    config.get_slice(key).ok()
+        .map(|values| values.iter().filter_map(|v| v.as_str().map(|s| s.to_lowercase())).collect())
+}
+
+fn resolve(domain: &str, port: u16) -> Result<Vec<IpAddr>, String> {
+    let addresses: Vec<IpAddr> = (domain, port).to_socket_addrs()
+        .map_err(|e| format!("{}", e))?
+        .map(|a| a.ip())
+        .collect();
+    if addresses.is_empty() {
+        return Err("no addresses".to_string());
+    }
+    Ok(addresses)
+}
+
+// is_internal - loopback, private, link-local, shared and unspecified addresses, including IPv4
+// addresses embedded in IPv6 ones
+fn is_internal(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => is_internal_v4(ip),
+        IpAddr::V6(ref ip) => {
+            if let Some(v4) = ip.to_ipv4() {
+                return is_internal_v4(&v4);
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified()
+                // fe80::/10 link-local, fc00::/7 unique local
+                || first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00
+        }
+    }
+}
+
+fn is_internal_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() || ip.is_unspecified()
+        // 0.0.0.0/8 this network, 100.64.0.0/10 shared address space
+        || octets[0] == 0 || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+}
@@ -0,0 +1,13 @@
+use chrono::prelude::*;
+
+pub trait Clock {
+    fn now(&self) -> DateTime<Local>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
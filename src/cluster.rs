@@ -0,0 +1,156 @@
+use downcast_rs::Downcast;
+use http::HttpClient;
+use hyper::method::Method;
+use rocket::config::Config;
+use std::time::Duration;
+use super::headers::{AUTHORIZATION_HEADER, PUBLISHER_TOKEN_HEADER};
+use uuid::Uuid;
+
+// VIRTUAL_NODES - points of every node on the ring, spreads topics evenly over few nodes
+const VIRTUAL_NODES: usize = 64;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub name: String,
+    // url - where the node is reachable, e.g. http://127.0.0.1:8001
+    pub url: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    // node - name of this node, must be one of the nodes
+    pub node: String,
+    pub nodes: Vec<Node>,
+    // api_key - admin API key of the other nodes, sent when they have authentication enabled
+    pub api_key: Option<String>,
+}
+
+impl ClusterConfig {
+    // from_config - reads cluster_node, cluster_nodes (array of name and url tables) and
+    // cluster_api_key keys, None when cluster_nodes is not set
+    pub fn from_config(config: &Config) -> Result<Option<ClusterConfig>, String> {
+        let nodes = match config.get_slice("cluster_nodes") {
+            Ok(nodes) => nodes.iter()
+                .map(|n| {
+                    let node: Result<Node, _> = n.clone().try_into();
+                    node.map_err(|e| format!("invalid cluster node {}: {}", n, e))
+                })
+                .collect::<Result<Vec<Node>, String>>()?,
+            Err(_) => return Ok(None)
+        };
+        let node = config.get_str("cluster_node")
+            .map(|n| n.to_string())
+            .map_err(|_| "cluster_node must be set together with cluster_nodes".to_string())?;
+        let api_key = config.get_str("cluster_api_key").ok().map(|k| k.to_string());
+        Ok(Some(ClusterConfig { node, nodes, api_key }))
+    }
+}
+
+// Cluster - static membership and the consistent hashing ring assigning every topic to one node
+pub struct Cluster {
+    local: String,
+    nodes: Vec<Node>,
+    api_key: Option<String>,
+    // ring - points sorted by hash, each pointing to a node
+    ring: Vec<(u64, usize)>,
+}
+
+impl Cluster {
+    pub fn new(config: ClusterConfig) -> Result<Cluster, String> {
+        if !config.nodes.iter().any(|n| n.name == config.node) {
+            return Err(format!("cluster_node {} is not one of cluster_nodes", config.node));
+        }
+        let mut ring: Vec<(u64, usize)> = config.nodes.iter()
+            .enumerate()
+            .flat_map(|(i, n)| (0..VIRTUAL_NODES).map(move |v| (fnv1a(format!("{}#{}", n.name, v).as_bytes()), i)))
+            .collect();
+        ring.sort();
+        Ok(Cluster { local: config.node, nodes: config.nodes, api_key: config.api_key, ring })
+    }
+
+    // owner - node owning the key, a topic or the id of a subscriber
+    pub fn owner(&self, key: &str) -> &Node {
+        let hash = fnv1a(key.as_bytes());
+        let point = match self.ring.binary_search_by(|&(h, _)| h.cmp(&hash)) {
+            Ok(i) => i,
+            Err(i) if i == self.ring.len() => 0,
+            Err(i) => i
+        };
+        &self.nodes[self.ring[point].1]
+    }
+
+    pub fn is_local(&self, node: &Node) -> bool {
+        node.name == self.local
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    // others - all nodes but this one
+    pub fn others(&self) -> Vec<Node> {
+        self.nodes.iter().filter(|n| !self.is_local(n)).cloned().collect()
+    }
+
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key.clone()
+    }
+}
+
+// Nodes - calls to the other nodes of the cluster. Publishers are registered on every node, so
+// they can publish to topics of any node with the same token
+pub trait Nodes: Downcast {
+    // add_publisher - registers the publisher with its token on the node
+    fn add_publisher(&self, node: &Node, api_key: Option<&String>, id: &Uuid, token: &String) -> Result<(), String>;
+
+    // remove_publisher - removes the publisher with its retained messages from the node
+    fn remove_publisher(&self, node: &Node, api_key: Option<&String>, id: &Uuid) -> Result<(), String>;
+
+    // clear_publisher_token - undoes a token issued to a publisher registered without a token
+    fn clear_publisher_token(&self, node: &Node, api_key: Option<&String>, id: &Uuid) -> Result<(), String>;
+}
+
+impl_downcast!(Nodes);
+
+// NodeService - calls the other nodes over HTTP
+pub struct NodeService {
+    client: HttpClient,
+}
+
+impl Nodes for NodeService {
+    fn add_publisher(&self, node: &Node, api_key: Option<&String>, id: &Uuid, token: &String) -> Result<(), String> {
+        let mut headers = vec![(PUBLISHER_TOKEN_HEADER, token.clone())];
+        headers.extend(bearer(api_key));
+        self.client.send(Method::Put, &publisher_url(node, id), headers, &[]).map(|_| ())
+    }
+
+    fn remove_publisher(&self, node: &Node, api_key: Option<&String>, id: &Uuid) -> Result<(), String> {
+        self.client.send(Method::Delete, &publisher_url(node, id), bearer(api_key).into_iter().collect(), &[])
+            .map(|_| ())
+    }
+
+    fn clear_publisher_token(&self, node: &Node, api_key: Option<&String>, id: &Uuid) -> Result<(), String> {
+        let url = format!("{}/token", publisher_url(node, id));
+        self.client.send(Method::Delete, &url, bearer(api_key).into_iter().collect(), &[]).map(|_| ())
+    }
+}
+
+impl NodeService {
+    pub fn new() -> Self {
+        NodeService { client: HttpClient::new(Duration::from_secs(REQUEST_TIMEOUT_SECS)) }
+    }
+}
+
+fn publisher_url(node: &Node, id: &Uuid) -> String {
+    format!("{}/info/cluster/publisher/{}", node.url, id.hyphenated())
+}
+
+fn bearer(api_key: Option<&String>) -> Option<(&'static str, String)> {
+    api_key.map(|key| (AUTHORIZATION_HEADER, format!("Bearer {}", key)))
+}
+
+// fnv1a - 64-bit FNV-1a, stable across processes and builds unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,284 @@
+extern crate rocket;
+extern crate rocket_contrib;
+
+use models::{Subject, Topic};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::response::status;
+use self::rocket_contrib::Json;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PubSubError {
+    UnknownPublisher(Uuid),
+    PublisherExists(Uuid),
+    UnknownSubscriber(Uuid),
+    UnknownTopic(Topic),
+    UnknownSubject { topic: Topic, subject: Subject },
+    UnknownScheduled(Uuid),
+    UnknownSchema { topic: Topic, version: Option<u32> },
+    PublisherNotAllowed { publisher: Uuid, topic: Topic },
+    SubjectLimitReached { topic: Topic, limit: usize },
+    MessageTooLarge { size: usize, limit: usize },
+    InvalidTopicConfig(String),
+    ScheduleInPast(String),
+    InvalidPatch(String),
+    InvalidSchema(String),
+    SchemaViolation { topic: Topic, violations: Vec<String> },
+    // request validation errors, see rest.rs
+    PayloadTooLarge { limit: u64 },
+    TooManyHeaders { count: usize, limit: usize },
+    HeadersTooLarge { size: usize, limit: usize },
+    InvalidName { field: &'static str, value: String, reason: String },
+    InvalidHeader { name: &'static str, reason: String },
+    MissingHeader(&'static str),
+    InvalidCallback { callback: String, reason: String },
+    MalformedBody(String),
+    InvalidFilter(String),
+    SnapshotFailed(String),
+    SnapshotDirDisabled,
+    WalFailed(String),
+    StorageFailed(String),
+    ClusterFailed(String),
+    ReadOnlyReplica,
+    NotReplica,
+    UnknownBridge(String),
+    BridgeNotAllowed { bridge: String, topic: Topic },
+    WrongNode { node: String, location: String },
+    TopicsOnSeveralNodes(Vec<Topic>),
+    Unauthorized(String),
+    Forbidden(String),
+    ApiKeyExists(String),
+    UnknownApiKey(String),
+    InvalidPublisherToken(Uuid),
+    AuditLogDisabled,
+    AuditLogFailed(String),
+    InvalidAuditQuery(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    violations: Vec<String>,
+}
+
+impl PubSubError {
+    pub fn status(&self) -> Status {
+        match *self {
+            PubSubError::UnknownPublisher(_) |
+            PubSubError::UnknownSubscriber(_) |
+            PubSubError::UnknownTopic(_) |
+            PubSubError::UnknownSubject { .. } |
+            PubSubError::UnknownScheduled(_) |
+            PubSubError::UnknownSchema { .. } |
+            PubSubError::UnknownBridge(_) |
+            PubSubError::UnknownApiKey(_) => Status::NotFound,
+            PubSubError::PublisherExists(_) |
+            PubSubError::SubjectLimitReached { .. } |
+            PubSubError::NotReplica |
+            PubSubError::ApiKeyExists(_) |
+            PubSubError::SnapshotDirDisabled |
+            PubSubError::AuditLogDisabled => Status::Conflict,
+            PubSubError::PublisherNotAllowed { .. } |
+            PubSubError::BridgeNotAllowed { .. } |
+            PubSubError::Forbidden(_) |
+            PubSubError::InvalidPublisherToken(_) => Status::Forbidden,
+            PubSubError::Unauthorized(_) => Status::Unauthorized,
+            PubSubError::MessageTooLarge { .. } |
+            PubSubError::PayloadTooLarge { .. } => Status::PayloadTooLarge,
+            PubSubError::InvalidTopicConfig(_) |
+            PubSubError::ScheduleInPast(_) |
+            PubSubError::InvalidPatch(_) |
+            PubSubError::InvalidSchema(_) |
+            PubSubError::SchemaViolation { .. } |
+            PubSubError::TopicsOnSeveralNodes(_) => Status::UnprocessableEntity,
+            PubSubError::TooManyHeaders { .. } |
+            PubSubError::HeadersTooLarge { .. } |
+            PubSubError::InvalidName { .. } |
+            PubSubError::InvalidHeader { .. } |
+            PubSubError::MissingHeader(_) |
+            PubSubError::InvalidCallback { .. } |
+            PubSubError::MalformedBody(_) |
+            PubSubError::InvalidFilter(_) |
+            PubSubError::InvalidAuditQuery(_) => Status::BadRequest,
+            PubSubError::SnapshotFailed(_) |
+            PubSubError::WalFailed(_) |
+            PubSubError::StorageFailed(_) |
+            PubSubError::AuditLogFailed(_) => Status::InternalServerError,
+            PubSubError::ClusterFailed(_) => Status::BadGateway,
+            PubSubError::ReadOnlyReplica => Status::ServiceUnavailable,
+            PubSubError::WrongNode { .. } => Status::TemporaryRedirect,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            PubSubError::UnknownPublisher(_) => "unknown_publisher",
+            PubSubError::PublisherExists(_) => "publisher_exists",
+            PubSubError::UnknownSubscriber(_) => "unknown_subscriber",
+            PubSubError::UnknownTopic(_) => "unknown_topic",
+            PubSubError::UnknownSubject { .. } => "unknown_subject",
+            PubSubError::UnknownScheduled(_) => "unknown_scheduled",
+            PubSubError::UnknownSchema { .. } => "unknown_schema",
+            PubSubError::PublisherNotAllowed { .. } => "publisher_not_allowed",
+            PubSubError::SubjectLimitReached { .. } => "subject_limit_reached",
+            PubSubError::MessageTooLarge { .. } => "message_too_large",
+            PubSubError::InvalidTopicConfig(_) => "invalid_topic_config",
+            PubSubError::ScheduleInPast(_) => "schedule_in_past",
+            PubSubError::InvalidPatch(_) => "invalid_patch",
+            PubSubError::InvalidSchema(_) => "invalid_schema",
+            PubSubError::SchemaViolation { .. } => "schema_violation",
+            PubSubError::PayloadTooLarge { .. } => "payload_too_large",
+            PubSubError::TooManyHeaders { .. } => "too_many_headers",
+            PubSubError::HeadersTooLarge { .. } => "headers_too_large",
+            PubSubError::InvalidName { .. } => "invalid_name",
+            PubSubError::InvalidHeader { .. } => "invalid_header",
+            PubSubError::InvalidCallback { .. } => "invalid_callback",
+            PubSubError::MissingHeader(_) => "missing_header",
+            PubSubError::MalformedBody(_) => "malformed_body",
+            PubSubError::InvalidFilter(_) => "invalid_filter",
+            PubSubError::SnapshotFailed(_) => "snapshot_failed",
+            PubSubError::SnapshotDirDisabled => "snapshot_dir_disabled",
+            PubSubError::WalFailed(_) => "wal_failed",
+            PubSubError::StorageFailed(_) => "storage_failed",
+            PubSubError::ClusterFailed(_) => "cluster_failed",
+            PubSubError::ReadOnlyReplica => "read_only_replica",
+            PubSubError::NotReplica => "not_replica",
+            PubSubError::UnknownBridge(_) => "unknown_bridge",
+            PubSubError::BridgeNotAllowed { .. } => "bridge_not_allowed",
+            PubSubError::WrongNode { .. } => "wrong_node",
+            PubSubError::TopicsOnSeveralNodes(_) => "topics_on_several_nodes",
+            PubSubError::Unauthorized(_) => "unauthorized",
+            PubSubError::Forbidden(_) => "forbidden",
+            PubSubError::InvalidPublisherToken(_) => "invalid_publisher_token",
+            PubSubError::ApiKeyExists(_) => "api_key_exists",
+            PubSubError::UnknownApiKey(_) => "unknown_api_key",
+            PubSubError::AuditLogDisabled => "audit_log_disabled",
+            PubSubError::AuditLogFailed(_) => "audit_log_failed",
+            PubSubError::InvalidAuditQuery(_) => "invalid_audit_query",
+        }
+    }
+}
+
+impl Display for PubSubError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            PubSubError::UnknownPublisher(id) =>
+                write!(f, "Unknown publisher with id: {}", id),
+            PubSubError::PublisherExists(id) =>
+                write!(f, "Publisher with id: {} is already registered", id),
+            PubSubError::UnknownSubscriber(id) =>
+                write!(f, "Unknown subscriber with id: {}", id),
+            PubSubError::UnknownTopic(ref topic) =>
+                write!(f, "Topic {} does not exist", topic),
+            PubSubError::UnknownSubject { ref topic, ref subject } =>
+                write!(f, "Subject {} is not found in topic {}", subject, topic),
+            PubSubError::UnknownScheduled(id) =>
+                write!(f, "Scheduled message with id: {} is not found", id),
+            PubSubError::UnknownSchema { ref topic, version: Some(v) } =>
+                write!(f, "Topic {} has no schema version {}", topic, v),
+            PubSubError::UnknownSchema { ref topic, version: None } =>
+                write!(f, "Topic {} has no active schema", topic),
+            PubSubError::PublisherNotAllowed { publisher, ref topic } =>
+                write!(f, "Publisher {} is not allowed to publish to topic {}", publisher, topic),
+            PubSubError::SubjectLimitReached { ref topic, limit } =>
+                write!(f, "Topic {} reached its limit of {} subjects", topic, limit),
+            PubSubError::MessageTooLarge { size, limit } =>
+                write!(f, "Message size {} exceeds topic limit of {} bytes", size, limit),
+            PubSubError::InvalidTopicConfig(ref e) =>
+                write!(f, "Invalid topic configuration: {}", e),
+            PubSubError::ScheduleInPast(ref due) =>
+                write!(f, "Scheduled time {} is in the past", due),
+            PubSubError::InvalidPatch(ref e) =>
+                write!(f, "Cannot apply merge patch: {}", e),
+            PubSubError::InvalidSchema(ref e) =>
+                write!(f, "Invalid JSON Schema: {}", e),
+            PubSubError::SchemaViolation { ref topic, ref violations } =>
+                write!(f, "Message body violates schema of topic {} in {} places", topic, violations.len()),
+            PubSubError::PayloadTooLarge { limit } =>
+                write!(f, "Request body exceeds limit of {} bytes", limit),
+            PubSubError::TooManyHeaders { count, limit } =>
+                write!(f, "Request has {} headers, limit is {}", count, limit),
+            PubSubError::HeadersTooLarge { size, limit } =>
+                write!(f, "Request headers take {} bytes, limit is {}", size, limit),
+            PubSubError::InvalidName { field, ref value, ref reason } =>
+                write!(f, "Invalid {} '{}': {}", field, value, reason),
+            PubSubError::InvalidHeader { name, ref reason } =>
+                write!(f, "Invalid {} header: {}", name, reason),
+            PubSubError::MissingHeader(name) =>
+                write!(f, "HTTP request must have {} header", name),
+            PubSubError::InvalidCallback { ref callback, ref reason } =>
+                write!(f, "Callback {} is not allowed: {}", callback, reason),
+            PubSubError::MalformedBody(ref e) =>
+                write!(f, "Malformed request body: {}", e),
+            PubSubError::InvalidFilter(ref e) =>
+                write!(f, "Invalid subject filter: {}", e),
+            PubSubError::SnapshotFailed(ref e) =>
+                write!(f, "Snapshot failed: {}", e),
+            PubSubError::SnapshotDirDisabled =>
+                write!(f, "Snapshots are not written on the server, set snapshot_dir"),
+            PubSubError::WalFailed(ref e) =>
+                write!(f, "Cannot append to write-ahead log, nothing was changed: {}", e),
+            PubSubError::StorageFailed(ref e) =>
+                write!(f, "Storage failed: {}", e),
+            PubSubError::ClusterFailed(ref e) =>
+                write!(f, "Cannot reach other nodes of the cluster: {}", e),
+            PubSubError::ReadOnlyReplica =>
+                write!(f, "This instance is a read-only replica, send writes to the primary"),
+            PubSubError::NotReplica =>
+                write!(f, "This instance is not a replica"),
+            PubSubError::UnknownBridge(ref bridge) =>
+                write!(f, "Bridge {} is not configured", bridge),
+            PubSubError::BridgeNotAllowed { ref bridge, ref topic } =>
+                write!(f, "Bridge {} does not bring topic {} here", bridge, topic),
+            PubSubError::WrongNode { ref node, ref location } =>
+                write!(f, "Topic is owned by node {}, see {}", node, location),
+            PubSubError::TopicsOnSeveralNodes(ref topics) =>
+                write!(f, "Topics {} are owned by different nodes", topics.join(", ")),
+            PubSubError::Unauthorized(ref e) =>
+                write!(f, "Authentication failed: {}", e),
+            PubSubError::Forbidden(ref e) =>
+                write!(f, "Access denied: {}", e),
+            PubSubError::ApiKeyExists(ref name) =>
+                write!(f, "API key {} already exists", name),
+            PubSubError::UnknownApiKey(ref name) =>
+                write!(f, "Unknown API key {}", name),
+            PubSubError::InvalidPublisherToken(id) =>
+                write!(f, "Missing or invalid token of publisher {}", id),
+            PubSubError::AuditLogDisabled =>
+                write!(f, "Audit log is not written to a file, set audit_path"),
+            PubSubError::AuditLogFailed(ref e) =>
+                write!(f, "Cannot read audit log: {}", e),
+            PubSubError::InvalidAuditQuery(ref e) =>
+                write!(f, "Invalid audit query: {}", e),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for PubSubError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        println!("request failed: {}", self);
+        let violations = match self {
+            PubSubError::SchemaViolation { ref violations, .. } => violations.clone(),
+            _ => vec![]
+        };
+        let location = match self {
+            PubSubError::WrongNode { ref location, .. } => Some(location.clone()),
+            _ => None
+        };
+        let body = ErrorBody { error: self.kind(), message: format!("{}", self), violations };
+        let mut response = status::Custom(self.status(), Json(body)).respond_to(request)?;
+        if let Some(location) = location {
+            response.set_raw_header("Location", location);
+        }
+        if let PubSubError::Unauthorized(_) = self {
+            response.set_raw_header("WWW-Authenticate", "Bearer");
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,264 @@
+use downcast_rs::Downcast;
+use http::HttpClient;
+use hyper::method::Method;
+use models::Topic;
+use rocket::config::Config;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use super::headers::{AUTHORIZATION_HEADER, CALLBACK_HEADER, HOPS_HEADER, ORIGIN_HEADER};
+use url::Url;
+
+const DEFAULT_MAX_HOPS: u32 = 4;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+// FORWARDED_PREFIX - headers of messages delivered to subscribers are prefixed, see format_headers
+const FORWARDED_PREFIX: &str = "info-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeDirection {
+    // Inbound - remote messages are republished locally
+    Inbound,
+    // Outbound - local messages are republished on the remote hub
+    Outbound,
+    Both,
+}
+
+impl BridgeDirection {
+    pub fn inbound(&self) -> bool {
+        *self != BridgeDirection::Outbound
+    }
+
+    pub fn outbound(&self) -> bool {
+        *self != BridgeDirection::Inbound
+    }
+}
+
+impl Default for BridgeDirection {
+    fn default() -> Self {
+        BridgeDirection::Both
+    }
+}
+
+// Bridge - mirrored topics of a remote hub. Name is the instance name of the remote hub
+#[derive(Debug, Clone, Deserialize)]
+pub struct Bridge {
+    pub name: String,
+    // url - base URL of the remote hub, e.g. http://hub-b:8000/info/
+    pub url: String,
+    pub topics: Vec<Topic>,
+    #[serde(default)]
+    pub direction: BridgeDirection,
+    // api_key - API key on the remote hub, sent when it has authentication enabled
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    // instance - name of this hub, remote hubs know it as their bridge name
+    pub instance: String,
+    // url - base URL of this hub, remote hubs deliver inbound messages to it
+    pub url: String,
+    pub max_hops: u32,
+    pub bridges: Vec<Bridge>,
+}
+
+impl FederationConfig {
+    // from_config - reads federation_instance, federation_url, federation_max_hops and
+    // federation_bridges keys, None when federation_instance is not set. The URLs of this hub and
+    // of the bridges must be absolute http(s) URLs, they are called by other hubs and by this one
+    pub fn from_config(config: &Config) -> Result<Option<FederationConfig>, String> {
+        let instance = match config.get_str("federation_instance") {
+            Ok(i) => i.to_string(),
+            Err(_) => return Ok(None)
+        };
+        let url = config.get_str("federation_url")
+            .map(|u| u.to_string())
+            .map_err(|_| "federation_url must be set together with federation_instance".to_string())?;
+        let max_hops = config.get_int("federation_max_hops")
+            .map(|v| v as u32)
+            .unwrap_or(DEFAULT_MAX_HOPS);
+        let bridges = match config.get_slice("federation_bridges") {
+            Ok(bridges) => bridges.iter()
+                .map(|b| {
+                    let bridge: Result<Bridge, _> = b.clone().try_into();
+                    bridge.map_err(|e| format!("invalid bridge {}: {}", b, e))
+                })
+                .collect::<Result<Vec<Bridge>, String>>()?,
+            Err(_) => vec![]
+        };
+        check_url("federation_url", &url)?;
+        for b in &bridges {
+            check_url(&format!("url of bridge {}", b.name), &b.url)?;
+        }
+        Ok(Some(FederationConfig { instance, url, max_hops, bridges }))
+    }
+
+    pub fn bridge(&self, name: &str) -> Option<&Bridge> {
+        self.bridges.iter().find(|b| b.name == name)
+    }
+
+    // inbound_callback - callback a remote hub delivers the bridge's messages to
+    pub fn inbound_callback(&self, bridge: &Bridge) -> String {
+        format!("{}bridge/{}/", self.url, bridge.name)
+    }
+
+    // outbound_callback - callback on the remote hub local messages are delivered to
+    pub fn outbound_callback(&self, bridge: &Bridge) -> String {
+        format!("{}bridge/{}/", bridge.url, self.instance)
+    }
+
+    // callback_key - API key of the remote hub the callback belongs to. Deliveries to the bridge
+    // callback of this hub on a remote hub authenticate with the key of that bridge, whichever hub
+    // subscribed it
+    pub fn callback_key(&self, callback: &str) -> Option<String> {
+        self.callback_bridge(callback).and_then(|b| b.api_key.clone())
+    }
+
+    // callback_bridge - the bridge whose callback on the remote hub it is
+    pub fn callback_bridge(&self, callback: &str) -> Option<&Bridge> {
+        self.bridges.iter().find(|b| self.outbound_callback(b) == callback)
+    }
+}
+
+// check_url - base URLs are absolute http(s) URLs ending with a slash, paths are appended to them
+fn check_url(name: &str, url: &str) -> Result<(), String> {
+    match Url::parse(url) {
+        Ok(ref u) if (u.scheme() == "http" || u.scheme() == "https") && u.has_host() && url.ends_with('/') => Ok(()),
+        Ok(_) => Err(format!("{} must be an http(s) URL ending with /, got: {}", name, url)),
+        Err(e) => Err(format!("{} must be an absolute URL, got {}: {}", name, url, e))
+    }
+}
+
+// Hubs - calls to remote hubs, the same subscribe protocol as of any other subscriber
+pub trait Hubs: Downcast {
+    // subscribe - subscribes the callback to the topic of the remote hub, returns subscription id
+    fn subscribe(&self, hub: &String, api_key: Option<&String>, topic: &Topic, callback: &String) -> Result<String, String>;
+
+    fn touch_subscriber(&self, hub: &String, api_key: Option<&String>, id: &String) -> Result<(), String>;
+}
+
+impl_downcast!(Hubs);
+
+// HubService - calls remote hubs over HTTP
+pub struct HubService {
+    client: HttpClient,
+}
+
+impl Hubs for HubService {
+    fn subscribe(&self, hub: &String, api_key: Option<&String>, topic: &Topic, callback: &String) -> Result<String, String> {
+        let mut headers = vec![(CALLBACK_HEADER, callback.clone())];
+        headers.extend(bearer(api_key));
+        self.client.send(Method::Get, &format!("{}subscribe/{}", hub, topic), headers, &[])
+    }
+
+    fn touch_subscriber(&self, hub: &String, api_key: Option<&String>, id: &String) -> Result<(), String> {
+        self.client.send(Method::Head, &format!("{}subscribe/{}", hub, id), bearer(api_key).into_iter().collect(), &[])
+            .map(|_| ())
+    }
+}
+
+impl HubService {
+    pub fn new() -> Self {
+        HubService { client: HttpClient::new(Duration::from_secs(REQUEST_TIMEOUT_SECS)) }
+    }
+}
+
+fn bearer(api_key: Option<&String>) -> Option<(&'static str, String)> {
+    api_key.map(|key| (AUTHORIZATION_HEADER, format!("Bearer {}", key)))
+}
+
+// Link - subscription of one bridged topic in one direction. Inbound subscriptions live on the
+// remote hub, outbound ones are local subscribers
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub bridge: Bridge,
+    pub topic: Topic,
+    pub inbound: bool,
+    pub subscription: Option<String>,
+}
+
+pub struct Federation {
+    config: Mutex<Option<FederationConfig>>,
+    links: Mutex<Vec<Link>>,
+}
+
+impl Federation {
+    pub fn new() -> Self {
+        Federation {
+            config: Mutex::new(None),
+            links: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn configure(&self, config: FederationConfig) {
+        let links = config.bridges.iter()
+            .flat_map(|b| {
+                let inbound = b.topics.iter()
+                    .filter(move |_| b.direction.inbound())
+                    .map(move |t| Link { bridge: b.clone(), topic: t.clone(), inbound: true, subscription: None });
+                let outbound = b.topics.iter()
+                    .filter(move |_| b.direction.outbound())
+                    .map(move |t| Link { bridge: b.clone(), topic: t.clone(), inbound: false, subscription: None });
+                inbound.chain(outbound)
+            })
+            .collect();
+        *self.links.lock().unwrap() = links;
+        *self.config.lock().unwrap() = Some(config);
+    }
+
+    pub fn config(&self) -> Option<FederationConfig> {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn links(&self) -> Vec<Link> {
+        self.links.lock().unwrap().clone()
+    }
+
+    pub fn set_subscription(&self, link: &Link, subscription: Option<String>) {
+        self.links.lock().unwrap().iter_mut()
+            .filter(|l| l.bridge.name == link.bridge.name && l.topic == link.topic && l.inbound == link.inbound)
+            .for_each(|l| l.subscription = subscription.clone());
+    }
+}
+
+// Route - origin and hop count of a bridged message, read from its headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub origin: Option<String>,
+    pub hops: u32,
+}
+
+impl Route {
+    pub fn from_headers(headers: &HashMap<String, String>) -> Route {
+        Route {
+            origin: federation_header(headers, ORIGIN_HEADER),
+            hops: federation_header(headers, HOPS_HEADER)
+                .and_then(|h| h.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+}
+
+fn federation_header(headers: &HashMap<String, String>, name: &str) -> Option<String> {
+    let forwarded = format!("{}{}", FORWARDED_PREFIX, name);
+    headers.iter()
+        .find(|&(k, _)| k.eq_ignore_ascii_case(name) || k.eq_ignore_ascii_case(&forwarded))
+        .map(|(_, v)| v.clone())
+}
+
+// republished_headers - headers of a bridged message without the delivery prefix, with the route
+// of the next hop
+pub fn republished_headers(headers: &HashMap<String, String>, origin: &str, hops: u32) -> HashMap<String, String> {
+    let mut republished: HashMap<String, String> = headers.iter()
+        .map(|(k, v)| {
+            let name = if k.to_lowercase().starts_with(FORWARDED_PREFIX) { &k[FORWARDED_PREFIX.len()..] } else { &k[..] };
+            (name.to_string(), v.clone())
+        })
+        .filter(|&(ref k, _)| !k.eq_ignore_ascii_case(ORIGIN_HEADER) && !k.eq_ignore_ascii_case(HOPS_HEADER))
+        .collect();
+    republished.insert(ORIGIN_HEADER.to_string(), origin.to_string());
+    republished.insert(HOPS_HEADER.to_string(), format!("{}", hops));
+    republished
+}
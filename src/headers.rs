@@ -1,6 +1,20 @@
 use std::collections::HashMap;
 
 pub const CALLBACK_HEADER: &str = "Location";
+pub const AUTHORIZATION_HEADER: &str = "Authorization";
+pub const HOST_HEADER: &str = "Host";
+pub const BATCH_SIZE_HEADER: &str = "Batch-Size";
+pub const BATCH_WINDOW_HEADER: &str = "Batch-Window";
+pub const CONTENT_TYPE_HEADER: &str = "Content-Type";
+pub const CONTENT_ENCODING_HEADER: &str = "Content-Encoding";
+pub const TTL_HEADER: &str = "TTL";
+pub const PUBLISH_AT_HEADER: &str = "Publish-At";
+pub const DELAY_HEADER: &str = "Delay";
+pub const RETAIN_HEADER: &str = "Retain";
+pub const DELIVERY_HEADER: &str = "Delivery";
+pub const ORIGIN_HEADER: &str = "Federation-Origin";
+pub const HOPS_HEADER: &str = "Federation-Hops";
+pub const PUBLISHER_TOKEN_HEADER: &str = "Publisher-Token";
 
 pub fn format_headers(h: &HashMap<String, String>) -> HashMap<String, String> {
     h.iter()
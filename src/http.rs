@@ -0,0 +1,54 @@
+use hyper::Client;
+use hyper::client::RedirectPolicy;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use std::borrow::Cow;
+use std::io::Read;
+use std::time::Duration;
+
+// HttpClient - blocking HTTP client for calls to other instances: replicas, remote hubs and
+// subscriber callbacks
+pub struct HttpClient {
+    client: Client,
+}
+
+impl HttpClient {
+    // new - calls give up when sending or reading stalls for longer than the timeout. Redirects
+    // are not followed, a callback could redirect to an internal address otherwise
+    pub fn new(timeout: Duration) -> Self {
+        let mut client = Client::new();
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        client.set_read_timeout(Some(timeout));
+        client.set_write_timeout(Some(timeout));
+        HttpClient { client }
+    }
+
+    // send - returns the body of a successful response, any other status is an error
+    pub fn send<K: Into<Cow<'static, str>>>(&self, method: Method, url: &str, headers: Vec<(K, String)>,
+                                             body: &[u8]) -> Result<String, String> {
+        let (status, text) = self.request(method.clone(), url, headers, body)?;
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(format!("{} {} responded with {}", method, url, status))
+        }
+    }
+
+    // request - status and body of the response, an error only when the call itself fails
+    pub fn request<K: Into<Cow<'static, str>>>(&self, method: Method, url: &str, headers: Vec<(K, String)>,
+                                                body: &[u8]) -> Result<(StatusCode, String), String> {
+        let mut h = Headers::new();
+        headers.into_iter().for_each(|(name, value)| h.set_raw(name, vec![value.into_bytes()]));
+        let mut res = self.client.request(method.clone(), url)
+            .headers(h)
+            .body(body)
+            .send()
+            .map_err(|e| format!("{} {} failed: {}", method, url, e))?;
+
+        let mut text = String::new();
+        res.read_to_string(&mut text)
+            .map_err(|e| format!("cannot read response of {} {}: {}", method, url, e))?;
+        Ok((res.status, text))
+    }
+}
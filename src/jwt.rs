@@ -0,0 +1,147 @@
+use acl::Grant;
+use auth::Principal;
+use errors::PubSubError;
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use rocket::config::Config;
+use std::fs;
+use std::sync::Mutex;
+
+// ADMIN_ROLE - role claim of principals allowed to call administrative endpoints
+const ADMIN_ROLE: &str = "admin";
+
+// JwtKey - a verification key of the key set, selected by the kid header of a token
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtKey {
+    pub kid: String,
+    // algorithm - HS256 or RS256
+    pub algorithm: String,
+    // secret - shared secret of HS256 keys
+    #[serde(default)]
+    pub secret: Option<String>,
+    // public_key - path of the DER encoded RSA public key of RS256 keys
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub keys: Vec<JwtKey>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+impl JwtConfig {
+    // from_config - reads jwt_keys (array of kid, algorithm, secret or public_key tables),
+    // jwt_issuer and jwt_audience keys, None when jwt_keys is not set
+    pub fn from_config(config: &Config) -> Result<Option<JwtConfig>, String> {
+        let keys = match config.get_slice("jwt_keys") {
+            Ok(keys) => keys.iter()
+                .map(|k| {
+                    let key: Result<JwtKey, _> = k.clone().try_into();
+                    key.map_err(|e| format!("invalid JWT key {}: {}", k, e))
+                })
+                .collect::<Result<Vec<JwtKey>, String>>()?,
+            Err(_) => return Ok(None)
+        };
+        Ok(Some(JwtConfig {
+            keys,
+            issuer: config.get_str("jwt_issuer").ok().map(|i| i.to_string()),
+            audience: config.get_str("jwt_audience").ok().map(|a| a.to_string()),
+        }))
+    }
+}
+
+// Claims - claims of a token driving the principal. Without a topics claim the ACL rules of the
+// subject apply
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    topics: Option<Vec<Grant>>,
+}
+
+struct VerificationKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: Vec<u8>,
+}
+
+// JwtKeys - local key set verifying tokens issued by the platform
+pub struct JwtKeys {
+    keys: Mutex<Vec<VerificationKey>>,
+    issuer: Mutex<Option<String>>,
+    audience: Mutex<Option<String>>,
+}
+
+impl JwtKeys {
+    pub fn new() -> Self {
+        JwtKeys {
+            keys: Mutex::new(vec![]),
+            issuer: Mutex::new(None),
+            audience: Mutex::new(None),
+        }
+    }
+
+    // configure - loads the keys, fails on unsupported algorithms and unreadable key files
+    pub fn configure(&self, config: JwtConfig) -> Result<(), String> {
+        let keys = config.keys.into_iter()
+            .map(|k| {
+                let (algorithm, key) = match (k.algorithm.as_str(), k.secret, k.public_key) {
+                    ("HS256", Some(secret), _) => (Algorithm::HS256, secret.into_bytes()),
+                    ("RS256", _, Some(path)) => (Algorithm::RS256, fs::read(&path)
+                        .map_err(|e| format!("cannot read public key {} of JWT key {}: {}", path, k.kid, e))?),
+                    ("HS256", None, _) => return Err(format!("JWT key {} needs a secret", k.kid)),
+                    ("RS256", _, None) => return Err(format!("JWT key {} needs a public_key", k.kid)),
+                    (a, _, _) => return Err(format!("JWT key {} has unsupported algorithm {}", k.kid, a))
+                };
+                Ok(VerificationKey { kid: k.kid, algorithm, key })
+            })
+            .collect::<Result<Vec<VerificationKey>, String>>()?;
+        *self.keys.lock().unwrap() = keys;
+        *self.issuer.lock().unwrap() = config.issuer;
+        *self.audience.lock().unwrap() = config.audience;
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.lock().unwrap().is_empty()
+    }
+
+    // authenticate - principal of a valid token, the signature, expiry, issuer and audience are
+    // verified with the key named by the kid header
+    pub fn authenticate(&self, token: &str) -> Result<Principal, PubSubError> {
+        let invalid = |reason: String| PubSubError::Unauthorized(format!("invalid token: {}", reason));
+
+        let header = decode_header(token).map_err(|e| invalid(format!("{}", e)))?;
+        let keys = self.keys.lock().unwrap();
+        let key = match header.kid {
+            Some(ref kid) => keys.iter().find(|k| &k.kid == kid),
+            None if keys.len() == 1 => keys.first(),
+            None => None
+        }.ok_or(invalid("unknown key".to_string()))?;
+        if header.alg != key.algorithm {
+            return Err(invalid(format!("key {} does not sign {:?} tokens", key.kid, header.alg)));
+        }
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_iat = false;
+        validation.iss = self.issuer.lock().unwrap().clone();
+        if let Some(ref audience) = *self.audience.lock().unwrap() {
+            validation.set_audience(audience);
+        }
+        let claims = decode::<Claims>(token, &key.key, &validation)
+            .map_err(|e| invalid(format!("{}", e)))?
+            .claims;
+
+        let mut principal = Principal::new(claims.sub, claims.roles.iter().any(|r| r == ADMIN_ROLE));
+        principal.grants = claims.topics;
+        Ok(principal)
+    }
+}
+
+// is_jwt - tokens of three dot separated parts are JWTs, anything else is an API key
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
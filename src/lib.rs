@@ -2,15 +2,38 @@
 #![feature(const_fn)]
 #![feature(plugin)]
 #![plugin(rocket_codegen)]
+extern crate base64;
+extern crate bincode;
 extern crate chrono;
+extern crate hyper;
+extern crate jsonwebtoken;
 extern crate rocket;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+extern crate sled;
 extern crate uuid;
+extern crate url;
+extern crate valico;
 #[macro_use]
 extern crate downcast_rs;
-#[macro_use]
-extern crate lazy_static;
 
-use rocket::Rocket;
+use rocket::{Config, Rocket};
+use rocket::fairing::AdHoc;
+use limits::Limits;
+use callbacks::CallbackPolicy;
+use acl::Acl;
+use audit::AuditConfig;
+use auth::ApiKeys;
+use cluster::ClusterConfig;
+use federation::FederationConfig;
+use jwt::{JwtConfig, JwtKeys};
+use replication::ReplicationConfig;
+use snapshot::SnapshotConfig;
+use ticker::TickerConfig;
+use wal::WalConfig;
 use self::rest::*;
 use server::PubSubServer;
 
@@ -18,11 +41,188 @@ pub mod rest;
 pub mod server;
 pub mod subscribers;
 pub mod models;
+pub mod clock;
+pub mod scheduler;
+pub mod batcher;
+pub mod limits;
+pub mod callbacks;
+pub mod errors;
+pub mod pattern;
+pub mod patch;
+pub mod schemas;
+pub mod wal;
+pub mod snapshot;
+pub mod storage;
+pub mod sled_storage;
+pub mod replication;
+pub mod federation;
+pub mod cluster;
+pub mod auth;
+pub mod acl;
+pub mod jwt;
+pub mod audit;
+pub mod ticker;
 mod headers;
+mod http;
 
 pub fn mount_routes(server: PubSubServer) -> Rocket {
-    rocket::ignite()
+    mount(rocket::ignite(), server)
+}
+
+// mount_routes_with_config - the server with the given configuration instead of Rocket.toml and
+// ROCKET_* variables
+pub fn mount_routes_with_config(server: PubSubServer, config: Config) -> Rocket {
+    mount(rocket::custom(config, true), server)
+}
+
+fn mount(rocket: Rocket, server: PubSubServer) -> Rocket {
+    rocket
         .manage(server)
+        .attach(AdHoc::on_attach(|rocket| {
+            let limits = Limits::from_config(rocket.config());
+            println!("request limits: {:?}", limits);
+            Ok(rocket.manage(limits))
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            let policy = CallbackPolicy::from_config(rocket.config());
+            println!("callback policy: {:?}", policy);
+            rocket.state::<PubSubServer>().unwrap().configure_callbacks(policy);
+            Ok(rocket)
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            let config = SnapshotConfig::from_config(rocket.config());
+            println!("snapshot directory: {:?}", config.dir);
+            Ok(rocket.manage(config))
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            match ApiKeys::from_config(rocket.config()) {
+                Ok(keys) => {
+                    println!("API key authentication enabled: {}", keys.enabled());
+                    Ok(rocket.manage(keys))
+                }
+                Err(e) => {
+                    println!("invalid API keys: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            let jwt_keys = JwtKeys::new();
+            let configured = match JwtConfig::from_config(rocket.config()) {
+                Ok(Some(config)) => jwt_keys.configure(config),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e)
+            };
+            match configured {
+                Ok(_) => {
+                    println!("JWT authentication enabled: {}", jwt_keys.enabled());
+                    Ok(rocket.manage(jwt_keys))
+                }
+                Err(e) => {
+                    println!("invalid JWT keys: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            match Acl::from_config(rocket.config()) {
+                Ok(acl) => {
+                    println!("access control lists enabled: {}", acl.enabled());
+                    Ok(rocket.manage(acl))
+                }
+                Err(e) => {
+                    println!("invalid access control lists: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            match ReplicationConfig::from_config(rocket.config()) {
+                Ok(Some(config)) => {
+                    rocket.state::<PubSubServer>().unwrap().configure_replication(config);
+                    Ok(rocket)
+                }
+                Ok(None) => Ok(rocket),
+                Err(e) => {
+                    println!("invalid replication config: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            let configured = match ClusterConfig::from_config(rocket.config()) {
+                Ok(Some(config)) => rocket.state::<PubSubServer>().unwrap().configure_cluster(config),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e)
+            };
+            match configured {
+                Ok(_) => Ok(rocket),
+                Err(e) => {
+                    println!("invalid cluster config: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            match FederationConfig::from_config(rocket.config()) {
+                Ok(Some(config)) => {
+                    rocket.state::<PubSubServer>().unwrap().configure_federation(config);
+                    Ok(rocket)
+                }
+                Ok(None) => Ok(rocket),
+                Err(e) => {
+                    println!("invalid federation config: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            let opened = match AuditConfig::from_config(rocket.config()) {
+                Some(config) => rocket.state::<PubSubServer>().unwrap().configure_audit(config),
+                None => Ok(())
+            };
+            match opened {
+                Ok(_) => Ok(rocket),
+                Err(e) => {
+                    println!("cannot open audit log: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            // durable mode - state is rebuilt from the write-ahead log before serving requests
+            let opened = match WalConfig::from_config(rocket.config()) {
+                Ok(Some(config)) => rocket.state::<PubSubServer>().unwrap()
+                    .open_wal(config)
+                    .map_err(|e| format!("{}", e)),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e)
+            };
+            match opened {
+                Ok(_) => Ok(rocket),
+                Err(e) => {
+                    println!("cannot open write-ahead log: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach(|rocket| {
+            match TickerConfig::from_config(rocket.config()) {
+                Ok(config) => Ok(rocket.manage(config)),
+                Err(e) => {
+                    println!("invalid ticker config: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_launch(|rocket| {
+            // subjects expire and replicas are fed on background threads, they are not started for
+            // local test clients
+            let config = *rocket.state::<TickerConfig>().unwrap();
+            let server = rocket.state::<PubSubServer>().unwrap();
+            server.start_ticker(config);
+            server.start_replication();
+        }))
         .mount(
             "/info",
             routes![
@@ -34,7 +234,42 @@ pub fn mount_routes(server: PubSubServer) -> Rocket {
                 remove_publisher,
                 touch_publisher,
                 publish,
-                remove
+                publish_bulk,
+                patch_subject,
+                transaction,
+                topic_owner,
+                join_publisher,
+                leave_publisher,
+                clear_publisher_token,
+                remove,
+                remove_matching,
+                bridge_receive,
+                bridge_remove,
+                schedule,
+                scheduled,
+                cancel_scheduled,
+                set_retention,
+                topic_config,
+                configure_topic,
+                remove_topic,
+                register_schema,
+                active_schema,
+                topic_schema,
+                activate_schema,
+                deactivate_schema,
+                download_snapshot,
+                export_snapshot,
+                api_keys,
+                create_api_key,
+                revoke_api_key,
+                audit_log,
+                query_audit_log,
+                replication_status,
+                replicate_snapshot,
+                replicate_entries,
+                promote,
+                auto_create_topics
             ],
         )
+        .catch(errors![unauthorized, forbidden])
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+use rocket::Config;
+
+const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+const DEFAULT_MAX_HEADERS: usize = 64;
+const DEFAULT_MAX_HEADERS_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_NAME_LENGTH: usize = 256;
+const DEFAULT_NAME_CHARS: &str = "-_.:";
+
+// Limits - request limits of the REST API, configured via Rocket.toml extras or ROCKET_*
+// environment variables, e.g. ROCKET_MAX_BODY_SIZE=65536
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_body_size: u64,
+    pub max_headers: usize,
+    pub max_headers_size: usize,
+    pub max_name_length: usize,
+    // name_chars - characters allowed in topic and subject names in addition to alphanumerics
+    pub name_chars: String,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_headers_size: DEFAULT_MAX_HEADERS_SIZE,
+            max_name_length: DEFAULT_MAX_NAME_LENGTH,
+            name_chars: DEFAULT_NAME_CHARS.to_string(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Limits {
+        let default = Limits::default();
+        Limits {
+            max_body_size: config.get_int("max_body_size")
+                .map(|v| v as u64)
+                .unwrap_or(default.max_body_size),
+            max_headers: config.get_int("max_headers")
+                .map(|v| v as usize)
+                .unwrap_or(default.max_headers),
+            max_headers_size: config.get_int("max_headers_size")
+                .map(|v| v as usize)
+                .unwrap_or(default.max_headers_size),
+            max_name_length: config.get_int("max_name_length")
+                .map(|v| v as usize)
+                .unwrap_or(default.max_name_length),
+            name_chars: config.get_str("name_chars")
+                .map(|v| v.to_string())
+                .unwrap_or(default.name_chars),
+        }
+    }
+
+    pub fn is_allowed_char(&self, c: char) -> bool {
+        c.is_ascii_alphanumeric() || self.name_chars.contains(c)
+    }
+}
@@ -5,8 +5,59 @@ extern crate rocket;
 
 use pub_sub_server::mount_routes;
 use pub_sub_server::server::PubSubServer;
+use pub_sub_server::sled_storage::SledStorage;
+use pub_sub_server::snapshot::Snapshot;
+use std::env;
+use std::path::Path;
+use std::process;
 
+// Usage: pub_sub_server [--storage-path <dir>] [--import-snapshot <file>] [--export-snapshot <file>]
+// --storage-path keeps retained messages, publishers and subscriptions in an on-disk store in the
+// directory instead of memory.
+// --import-snapshot restores the state before serving requests, --export-snapshot writes the state
+// (after the write-ahead log replay and import) to a file and exits. Files with .json extension are
+// JSON snapshots, anything else is binary
 fn main() {
-    let error = mount_routes(PubSubServer::new()).launch();
+    let args: Vec<String> = env::args().collect();
+    let server = match arg_value(&args, "--storage-path") {
+        Some(path) => match SledStorage::open(Path::new(path)) {
+            Ok(storage) => PubSubServer::new().with_storage(Box::new(storage)),
+            Err(e) => {
+                println!("cannot open storage at {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => PubSubServer::new()
+    };
+    let rocket = mount_routes(server);
+    {
+        let server = rocket.state::<PubSubServer>().unwrap();
+        if let Some(path) = arg_value(&args, "--import-snapshot") {
+            let restored = Snapshot::read(Path::new(path))
+                .and_then(|s| server.restore(s).map_err(|e| format!("{}", e)));
+            if let Err(e) = restored {
+                println!("cannot import snapshot: {}", e);
+                process::exit(1);
+            }
+        }
+        if let Some(path) = arg_value(&args, "--export-snapshot") {
+            match server.snapshot().map_err(|e| format!("{}", e)).and_then(|s| s.write(Path::new(path))) {
+                Ok(_) => println!("exported snapshot to {}", path),
+                Err(e) => {
+                    println!("cannot export snapshot: {}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+
+    let error = rocket.launch();
     drop(error);
 }
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+}
@@ -1,20 +1,69 @@
 use std::collections::HashMap;
+use chrono::Duration;
 use chrono::prelude::*;
 use uuid::Uuid;
 use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fmt;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Subscriber {
     pub id: Uuid,
     pub callback: String,
     pub topic: String,
+    // batching - Some if a subscriber opted in to batched delivery
+    pub batching: Option<BatchPolicy>,
+    pub delivery: Delivery,
 }
 
 impl Subscriber {
     pub fn new(callback: String, topic: String) -> Self {
-        Subscriber { id: Uuid::new_v4(), callback, topic }
+        Subscriber { id: Uuid::new_v4(), callback, topic, batching: None, delivery: Delivery::Full }
+    }
+
+    pub fn with_batching(self, batching: Option<BatchPolicy>) -> Self {
+        Subscriber { batching, ..self }
+    }
+
+    pub fn with_delivery(self, delivery: Delivery) -> Self {
+        Subscriber { delivery, ..self }
+    }
+}
+
+// Delivery - whether a subscriber receives the merged document or just the merge patch when a
+// retained subject is patched
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    Full,
+    Patch,
+}
+
+// BatchPolicy - a batch is sent once it has max_size entries or is open for window_ms
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BatchPolicy {
+    pub max_size: usize,
+    pub window_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchEntry {
+    Publish(Message),
+    Remove(Message),
+}
+
+// Batch - ordered publications and removals of one topic delivered to a subscriber at once.
+// transaction - set if the batch is a result of a committed transaction
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub topic: Topic,
+    pub entries: Vec<BatchEntry>,
+    pub transaction: Option<Uuid>,
+}
+
+impl Batch {
+    pub fn new(topic: Topic) -> Self {
+        Batch { topic, entries: vec![], transaction: None }
     }
 }
 
@@ -27,13 +76,17 @@ impl Display for Subscriber {
 #[derive(Debug)]
 pub struct Publisher {
     pub id: Uuid,
+    // token_hash - hash of the secret proving ownership of the publisher, None for publishers
+    // registered without a token
+    pub token_hash: Option<String>,
     last_seen: DateTime<Local>,
 }
 
 impl Publisher {
-    pub fn new(id: Uuid) -> Self {
+    pub fn new(id: Uuid, token_hash: Option<String>) -> Self {
         Publisher {
             id,
+            token_hash,
             last_seen: Local::now(),
         }
     }
@@ -49,27 +102,119 @@ impl Display for Publisher {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub publisher: Uuid,
     pub topic: Topic,
     pub subject: Subject,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    // ttl - seconds requested by a publisher, expires_at - computed by the server on publish
+    pub ttl: Option<u64>,
+    pub expires_at: Option<DateTime<Local>>,
+    pub retention: Retention,
 }
 
 impl Message {
+    pub fn new(publisher: Uuid, topic: Topic, subject: Subject, headers: HashMap<String, String>,
+               body: Vec<u8>) -> Message {
+        Message {
+            publisher,
+            topic,
+            subject,
+            headers,
+            body,
+            content_type: None,
+            content_encoding: None,
+            ttl: None,
+            expires_at: None,
+            retention: Retention::Retained,
+        }
+    }
+
     pub fn with_headers(self, h: HashMap<String, String>) -> Message {
         Message { headers: h, ..self }
     }
+
+    pub fn with_content(self, content_type: Option<String>, content_encoding: Option<String>) -> Message {
+        Message { content_type, content_encoding, ..self }
+    }
+
+    pub fn with_ttl(self, ttl: Option<u64>) -> Message {
+        Message { ttl, ..self }
+    }
+
+    pub fn with_retention(self, retention: Retention) -> Message {
+        Message { retention, ..self }
+    }
+
+    pub fn expiring_from(self, now: DateTime<Local>) -> Message {
+        let expires_at = self.ttl.map(|t| now + Duration::seconds(t as i64));
+        Message { expires_at, ..self }
+    }
+
+    pub fn is_expired(&self, now: &DateTime<Local>) -> bool {
+        self.expires_at.map_or(false, |t| t <= *now)
+    }
 }
 
 impl Display for Message {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "({}, {}, {}, {:?}, \n body: {})", self.publisher.hyphenated(), self.topic, self
-            .subject, self.headers, self.body)
+        write!(f, "({}, {}, {}, {:?}, {:?}, \n body: {})", self.publisher.hyphenated(), self.topic, self
+            .subject, self.headers, self.content_type, String::from_utf8_lossy(&self.body))
+    }
+}
+
+// Retained - message is stored in topics and replayed to late subscribers,
+// Transient - message is only delivered to currently active subscribers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Retention {
+    Retained,
+    Transient,
+}
+
+// TopicConfig - limits and defaults of a topic. Missing fields mean "unlimited"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicConfig {
+    pub retention: Retention,
+    pub max_subjects: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub default_ttl: Option<u64>,
+    pub allowed_publishers: Option<Vec<Uuid>>,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        TopicConfig {
+            retention: Retention::Retained,
+            max_subjects: None,
+            max_message_size: None,
+            default_ttl: None,
+            allowed_publishers: None,
+        }
     }
 }
 
 pub type Subject = String;
-pub type Topic = String;
\ No newline at end of file
+pub type Topic = String;
+
+// base64_body - message bodies are serialized base64 encoded, e.g. in the write-ahead log
+mod base64_body {
+    use base64;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(body: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(&encoded).map_err(D::Error::custom)
+    }
+}
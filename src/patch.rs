@@ -0,0 +1,23 @@
+use serde_json::{Map, Value};
+
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+
+// merge_patch - applies an RFC 7396 JSON merge patch to the target document
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    match *patch {
+        Value::Object(ref fields) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let doc = target.as_object_mut().unwrap();
+            for (k, v) in fields {
+                if v.is_null() {
+                    doc.remove(k);
+                } else {
+                    merge_patch(doc.entry(k.as_str()).or_insert(Value::Null), v);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
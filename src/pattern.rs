@@ -0,0 +1,41 @@
+// SubjectFilter - selects subjects either by prefix or by a glob pattern where '*' matches any
+// sequence of characters and '?' matches exactly one
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubjectFilter {
+    Prefix(String),
+    Glob(String),
+}
+
+impl SubjectFilter {
+    pub fn matches(&self, subject: &str) -> bool {
+        match *self {
+            SubjectFilter::Prefix(ref prefix) => subject.starts_with(prefix.as_str()),
+            SubjectFilter::Glob(ref pattern) => glob_matches(pattern, subject),
+        }
+    }
+}
+
+pub fn glob_matches(pattern: &str, value: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let v: Vec<char> = value.chars().collect();
+    let (mut pi, mut vi) = (0, 0);
+    // position of the last '*' seen in the pattern and of the value char it currently covers
+    let mut star: Option<(usize, usize)> = None;
+
+    while vi < v.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == v[vi]) {
+            pi += 1;
+            vi += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, vi));
+            pi += 1;
+        } else if let Some((sp, sv)) = star {
+            pi = sp + 1;
+            vi = sv + 1;
+            star = Some((sp, sv + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
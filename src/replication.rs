@@ -0,0 +1,247 @@
+use downcast_rs::Downcast;
+use http::HttpClient;
+use hyper::method::Method;
+use rocket::config::Config;
+use serde_json;
+use snapshot::{Snapshot, SnapshotFormat};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use super::headers::{AUTHORIZATION_HEADER, CONTENT_TYPE_HEADER};
+use wal::WalEntry;
+
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+// SNAPSHOT_FORMAT - encoding of snapshots sent to replicas
+pub const SNAPSHOT_FORMAT: SnapshotFormat = SnapshotFormat::Binary;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    // Replica - read-only copy of a primary, state changes only by replication
+    Replica,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    pub role: Role,
+    // replicas - base URLs of the replica instances, e.g. http://standby:8000/info/
+    pub replicas: Vec<String>,
+    // api_key - admin API key of the replicas, sent when they have authentication enabled
+    pub api_key: Option<String>,
+}
+
+impl ReplicationConfig {
+    // from_config - reads replication_role (primary or replica), replicas (array of URLs) and
+    // replication_api_key keys, None when neither role nor replicas is set
+    pub fn from_config(config: &Config) -> Result<Option<ReplicationConfig>, String> {
+        if config.get_str("replication_role").is_err() && config.get_slice("replicas").is_err() {
+            return Ok(None);
+        }
+        let role = match config.get_str("replication_role") {
+            Ok("primary") | Err(_) => Role::Primary,
+            Ok("replica") => Role::Replica,
+            Ok(r) => return Err(format!("replication_role must be primary or replica, got: {}", r))
+        };
+        let replicas = match config.get_slice("replicas") {
+            Ok(urls) => urls.iter()
+                .map(|url| url.as_str()
+                    .map(|s| s.to_string())
+                    .ok_or(format!("replicas must be URL strings, got: {}", url)))
+                .collect::<Result<Vec<String>, String>>()?,
+            Err(_) => vec![]
+        };
+        let api_key = config.get_str("replication_api_key").ok().map(|k| k.to_string());
+        Ok(Some(ReplicationConfig { role, replicas, api_key }))
+    }
+}
+
+// Replicas - transport of the state from the primary to its replicas
+pub trait Replicas: Downcast {
+    // send_snapshot - replaces the whole state of the replica
+    fn send_snapshot(&self, replica: &String, api_key: Option<&String>, snapshot: &Snapshot) -> Result<(), String>;
+
+    // send_entries - applies the mutations to the replica in order
+    fn send_entries(&self, replica: &String, api_key: Option<&String>, entries: &[WalEntry]) -> Result<(), String>;
+}
+
+impl_downcast!(Replicas);
+
+// ReplicaService - sends the state to replicas over HTTP
+pub struct ReplicaService {
+    client: HttpClient,
+}
+
+impl Replicas for ReplicaService {
+    fn send_snapshot(&self, replica: &String, api_key: Option<&String>, snapshot: &Snapshot) -> Result<(), String> {
+        let body = snapshot.encode(SNAPSHOT_FORMAT)?;
+        let url = format!("{}replication/snapshot", replica);
+        self.call(Method::Put, &url, "application/octet-stream", api_key, &body)
+    }
+
+    fn send_entries(&self, replica: &String, api_key: Option<&String>, entries: &[WalEntry]) -> Result<(), String> {
+        let body = serde_json::to_vec(entries).map_err(|e| format!("{}", e))?;
+        let url = format!("{}replication/entries", replica);
+        self.call(Method::Post, &url, "application/json", api_key, &body)
+    }
+}
+
+impl ReplicaService {
+    pub fn new() -> Self {
+        ReplicaService { client: HttpClient::new(Duration::from_secs(REQUEST_TIMEOUT_SECS)) }
+    }
+
+    fn call(&self, method: Method, url: &str, content_type: &str, api_key: Option<&String>,
+            body: &[u8]) -> Result<(), String> {
+        let mut headers = vec![(CONTENT_TYPE_HEADER, content_type.to_string())];
+        if let Some(key) = api_key {
+            headers.push((AUTHORIZATION_HEADER, format!("Bearer {}", key)));
+        }
+        self.client.send(method, url, headers, body).map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicaStatus {
+    pub url: String,
+    // synced - whether the replica received a snapshot and gets only further mutations
+    pub synced: bool,
+    pub pending: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationStatus {
+    pub role: Role,
+    pub replicas: Vec<ReplicaStatus>,
+}
+
+struct Replica {
+    url: String,
+    synced: bool,
+    pending: Vec<WalEntry>,
+}
+
+// Replication - role of this instance and mutations queued for each replica. A replica that is
+// not synced gets a snapshot first, entries are queued only for synced replicas
+pub struct Replication {
+    role: Mutex<Role>,
+    replicas: Mutex<Vec<Replica>>,
+    api_key: Mutex<Option<String>>,
+    // shipping - held while sending, so concurrent senders don't reorder entries
+    shipping: Mutex<()>,
+    // queued - set when there is something to ship, the replication worker waits for it
+    queued: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Replication {
+            role: Mutex::new(Role::Primary),
+            replicas: Mutex::new(vec![]),
+            api_key: Mutex::new(None),
+            shipping: Mutex::new(()),
+            queued: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    pub fn configure(&self, config: ReplicationConfig) {
+        *self.role.lock().unwrap() = config.role;
+        *self.replicas.lock().unwrap() = config.replicas.into_iter()
+            .map(|url| Replica { url, synced: false, pending: vec![] })
+            .collect();
+        *self.api_key.lock().unwrap() = config.api_key;
+        self.notify();
+    }
+
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key.lock().unwrap().clone()
+    }
+
+    pub fn role(&self) -> Role {
+        *self.role.lock().unwrap()
+    }
+
+    pub fn promote(&self) -> bool {
+        let mut role = self.role.lock().unwrap();
+        let promoted = *role == Role::Replica;
+        *role = Role::Primary;
+        promoted
+    }
+
+    pub fn record(&self, entry: &WalEntry) {
+        if self.role() != Role::Primary {
+            return;
+        }
+        self.replicas.lock().unwrap().iter_mut()
+            .filter(|r| r.synced)
+            .for_each(|r| r.pending.push(entry.clone()));
+        self.notify();
+    }
+
+    pub fn shipping(&self) -> &Mutex<()> {
+        &self.shipping
+    }
+
+    fn notify(&self) {
+        *self.queued.lock().unwrap() = true;
+        self.wakeup.notify_one();
+    }
+
+    // wait - blocks until something is queued or the timeout passes, replicas which failed are
+    // retried after the timeout
+    pub fn wait(&self, timeout: Duration) {
+        let mut queued = self.queued.lock().unwrap();
+        if !*queued {
+            queued = self.wakeup.wait_timeout(queued, timeout).unwrap().0;
+        }
+        *queued = false;
+    }
+
+    pub fn unsynced(&self) -> Vec<String> {
+        if self.role() != Role::Primary {
+            return vec![];
+        }
+        self.replicas.lock().unwrap().iter()
+            .filter(|r| !r.synced)
+            .map(|r| r.url.clone())
+            .collect()
+    }
+
+    // mark_synced - must be called while the state captured by the snapshot is still locked
+    pub fn mark_synced(&self, url: &String) {
+        self.replicas.lock().unwrap().iter_mut()
+            .filter(|r| &r.url == url)
+            .for_each(|r| {
+                r.synced = true;
+                r.pending.clear();
+            });
+    }
+
+    // mark_unsynced - the replica missed entries, it gets a new snapshot on the next attempt
+    pub fn mark_unsynced(&self, url: &String) {
+        self.replicas.lock().unwrap().iter_mut()
+            .filter(|r| &r.url == url)
+            .for_each(|r| {
+                r.synced = false;
+                r.pending.clear();
+            });
+    }
+
+    pub fn take_pending(&self) -> Vec<(String, Vec<WalEntry>)> {
+        self.replicas.lock().unwrap().iter_mut()
+            .filter(|r| r.synced && !r.pending.is_empty())
+            .map(|r| (r.url.clone(), r.pending.drain(..).collect()))
+            .collect()
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        ReplicationStatus {
+            role: self.role(),
+            replicas: self.replicas.lock().unwrap().iter()
+                .map(|r| ReplicaStatus { url: r.url.clone(), synced: r.synced, pending: r.pending.len() })
+                .collect(),
+        }
+    }
+}
+
@@ -1,114 +1,934 @@
 extern crate rocket;
 extern crate rocket_contrib;
 
-use models::Message;
+use chrono::prelude::*;
+use errors::PubSubError;
+use limits::Limits;
+use models::{BatchEntry, BatchPolicy, Delivery, Message, Retention, TopicConfig};
+use pattern::SubjectFilter;
 use rocket::http::Status;
-use rocket::Outcome;
-use rocket::request::{self, FromRequest, Request};
+use rocket::http::RawStr;
+use rocket::{Data, Outcome};
+use rocket::request::{self, FromParam, FromRequest, Request};
+use rocket::response::content::Content;
+use rocket::response::{self, Responder};
 use rocket::response::status;
-use rocket::response::status::NotFound;
 use self::rocket::State;
-use self::rocket_contrib::UUID;
+use self::rocket_contrib::{Json, UUID};
 use std::collections::HashMap;
-use super::headers::CALLBACK_HEADER;
+use std::io::Read;
+use base64;
+use rocket::http::ContentType;
+use serde_json;
+use super::headers::{BATCH_SIZE_HEADER, BATCH_WINDOW_HEADER, CALLBACK_HEADER};
+use super::headers::{CONTENT_ENCODING_HEADER, CONTENT_TYPE_HEADER};
+use super::headers::{DELAY_HEADER, DELIVERY_HEADER, PUBLISH_AT_HEADER, RETAIN_HEADER, TTL_HEADER};
+use super::headers::{AUTHORIZATION_HEADER, PUBLISHER_TOKEN_HEADER};
+use super::scheduler::{Schedule, ScheduledMessage};
+use super::schemas::TopicSchema;
+use super::acl::Action;
+use super::audit::{AuditAction, AuditEvent, AuditQuery};
+use super::auth::{Admin, ApiKey, ApiKeys, KeyView, Principal};
+use super::cluster::Node;
+use super::replication::{self, ReplicationStatus};
+use super::snapshot::{Snapshot, SnapshotConfig, SnapshotFormat};
 use super::server::PubSubServer;
-use uuid::ParseError;
+use super::wal::WalEntry;
+use uuid::Uuid;
 
 type Code = status::Custom<()>;
 
 const OK: Code = status::Custom(Status::Ok, ());
 
-lazy_static! {
-    static ref NO_HEADET_ERR: String = {
-        format!("HTTP request must have {} header containing Uuid of a subscriber", CALLBACK_HEADER)
-    };
+struct Headers {
+    v: HashMap<String, String>,
+    // content_type, content_encoding - stored as the content of the message, not with its headers
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+}
+
+fn validate_name(limits: &Limits, field: &'static str, value: &str) -> Result<(), PubSubError> {
+    let invalid = |reason: String| PubSubError::InvalidName { field, value: value.to_string(), reason };
+
+    if value.is_empty() {
+        Err(invalid("must not be empty".to_string()))
+    } else if value.len() > limits.max_name_length {
+        Err(invalid(format!("longer than {} characters", limits.max_name_length)))
+    } else if !value.chars().all(|c| limits.is_allowed_char(c)) {
+        Err(invalid(format!("only alphanumerics and '{}' are allowed", limits.name_chars)))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_headers(limits: &Limits, headers: &Headers) -> Result<(), PubSubError> {
+    let count = headers.v.len();
+    if count > limits.max_headers {
+        return Err(PubSubError::TooManyHeaders { count, limit: limits.max_headers });
+    }
+
+    let size: usize = headers.v.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if size > limits.max_headers_size {
+        return Err(PubSubError::HeadersTooLarge { size, limit: limits.max_headers_size });
+    }
+    Ok(())
 }
 
-struct Headers { v: HashMap<String, String> }
+fn validate_message(limits: &Limits, topic: &str, subject: &str, headers: &Headers)
+                    -> Result<(), PubSubError> {
+    validate_name(limits, "topic", topic)?;
+    validate_name(limits, "subject", subject)?;
+    validate_headers(limits, headers)
+}
+
+impl<'a> FromParam<'a> for Retention {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        match param.as_str() {
+            "retained" => Ok(Retention::Retained),
+            "transient" => Ok(Retention::Transient),
+            _ => Err(param)
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for SnapshotFormat {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        match param.as_str() {
+            "json" => Ok(SnapshotFormat::Json),
+            "binary" => Ok(SnapshotFormat::Binary),
+            _ => Err(param)
+        }
+    }
+}
+
+// BulkItem - one subject of a bulk publish, body is either text or base64 encoded bytes
+#[derive(Deserialize)]
+struct BulkItem {
+    subject: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: String,
+    body_base64: Option<String>,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TransactionOp {
+    Publish {
+        topic: String,
+        #[serde(flatten)]
+        item: BulkItem,
+    },
+    Remove {
+        topic: String,
+        subject: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct TransactionRequest {
+    operations: Vec<TransactionOp>,
+}
+
+// RemoveQuery - query of bulk removal, exactly one of prefix or glob must be given
+#[derive(FromForm)]
+struct RemoveQuery {
+    prefix: Option<String>,
+    glob: Option<String>,
+}
+
+// AuditParams - filters of the audit log, since is an RFC 3339 timestamp
+#[derive(FromForm)]
+struct AuditParams {
+    action: Option<String>,
+    principal: Option<String>,
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct NewApiKey {
+    name: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+// TopicOwner - node is the owner of the topic unless it is this node
+#[derive(Serialize)]
+struct TopicOwner {
+    topic: String,
+    local: bool,
+    node: Option<Node>,
+}
+
+#[derive(Serialize)]
+struct ScheduledView {
+    id: String,
+    topic: String,
+    subject: String,
+    due: String,
+}
+
+impl<'a> From<&'a ScheduledMessage> for ScheduledView {
+    fn from(s: &ScheduledMessage) -> Self {
+        ScheduledView {
+            id: format!("{}", s.id.hyphenated()),
+            topic: s.message.topic.clone(),
+            subject: s.message.subject.clone(),
+            due: s.due.to_rfc3339(),
+        }
+    }
+}
 
 impl<'a, 'r> FromRequest<'a, 'r> for Headers {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Headers, ()> {
+        let headers = request.headers();
         Outcome::Success(Headers {
-            v: request.headers()
-                .iter()
+            v: headers.iter()
+                .filter(|h| !is_credential(h.name.as_str()) && !is_content(h.name.as_str()))
                 .map(|h| (h.name.to_string(), h.value.to_string()))
-                .collect()
+                .collect(),
+            content_type: headers.get_one(CONTENT_TYPE_HEADER).map(|v| v.to_string()),
+            content_encoding: headers.get_one(CONTENT_ENCODING_HEADER).map(|v| v.to_string()),
         })
     }
 }
 
+fn is_content(name: &str) -> bool {
+    name.eq_ignore_ascii_case(CONTENT_TYPE_HEADER) || name.eq_ignore_ascii_case(CONTENT_ENCODING_HEADER)
+}
+
+// is_credential - headers proving identity are never stored with messages nor forwarded
+fn is_credential(name: &str) -> bool {
+    name.eq_ignore_ascii_case(AUTHORIZATION_HEADER) || name.eq_ignore_ascii_case(PUBLISHER_TOKEN_HEADER)
+}
+
+// PublisherToken - Publisher-Token header, proves ownership of the publisher in the path
+struct PublisherToken(Option<String>);
+
+impl PublisherToken {
+    fn get(&self) -> Option<&str> {
+        self.0.as_ref().map(|t| t.as_str())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for PublisherToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<PublisherToken, ()> {
+        Outcome::Success(PublisherToken(request.headers().get_one(PUBLISHER_TOKEN_HEADER).map(|t| t.to_string())))
+    }
+}
+
+// RegisteredPublisher - id of the publisher in the body, its token in the Publisher-Token header
+struct RegisteredPublisher {
+    id: String,
+    token: String,
+}
+
+impl<'r> Responder<'r> for RegisteredPublisher {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = self.id.respond_to(request)?;
+        response.set_raw_header(PUBLISHER_TOKEN_HEADER, self.token);
+        Ok(response)
+    }
+}
+
+// RequestUri - path and query of the request, used to redirect to the node owning the topic
+struct RequestUri(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestUri {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RequestUri, ()> {
+        Outcome::Success(RequestUri(request.uri().as_str().to_string()))
+    }
+}
+
+fn check_owner(server: &PubSubServer, topic: &str, uri: &RequestUri) -> Result<(), PubSubError> {
+    redirect(server.topic_owner(topic), uri)
+}
+
+// check_subscriber_owner - calls with a subscriber id go to the node the subscriber was created on
+fn check_subscriber_owner(server: &PubSubServer, id: &Uuid, uri: &RequestUri) -> Result<(), PubSubError> {
+    redirect(server.subscriber_owner(id), uri)
+}
+
+fn redirect(owner: Option<Node>, uri: &RequestUri) -> Result<(), PubSubError> {
+    match owner {
+        Some(node) => Err(PubSubError::WrongNode { location: format!("{}{}", node.url, uri.0), node: node.name }),
+        None => Ok(())
+    }
+}
+
+// authorize - ACL check of the principal before the request reaches the server
+fn authorize(server: &PubSubServer, principal: &Principal, action: Action,
+             topic: &str) -> Result<(), PubSubError> {
+    if principal.allows(action, topic) {
+        return Ok(());
+    }
+    server.audit(&principal.actor(), AuditAction::AccessDenied, topic.to_string(),
+                 Some(format!("{}", action)));
+    Err(PubSubError::Forbidden(format!("{} may not {} topic {}", principal.name, action, topic)))
+}
+
+// authorize_publisher - calls of a publisher need the publish grant of every topic it has retained
+// messages on, removing it removes them
+fn authorize_publisher(server: &PubSubServer, principal: &Principal, id: &Uuid) -> Result<(), PubSubError> {
+    server.publisher_topics(id)?.iter()
+        .map(|topic| authorize(server, principal, Action::Publish, topic))
+        .collect()
+}
+
+// authorize_bridge - bridge deliveries need the key of the remote hub or an admin key
+fn authorize_bridge(server: &PubSubServer, principal: &Principal, bridge: &str) -> Result<(), PubSubError> {
+    if principal.may_bridge(bridge) {
+        return Ok(());
+    }
+    server.audit(&principal.actor(), AuditAction::AccessDenied, format!("bridge {}", bridge), None);
+    Err(PubSubError::Forbidden(format!("{} may not deliver messages of bridge {}", principal.name, bridge)))
+}
+
 #[get("/")]
 fn index() -> &'static str {
     "Hello from Pub-Sub-Server!"
 }
 
 #[get("/subscribe/<topic>")]
-fn subscribe<'r>(server: State<PubSubServer>, topic: String, headers: Headers)
-                 -> Result<String, NotFound<&'static str>> {
+fn subscribe<'r>(server: State<PubSubServer>, limits: State<Limits>, topic: String, headers: Headers, uri: RequestUri,
+                 principal: Principal) -> Result<String, PubSubError> {
+    authorize(&server, &principal, Action::Subscribe, &topic)?;
+    validate_name(&limits, "topic", &topic)?;
+    check_owner(&server, &topic, &uri)?;
     let l = headers.v.get(CALLBACK_HEADER)
-        .ok_or(NotFound(NO_HEADET_ERR.as_ref()))?;
+        .ok_or(PubSubError::MissingHeader(CALLBACK_HEADER))?;
+    server.check_callback(l).map_err(|reason| {
+        println!("rejected callback {} of {}: {}", l, principal.name, reason);
+        PubSubError::InvalidCallback { callback: l.to_string(), reason }
+    })?;
+    let batching = parse_batching(&headers)?;
+    let delivery = parse_delivery(&headers)?;
 
-    println!("subscribing on topic {} location: {}", topic, l);
-    let id = server.add_pending_subscriber(l.to_string(), topic);
+    let id = server.add_pending_subscriber(l.to_string(), topic, batching, delivery, &principal.actor())?;
     Ok(format!("{}", id))
 }
 
+fn parse_delivery(headers: &Headers) -> Result<Delivery, PubSubError> {
+    match headers.v.get(DELIVERY_HEADER).map(|v| v.as_str()) {
+        None | Some("full") => Ok(Delivery::Full),
+        Some("patch") => Ok(Delivery::Patch),
+        Some(other) => Err(PubSubError::InvalidHeader {
+            name: DELIVERY_HEADER,
+            reason: format!("must be full or patch, got: {}", other),
+        })
+    }
+}
+
+const DEFAULT_BATCH_SIZE: usize = 100;
+const DEFAULT_BATCH_WINDOW_MS: u64 = 1000;
+
+// parse_batching - a subscriber opts in to batched delivery by sending any of the batch headers
+fn parse_batching(headers: &Headers) -> Result<Option<BatchPolicy>, PubSubError> {
+    let size = parse_number(headers, BATCH_SIZE_HEADER)?;
+    let window = parse_number(headers, BATCH_WINDOW_HEADER)?;
+    if size == Some(0) {
+        return Err(PubSubError::InvalidHeader {
+            name: BATCH_SIZE_HEADER,
+            reason: "must be greater than zero".to_string(),
+        });
+    }
+
+    Ok(match (size, window) {
+        (None, None) => None,
+        (size, window) => Some(BatchPolicy {
+            max_size: size.map_or(DEFAULT_BATCH_SIZE, |s| s as usize),
+            window_ms: window.unwrap_or(DEFAULT_BATCH_WINDOW_MS),
+        })
+    })
+}
+
+fn parse_number(headers: &Headers, name: &'static str) -> Result<Option<u64>, PubSubError> {
+    match headers.v.get(name) {
+        Some(v) => v.parse::<u64>()
+            .map(Some)
+            .map_err(|_| PubSubError::InvalidHeader {
+                name,
+                reason: format!("must be a non-negative number, got: {}", v),
+            }),
+        None => Ok(None)
+    }
+}
+
 #[delete("/subscribe/<id>")]
-fn unsubscribe(server: State<PubSubServer>, id: UUID) -> Result<String, ParseError> {
+fn unsubscribe(server: State<PubSubServer>, id: UUID, uri: RequestUri,
+               principal: Principal) -> Result<String, PubSubError> {
+    check_subscriber_owner(&server, &id, &uri)?;
+    authorize(&server, &principal, Action::Subscribe, &server.subscriber_topic(&id)?)?;
     let uuid = *id;
     let h_uuid = format!("{}", uuid.hyphenated());
-    println!("unsubscribe id {:?}", h_uuid);
-    server.remove_subscriber(uuid);
+    server.remove_subscriber(uuid, &principal.actor())?;
     Ok(h_uuid)
 }
 
 #[head("/subscribe/<id>")]
-fn touch_subscriber(server: State<PubSubServer>, id: UUID) -> Code {
-    server.touch_subscriber(*id);
-    OK
+fn touch_subscriber(server: State<PubSubServer>, id: UUID, uri: RequestUri,
+                    principal: Principal) -> Result<Code, PubSubError> {
+    check_subscriber_owner(&server, &id, &uri)?;
+    authorize(&server, &principal, Action::Subscribe, &server.subscriber_topic(&id)?)?;
+    server.touch_subscriber(*id).map(|_| OK)
 }
 
 #[get("/publish/<id>")]
-fn add_publisher(server: State<PubSubServer>, id: UUID) -> Result<String, ParseError> {
+fn add_publisher(server: State<PubSubServer>, id: UUID,
+                 principal: Principal) -> Result<RegisteredPublisher, PubSubError> {
     let uuid = *id;
     let h_uuid = format!("{}", uuid.hyphenated());
-    println!("adding publisher {}", h_uuid);
-    server.add_publisher(uuid);
-    Ok(h_uuid)
+    let token = server.add_publisher(uuid, &principal.actor())?;
+    Ok(RegisteredPublisher { id: h_uuid, token })
 }
 
 #[delete("/publish/<id>")]
-fn remove_publisher(server: State<PubSubServer>, id: UUID) -> Code {
-    let uuid = *id;
-    server.remove_publisher(uuid);
-    OK
+fn remove_publisher(server: State<PubSubServer>, id: UUID,
+                    token: PublisherToken, principal: Principal) -> Result<Code, PubSubError> {
+    server.verify_publisher(*id, token.get(), &principal.actor())?;
+    authorize_publisher(&server, &principal, &id)?;
+    server.remove_publisher(*id, &principal.actor()).map(|_| OK)
 }
 
 #[head("/publish/<id>")]
-fn touch_publisher(server: State<PubSubServer>, id: UUID) -> Result<(), NotFound<String>> {
-    let uuid = *id;
-    server.touch_publisher(uuid).map_err(|e| NotFound(e))
+fn touch_publisher(server: State<PubSubServer>, id: UUID,
+                   token: PublisherToken, principal: Principal) -> Result<Code, PubSubError> {
+    server.verify_publisher(*id, token.get(), &principal.actor())?;
+    authorize_publisher(&server, &principal, &id)?;
+    server.touch_publisher(*id).map(|_| OK)
 }
 
-#[put("/publish/<topic>/<publisher>/<subject>", data = "<body>")]
-fn publish(server: State<PubSubServer>, topic: String, publisher: UUID, subject: String,
-           headers: Headers, body: String) //TODO:  set max body size
-           -> Code {
-    server.publish_message(Message { publisher: *publisher, topic, subject, headers: headers.v, body });
-    OK
+#[put("/publish/<topic>/<publisher>/<subject>", data = "<data>")]
+fn publish(server: State<PubSubServer>, limits: State<Limits>, topic: String, publisher: UUID,
+           subject: String, headers: Headers, data: Data, uri: RequestUri,
+           token: PublisherToken, principal: Principal) -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Publish, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_message(&limits, &topic, &subject, &headers)?;
+    let body = read_body(&limits, data)?;
+    let ttl = parse_ttl(&headers)?;
+    let retention = parse_retention(&headers)?;
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.publish_message(Message::new(*publisher, topic, subject, headers.v, body)
+        .with_content(headers.content_type, headers.content_encoding)
+        .with_ttl(ttl)
+        .with_retention(retention))
+        .map(|_| OK)
+}
+
+#[patch("/publish/<topic>/<publisher>/<subject>", data = "<data>")]
+fn patch_subject(server: State<PubSubServer>, limits: State<Limits>, topic: String, publisher: UUID,
+                 subject: String, headers: Headers, data: Data, uri: RequestUri, token: PublisherToken,
+                 principal: Principal)
+                 -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Publish, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_message(&limits, &topic, &subject, &headers)?;
+    let body = read_body(&limits, data)?;
+    let ttl = parse_ttl(&headers)?;
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.patch_message(Message::new(*publisher, topic, subject, headers.v, body).with_ttl(ttl))
+        .map(|_| OK)
+}
+
+// read_body - reads at most max_body_size bytes, one more byte tells that the limit is exceeded
+fn read_body(limits: &Limits, data: Data) -> Result<Vec<u8>, PubSubError> {
+    let mut body = Vec::new();
+    data.open().take(limits.max_body_size + 1).read_to_end(&mut body)
+        .map_err(|e| PubSubError::MalformedBody(format!("{}", e)))?;
+
+    if body.len() as u64 > limits.max_body_size {
+        Err(PubSubError::PayloadTooLarge { limit: limits.max_body_size })
+    } else {
+        Ok(body)
+    }
+}
+
+fn parse_retention(headers: &Headers) -> Result<Retention, PubSubError> {
+    match headers.v.get(RETAIN_HEADER).map(|r| r.as_str()) {
+        Some("true") | None => Ok(Retention::Retained),
+        Some("false") => Ok(Retention::Transient),
+        Some(r) => Err(PubSubError::InvalidHeader {
+            name: RETAIN_HEADER,
+            reason: format!("must be true or false, got: {}", r),
+        })
+    }
+}
+
+fn parse_ttl(headers: &Headers) -> Result<Option<u64>, PubSubError> {
+    match headers.v.get(TTL_HEADER) {
+        Some(t) => t.parse::<u64>()
+            .map(Some)
+            .map_err(|_| PubSubError::InvalidHeader {
+                name: TTL_HEADER,
+                reason: format!("must be a number of seconds, got: {}", t),
+            }),
+        None => Ok(None)
+    }
+}
+
+#[put("/publish/<topic>/<publisher>", data = "<data>")]
+fn publish_bulk(server: State<PubSubServer>, limits: State<Limits>, topic: String, publisher: UUID,
+                headers: Headers, content_type: Option<&ContentType>, data: Data, uri: RequestUri,
+                token: PublisherToken, principal: Principal)
+                -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Publish, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_name(&limits, "topic", &topic)?;
+    validate_headers(&limits, &headers)?;
+    let body = read_body(&limits, data)?;
+    let retention = parse_retention(&headers)?;
+    let ndjson = content_type.map_or(false, |ct| *ct == ContentType::new("application", "x-ndjson"));
+    let items = parse_bulk(&body, ndjson)?;
+
+    let msgs = items.into_iter()
+        .map(|item| bulk_message(&limits, *publisher, &topic, item, retention))
+        .collect::<Result<Vec<Message>, PubSubError>>()?;
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.publish_messages(*publisher, msgs).map(|_| OK)
+}
+
+fn parse_bulk(body: &[u8], ndjson: bool) -> Result<Vec<BulkItem>, PubSubError> {
+    let malformed = |e: serde_json::Error| PubSubError::MalformedBody(format!("{}", e));
+    if ndjson {
+        String::from_utf8_lossy(body)
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| serde_json::from_str::<BulkItem>(l).map_err(malformed))
+            .collect()
+    } else {
+        serde_json::from_slice::<Vec<BulkItem>>(body).map_err(malformed)
+    }
+}
+
+fn bulk_message(limits: &Limits, publisher: Uuid, topic: &String, item: BulkItem,
+                retention: Retention) -> Result<Message, PubSubError> {
+    validate_name(limits, "subject", &item.subject)?;
+    let headers = Headers {
+        v: item.headers.into_iter().filter(|&(ref k, _)| !is_content(k)).collect(),
+        content_type: item.content_type,
+        content_encoding: item.content_encoding,
+    };
+    validate_headers(limits, &headers)?;
+    let body = match item.body_base64 {
+        Some(ref b) => base64::decode(b)
+            .map_err(|e| PubSubError::MalformedBody(format!("invalid body_base64: {}", e)))?,
+        None => item.body.into_bytes(),
+    };
+
+    Ok(Message::new(publisher, topic.clone(), item.subject, headers.v, body)
+        .with_content(headers.content_type, headers.content_encoding)
+        .with_ttl(item.ttl)
+        .with_retention(retention))
+}
+
+#[post("/transaction/<publisher>", data = "<data>")]
+fn transaction(server: State<PubSubServer>, limits: State<Limits>, publisher: UUID, headers: Headers,
+               data: Data, uri: RequestUri, token: PublisherToken,
+               principal: Principal) -> Result<String, PubSubError> {
+    validate_headers(&limits, &headers)?;
+    let body = read_body(&limits, data)?;
+    let request = serde_json::from_slice::<TransactionRequest>(&body)
+        .map_err(|e| PubSubError::MalformedBody(format!("{}", e)))?;
+
+    let ops = request.operations.into_iter()
+        .map(|op| match op {
+            TransactionOp::Publish { topic, item } => {
+                validate_name(&limits, "topic", &topic)?;
+                bulk_message(&limits, *publisher, &topic, item, Retention::Retained)
+                    .map(BatchEntry::Publish)
+            }
+            TransactionOp::Remove { topic, subject } => {
+                validate_name(&limits, "topic", &topic)?;
+                validate_name(&limits, "subject", &subject)?;
+                Ok(BatchEntry::Remove(Message::new(*publisher, topic, subject, HashMap::new(), Vec::new())))
+            }
+        })
+        .collect::<Result<Vec<BatchEntry>, PubSubError>>()?;
+    for op in &ops {
+        match *op {
+            BatchEntry::Publish(ref m) | BatchEntry::Remove(ref m) =>
+                authorize(&server, &principal, Action::Publish, &m.topic)?
+        }
+    }
+    check_transaction_owner(&server, &ops, &uri)?;
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+
+    server.commit_transaction(*publisher, ops)
+        .map(|id| format!("{}", id.hyphenated()))
+}
+
+// check_transaction_owner - a transaction is committed on the node owning all of its topics
+fn check_transaction_owner(server: &PubSubServer, ops: &[BatchEntry], uri: &RequestUri)
+                           -> Result<(), PubSubError> {
+    let mut topics: Vec<&String> = ops.iter()
+        .map(|op| match *op {
+            BatchEntry::Publish(ref m) | BatchEntry::Remove(ref m) => &m.topic
+        })
+        .collect();
+    topics.sort();
+    topics.dedup();
+
+    let owners: Vec<Option<String>> = topics.iter()
+        .map(|t| server.topic_owner(t).map(|n| n.name))
+        .collect();
+    if owners.iter().any(|o| o != &owners[0]) {
+        return Err(PubSubError::TopicsOnSeveralNodes(topics.into_iter().cloned().collect()));
+    }
+    match topics.first() {
+        Some(topic) => check_owner(server, topic, uri),
+        None => Ok(())
+    }
+}
+
+// join_publisher - called by another node of the cluster registering a publisher with its token
+#[put("/cluster/publisher/<id>")]
+fn join_publisher(server: State<PubSubServer>, id: UUID, token: PublisherToken,
+                  admin: Admin) -> Result<Code, PubSubError> {
+    let token = token.0.ok_or(PubSubError::MissingHeader(PUBLISHER_TOKEN_HEADER))?;
+    server.join_publisher(*id, token, &admin.0.actor()).map(|_| OK)
+}
+
+// leave_publisher - called by another node of the cluster removing a publisher, a publisher that is
+// not here is removed already
+#[delete("/cluster/publisher/<id>")]
+fn leave_publisher(server: State<PubSubServer>, id: UUID, admin: Admin) -> Result<Code, PubSubError> {
+    match server.leave_publisher(*id, &admin.0.actor()) {
+        Ok(_) | Err(PubSubError::UnknownPublisher(_)) => Ok(OK),
+        Err(e) => Err(e)
+    }
+}
+
+// clear_publisher_token - called by another node of the cluster which failed to issue a token to a
+// publisher registered without a token
+#[delete("/cluster/publisher/<id>/token")]
+fn clear_publisher_token(server: State<PubSubServer>, id: UUID, _admin: Admin) -> Result<Code, PubSubError> {
+    server.clear_publisher_token(*id).map(|_| OK)
+}
+
+#[get("/cluster/owner/<topic>")]
+fn topic_owner(server: State<PubSubServer>, topic: String, _principal: Principal) -> Json<TopicOwner> {
+    let node = server.topic_owner(&topic);
+    Json(TopicOwner { local: node.is_none(), topic, node })
 }
 
 #[delete("/publish/<topic>/<publisher>/<subject>")]
-fn remove(server: State<PubSubServer>, publisher: UUID, topic: String, subject: String, headers: Headers) -> Code {
-    server.remove(Message {
-        publisher: *publisher,
-        topic,
-        subject,
-        headers: headers.v,
-        body: ""
-            .to_string(),
-    });
+fn remove(server: State<PubSubServer>, limits: State<Limits>, publisher: UUID, topic: String,
+          subject: String, headers: Headers, uri: RequestUri,
+          token: PublisherToken, principal: Principal) -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Publish, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_message(&limits, &topic, &subject, &headers)?;
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.remove(Message::new(*publisher, topic, subject, headers.v, Vec::new()))
+        .map(|_| OK)
+}
+
+#[delete("/publish/<topic>/<publisher>?<query>")]
+fn remove_matching(server: State<PubSubServer>, limits: State<Limits>, publisher: UUID, topic: String,
+                   query: RemoveQuery, uri: RequestUri,
+                   token: PublisherToken, principal: Principal) -> Result<Json<Vec<String>>, PubSubError> {
+    authorize(&server, &principal, Action::Publish, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_name(&limits, "topic", &topic)?;
+    let filter = match (query.prefix, query.glob) {
+        (Some(prefix), None) => SubjectFilter::Prefix(prefix),
+        (None, Some(glob)) => SubjectFilter::Glob(glob),
+        _ => return Err(PubSubError::InvalidFilter("exactly one of prefix or glob is required".to_string()))
+    };
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.remove_matching(*publisher, &topic, &filter).map(Json)
+}
+
+// bridge_receive - delivery of a bridged topic by a remote hub, the same protocol as of subscribers
+#[post("/bridge/<bridge>/receive/<topic>/<publisher>/<subject>", data = "<data>")]
+fn bridge_receive(server: State<PubSubServer>, limits: State<Limits>, bridge: String, topic: String,
+                  publisher: UUID, subject: String, headers: Headers, data: Data, uri: RequestUri,
+                  principal: Principal) -> Result<Code, PubSubError> {
+    authorize_bridge(&server, &principal, &bridge)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_message(&limits, &topic, &subject, &headers)?;
+    let body = read_body(&limits, data)?;
+    server.receive_bridged(&bridge, Message::new(*publisher, topic, subject, headers.v, body)
+        .with_content(headers.content_type, headers.content_encoding))
+        .map(|_| OK)
+}
+
+#[delete("/bridge/<bridge>/remove/<topic>/<publisher>/<subject>")]
+fn bridge_remove(server: State<PubSubServer>, limits: State<Limits>, bridge: String, topic: String,
+                 publisher: UUID, subject: String, headers: Headers, uri: RequestUri,
+                 principal: Principal) -> Result<Code, PubSubError> {
+    authorize_bridge(&server, &principal, &bridge)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_message(&limits, &topic, &subject, &headers)?;
+    server.remove_bridged(&bridge, Message::new(*publisher, topic, subject, headers.v, Vec::new()))
+        .map(|_| OK)
+}
+
+#[get("/topic/<topic>")]
+fn topic_config(server: State<PubSubServer>, topic: String, uri: RequestUri, principal: Principal)
+                -> Result<Json<TopicConfig>, PubSubError> {
+    authorize(&server, &principal, Action::Read, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.topic_config(&topic).map(Json)
+}
+
+#[put("/topic/<topic>", data = "<config>")]
+fn configure_topic(server: State<PubSubServer>, limits: State<Limits>, topic: String,
+                   config: Json<TopicConfig>, uri: RequestUri,
+                   principal: Principal) -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Admin, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_name(&limits, "topic", &topic)?;
+    server.configure_topic(topic, config.into_inner()).map(|_| OK)
+}
+
+#[delete("/topic/<topic>")]
+fn remove_topic(server: State<PubSubServer>, topic: String, uri: RequestUri, principal: Principal)
+                -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Admin, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.remove_topic(&topic, &principal.actor()).map(|_| OK)
+}
+
+#[put("/topic/<topic>/schema", data = "<schema>")]
+fn register_schema(server: State<PubSubServer>, limits: State<Limits>, topic: String,
+                   schema: Json<serde_json::Value>, uri: RequestUri,
+                   principal: Principal) -> Result<String, PubSubError> {
+    authorize(&server, &principal, Action::Admin, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_name(&limits, "topic", &topic)?;
+    server.register_schema(&topic, schema.into_inner()).map(|v| format!("{}", v))
+}
+
+#[get("/topic/<topic>/schema")]
+fn active_schema(server: State<PubSubServer>, topic: String, uri: RequestUri, principal: Principal)
+                 -> Result<Json<TopicSchema>, PubSubError> {
+    authorize(&server, &principal, Action::Read, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.topic_schema(&topic, None).map(Json)
+}
+
+#[get("/topic/<topic>/schema/<version>")]
+fn topic_schema(server: State<PubSubServer>, topic: String, version: u32, uri: RequestUri,
+                principal: Principal)
+                -> Result<Json<TopicSchema>, PubSubError> {
+    authorize(&server, &principal, Action::Read, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.topic_schema(&topic, Some(version)).map(Json)
+}
+
+#[put("/topic/<topic>/schema/<version>")]
+fn activate_schema(server: State<PubSubServer>, topic: String, version: u32, uri: RequestUri,
+                   principal: Principal)
+                   -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Admin, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.activate_schema(&topic, version).map(|_| OK)
+}
+
+#[delete("/topic/<topic>/schema")]
+fn deactivate_schema(server: State<PubSubServer>, topic: String, uri: RequestUri, principal: Principal)
+                     -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Admin, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.deactivate_schema(&topic).map(|_| OK)
+}
+
+#[get("/admin/snapshot/<format>")]
+fn download_snapshot(server: State<PubSubServer>, format: SnapshotFormat, _admin: Admin)
+                     -> Result<Content<Vec<u8>>, PubSubError> {
+    let bytes = server.snapshot()?.encode(format).map_err(PubSubError::SnapshotFailed)?;
+    let content_type = match format {
+        SnapshotFormat::Json => ContentType::JSON,
+        SnapshotFormat::Binary => ContentType::Binary,
+    };
+    Ok(Content(content_type, bytes))
+}
+
+// export_snapshot - writes a snapshot to a file in snapshot_dir, the format follows the extension
+#[post("/admin/snapshot/<name>")]
+fn export_snapshot(server: State<PubSubServer>, config: State<SnapshotConfig>, name: String,
+                   _admin: Admin) -> Result<String, PubSubError> {
+    let path = config.path(&name)?;
+    server.snapshot()?.write(&path).map_err(PubSubError::SnapshotFailed)?;
+    println!("exported snapshot to {}", path.display());
+    Ok(name)
+}
+
+#[get("/admin/keys")]
+fn api_keys(keys: State<ApiKeys>, _admin: Admin) -> Json<Vec<KeyView>> {
+    Json(keys.list())
+}
+
+// create_api_key - the generated key is returned only once
+#[post("/admin/keys", data = "<key>")]
+fn create_api_key(server: State<PubSubServer>, keys: State<ApiKeys>, key: Json<NewApiKey>,
+                  admin: Admin) -> Result<Json<ApiKey>, PubSubError> {
+    let key = key.into_inner();
+    let created = keys.create(key.name, key.admin)?;
+    let detail = if created.admin { "admin key" } else { "key" };
+    server.audit(&admin.0.actor(), AuditAction::CreateApiKey, created.name.clone(), Some(detail.to_string()));
+    Ok(Json(created))
+}
+
+#[delete("/admin/keys/<name>")]
+fn revoke_api_key(server: State<PubSubServer>, keys: State<ApiKeys>, name: String,
+                  admin: Admin) -> Result<Code, PubSubError> {
+    keys.revoke(&name)?;
+    server.audit(&admin.0.actor(), AuditAction::RevokeApiKey, name, None);
+    Ok(OK)
+}
+
+#[get("/admin/audit", rank = 2)]
+fn audit_log(server: State<PubSubServer>, _admin: Admin) -> Result<Json<Vec<AuditEvent>>, PubSubError> {
+    server.audit_events(&AuditQuery::default()).map(Json)
+}
+
+#[get("/admin/audit?<params>")]
+fn query_audit_log(server: State<PubSubServer>, params: AuditParams,
+                   _admin: Admin) -> Result<Json<Vec<AuditEvent>>, PubSubError> {
+    let query = parse_audit_query(params)?;
+    server.audit_events(&query).map(Json)
+}
+
+fn parse_audit_query(params: AuditParams) -> Result<AuditQuery, PubSubError> {
+    let action = match params.action {
+        Some(a) => Some(a.parse::<AuditAction>().map_err(PubSubError::InvalidAuditQuery)?),
+        None => None
+    };
+    let since = match params.since {
+        Some(since) => Some(DateTime::parse_from_rfc3339(&since)
+            .map_err(|e| PubSubError::InvalidAuditQuery(format!("since is not an RFC 3339 timestamp: {}", e)))?
+            .with_timezone(&Local)),
+        None => None
+    };
+    Ok(AuditQuery { action, principal: params.principal, since, limit: params.limit })
+}
+
+#[error(401)]
+fn unauthorized() -> PubSubError {
+    PubSubError::Unauthorized("missing or invalid API key".to_string())
+}
+
+#[error(403)]
+fn forbidden() -> PubSubError {
+    PubSubError::Forbidden("admin API key required".to_string())
+}
+
+#[get("/replication")]
+fn replication_status(server: State<PubSubServer>, _admin: Admin) -> Json<ReplicationStatus> {
+    Json(server.replication_status())
+}
+
+// replicate_snapshot - called by the primary, the body is a binary snapshot
+#[put("/replication/snapshot", data = "<data>")]
+fn replicate_snapshot(server: State<PubSubServer>, data: Data,
+                      _admin: Admin) -> Result<Code, PubSubError> {
+    let mut body = Vec::new();
+    data.open().read_to_end(&mut body)
+        .map_err(|e| PubSubError::MalformedBody(format!("{}", e)))?;
+    let snapshot = Snapshot::decode(&body, replication::SNAPSHOT_FORMAT).map_err(PubSubError::MalformedBody)?;
+    server.replicate_snapshot(snapshot).map(|_| OK)
+}
+
+#[post("/replication/entries", data = "<entries>")]
+fn replicate_entries(server: State<PubSubServer>, entries: Json<Vec<WalEntry>>,
+                     _admin: Admin) -> Result<Code, PubSubError> {
+    server.replicate_entries(entries.into_inner()).map(|_| OK)
+}
+
+#[post("/replication/promote")]
+fn promote(server: State<PubSubServer>, _admin: Admin) -> Code {
+    server.promote();
     OK
 }
+
+#[put("/topics/auto_create/<enabled>")]
+fn auto_create_topics(server: State<PubSubServer>, enabled: bool,
+                      _admin: Admin) -> Result<Code, PubSubError> {
+    server.set_auto_create_topics(enabled).map(|_| OK)
+}
+
+#[put("/topic/<topic>/retention/<retention>")]
+fn set_retention(server: State<PubSubServer>, topic: String, retention: Retention, uri: RequestUri,
+                 principal: Principal)
+                 -> Result<Code, PubSubError> {
+    authorize(&server, &principal, Action::Admin, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    server.set_topic_retention(topic, retention).map(|_| OK)
+}
+
+#[put("/schedule/<topic>/<publisher>/<subject>", data = "<data>")]
+fn schedule(server: State<PubSubServer>, limits: State<Limits>, topic: String, publisher: UUID,
+            subject: String, headers: Headers, data: Data, uri: RequestUri, token: PublisherToken,
+            principal: Principal)
+            -> Result<String, PubSubError> {
+    authorize(&server, &principal, Action::Publish, &topic)?;
+    check_owner(&server, &topic, &uri)?;
+    validate_message(&limits, &topic, &subject, &headers)?;
+    let body = read_body(&limits, data)?;
+    let ttl = parse_ttl(&headers)?;
+    let retention = parse_retention(&headers)?;
+    let when = parse_schedule(&headers)?;
+    let msg = Message::new(*publisher, topic, subject, headers.v, body)
+        .with_content(headers.content_type, headers.content_encoding)
+        .with_ttl(ttl)
+        .with_retention(retention);
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.schedule_message(msg, when)
+        .map(|id| format!("{}", id.hyphenated()))
+}
+
+#[get("/schedule/<publisher>")]
+fn scheduled(server: State<PubSubServer>, publisher: UUID,
+             token: PublisherToken,
+             principal: Principal) -> Result<Json<Vec<ScheduledView>>, PubSubError> {
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.scheduled_messages(*publisher)
+        .map(|s| Json(s.iter().map(ScheduledView::from).collect()))
+}
+
+#[delete("/schedule/<publisher>/<id>")]
+fn cancel_scheduled(server: State<PubSubServer>, publisher: UUID, id: UUID, token: PublisherToken,
+                    principal: Principal)
+                    -> Result<Code, PubSubError> {
+    server.verify_publisher(*publisher, token.get(), &principal.actor())?;
+    server.cancel_scheduled(*publisher, *id).map(|_| OK)
+}
+
+fn parse_schedule(headers: &Headers) -> Result<Schedule, PubSubError> {
+    match (headers.v.get(PUBLISH_AT_HEADER), headers.v.get(DELAY_HEADER)) {
+        (Some(at), None) => DateTime::parse_from_rfc3339(at)
+            .map(|t| Schedule::At(t.with_timezone(&Local)))
+            .map_err(|_| PubSubError::InvalidHeader {
+                name: PUBLISH_AT_HEADER,
+                reason: format!("must be an RFC 3339 timestamp, got: {}", at),
+            }),
+        (None, Some(delay)) => delay.parse::<u64>()
+            .map(Schedule::After)
+            .map_err(|_| PubSubError::InvalidHeader {
+                name: DELAY_HEADER,
+                reason: format!("must be a number of seconds, got: {}", delay),
+            }),
+        _ => Err(PubSubError::InvalidHeader {
+            name: DELAY_HEADER,
+            reason: format!("either {} or {} header is required", PUBLISH_AT_HEADER, DELAY_HEADER),
+        })
+    }
+}
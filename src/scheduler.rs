@@ -0,0 +1,88 @@
+use chrono::Duration;
+use chrono::prelude::*;
+use models::Message;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+pub enum Schedule {
+    At(DateTime<Local>),
+    After(u64),
+}
+
+impl Schedule {
+    pub fn due(&self, now: DateTime<Local>) -> DateTime<Local> {
+        match *self {
+            Schedule::At(t) => t,
+            Schedule::After(secs) => now + Duration::seconds(secs as i64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: Uuid,
+    pub due: DateTime<Local>,
+    pub message: Message,
+}
+
+// Scheduler - holds messages which become visible to subscribers at their due time
+pub struct Scheduler {
+    pending: Mutex<HashMap<Uuid, ScheduledMessage>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler { pending: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn add(&self, scheduled: ScheduledMessage) {
+        self.pending.lock().unwrap().insert(scheduled.id, scheduled);
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<ScheduledMessage> {
+        self.pending.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &Uuid) -> Option<ScheduledMessage> {
+        self.pending.lock().unwrap().remove(id)
+    }
+
+    pub fn all(&self) -> Vec<ScheduledMessage> {
+        self.pending.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.pending.lock().unwrap().clear()
+    }
+
+    pub fn list(&self, publisher: &Uuid) -> Vec<ScheduledMessage> {
+        let mut scheduled: Vec<ScheduledMessage> = self.pending.lock().unwrap()
+            .values()
+            .filter(|s| &s.message.publisher == publisher)
+            .cloned()
+            .collect();
+        scheduled.sort_by_key(|s| s.due);
+        scheduled
+    }
+
+    pub fn cancel_all(&self, publisher: &Uuid) {
+        self.pending.lock().unwrap().retain(|_, s| &s.message.publisher != publisher);
+    }
+
+    // next_due - due time of the earliest scheduled message
+    pub fn next_due(&self) -> Option<DateTime<Local>> {
+        self.pending.lock().unwrap().values().map(|s| s.due).min()
+    }
+
+    // due - scheduled messages due at now, they stay scheduled until removed
+    pub fn due(&self, now: &DateTime<Local>) -> Vec<ScheduledMessage> {
+        let mut due: Vec<ScheduledMessage> = self.pending.lock().unwrap()
+            .values()
+            .filter(|s| s.due <= *now)
+            .cloned()
+            .collect();
+        due.sort_by_key(|s| s.due);
+        due
+    }
+}
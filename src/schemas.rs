@@ -0,0 +1,167 @@
+use models::Topic;
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use url::Url;
+use valico::json_schema;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicSchema {
+    pub version: u32,
+    pub schema: Value,
+}
+
+// TopicSchemas - all versions of a topic schema in the order they were registered, as kept in
+// snapshots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicSchemas {
+    #[serde(with = "json_strings")]
+    pub versions: Vec<Value>,
+    pub active: Option<u32>,
+}
+
+// Version - a schema compiled once on registration, each in its own scope so that versions with
+// the same id don't conflict
+struct Version {
+    schema: TopicSchema,
+    scope: json_schema::Scope,
+    url: Url,
+}
+
+// Schemas - versioned JSON Schemas of topics. A topic validates message bodies against its active
+// version, older versions are kept so that they can be activated again
+pub struct Schemas {
+    versions: Mutex<HashMap<Topic, Vec<Arc<Version>>>>,
+    active: Mutex<HashMap<Topic, u32>>,
+}
+
+impl Schemas {
+    pub fn new() -> Self {
+        Schemas { versions: Mutex::new(HashMap::new()), active: Mutex::new(HashMap::new()) }
+    }
+
+    // check - whether the schema compiles, without registering it
+    pub fn check(schema: &Value) -> Result<(), String> {
+        json_schema::Scope::new().compile(schema.clone(), false)
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    // register - adds a new version of the topic schema and activates it
+    pub fn register(&self, topic: &Topic, schema: Value) -> Result<u32, String> {
+        let mut scope = json_schema::Scope::new();
+        let url = scope.compile(schema.clone(), false).map_err(|e| format!("{:?}", e))?;
+        let mut versions = self.versions.lock().unwrap();
+        let topic_versions = versions.entry(topic.clone()).or_insert(vec![]);
+        let version = topic_versions.len() as u32 + 1;
+        topic_versions.push(Arc::new(Version { schema: TopicSchema { version, schema }, scope, url }));
+        self.active.lock().unwrap().insert(topic.clone(), version);
+        Ok(version)
+    }
+
+    pub fn get(&self, topic: &Topic, version: u32) -> Option<TopicSchema> {
+        self.version(topic, version).map(|v| v.schema.clone())
+    }
+
+    pub fn active(&self, topic: &Topic) -> Option<TopicSchema> {
+        self.active_version(topic).map(|v| v.schema.clone())
+    }
+
+    fn version(&self, topic: &Topic, version: u32) -> Option<Arc<Version>> {
+        self.versions.lock().unwrap()
+            .get(topic)
+            .and_then(|vs| vs.iter().find(|v| v.schema.version == version))
+            .cloned()
+    }
+
+    fn active_version(&self, topic: &Topic) -> Option<Arc<Version>> {
+        let version = self.active.lock().unwrap().get(topic).cloned();
+        version.and_then(|v| self.version(topic, v))
+    }
+
+    pub fn activate(&self, topic: &Topic, version: u32) -> bool {
+        if self.get(topic, version).is_none() {
+            return false;
+        }
+        self.active.lock().unwrap().insert(topic.clone(), version);
+        true
+    }
+
+    // deactivate - turns validation off, versions are kept
+    pub fn deactivate(&self, topic: &Topic) -> bool {
+        self.active.lock().unwrap().remove(topic).is_some()
+    }
+
+    pub fn remove(&self, topic: &Topic) {
+        self.active.lock().unwrap().remove(topic);
+        self.versions.lock().unwrap().remove(topic);
+    }
+
+    pub fn all(&self) -> HashMap<Topic, TopicSchemas> {
+        let versions = self.versions.lock().unwrap();
+        let active = self.active.lock().unwrap();
+        versions.iter()
+            .map(|(topic, vs)| (topic.clone(), TopicSchemas {
+                versions: vs.iter().map(|v| v.schema.schema.clone()).collect(),
+                active: active.get(topic).cloned(),
+            }))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.active.lock().unwrap().clear();
+        self.versions.lock().unwrap().clear();
+    }
+
+    // validate - returns violations of the body against the active schema of the topic
+    pub fn validate(&self, topic: &Topic, body: &[u8]) -> Vec<String> {
+        let version = match self.active_version(topic) {
+            Some(v) => v,
+            None => return vec![]
+        };
+
+        let doc: Value = match serde_json::from_slice(body) {
+            Ok(doc) => doc,
+            Err(e) => return vec![format!("body is not a JSON document: {}", e)]
+        };
+
+        match version.scope.resolve(&version.url) {
+            Some(compiled) => {
+                let state = compiled.validate(&doc);
+                let mut violations: Vec<String> = state.errors.iter()
+                    .map(|e| format!("{}: {}", path_of(e.get_path()), e.get_detail().unwrap_or(e.get_title())))
+                    .collect();
+                violations.extend(state.missing.iter().map(|url| format!("unresolved reference {}", url)));
+                violations
+            }
+            None => vec![format!("schema version {} is not compiled", version.schema.version)]
+        }
+    }
+}
+
+fn path_of(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+// json_strings - schemas are serialized as JSON text, binary snapshots can't hold arbitrary JSON
+// values
+mod json_strings {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+    use serde::ser::SerializeSeq;
+    use serde_json::{self, Value};
+
+    pub fn serialize<S: Serializer>(values: &Vec<Value>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(values.len()))?;
+        for value in values {
+            seq.serialize_element(&value.to_string())?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Value>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?.iter()
+            .map(|s| serde_json::from_str(s).map_err(D::Error::custom))
+            .collect()
+    }
+}
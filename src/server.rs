@@ -1,25 +1,53 @@
+use audit::{Actor, AuditAction, AuditConfig, AuditEvent, AuditLog, AuditQuery};
+use auth::{constant_time_eq, hash_token};
+use batcher::Batcher;
+use callbacks::CallbackPolicy;
+use clock::{Clock, SystemClock};
+use errors::PubSubError;
 use models::*;
-use std::collections::HashMap;
+use patch::{merge_patch, MERGE_PATCH_CONTENT_TYPE};
+use cluster::{Cluster, ClusterConfig, Node, NodeService, Nodes};
+use federation::{republished_headers, Federation, FederationConfig, HubService, Hubs, Route};
+use pattern::SubjectFilter;
+use replication::{ReplicaService, Replicas, ReplicationConfig, ReplicationStatus, Replication, Role};
+use serde_json::{self, Value};
+use scheduler::{Schedule, ScheduledMessage, Scheduler};
+use schemas::{Schemas, TopicSchema};
+use snapshot::Snapshot;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::slice;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use storage::{MemoryStorage, MessageOp, Storage};
 use subscribers::Subscribers;
+use ticker::TickerConfig;
 use super::headers::unformat_headers;
 use super::subscribers::SubscriberService;
 use uuid::Uuid;
+use wal::{Wal, WalConfig, WalEntry};
 
+// REPLICATION_RETRY_SECS - how long a replica that failed to receive waits for the next attempt
+const REPLICATION_RETRY_SECS: u64 = 1;
+
+// PubSubServer - handle of the server state, shared with the background ticker
 pub struct PubSubServer {
-    pub subs_service: Box<Subscribers + 'static>,
-    pending_subscribers: Arc<Mutex<HashMap<Uuid, Subscriber>>>,
-    publishers: Arc<Mutex<HashMap<Uuid, Publisher>>>,
-    subscribers: Arc<Mutex<HashMap<Topic, Vec<Subscriber>>>>,
-    // topics - main data container. A Subject can have only one message, i.e. Subject is a
-    // unique of a Message
-    topics: Arc<Mutex<HashMap<Topic, HashMap<Uuid, HashMap<Subject, Message>>>>>,
+    state: Arc<ServerState>,
 }
 
-unsafe impl<'a> Send for PubSubServer {}
+impl Deref for PubSubServer {
+    type Target = ServerState;
 
-unsafe impl<'a> Sync for PubSubServer {}
+    fn deref(&self) -> &ServerState {
+        &self.state
+    }
+}
 
 impl<'a> PubSubServer {
     pub fn new() -> Self {
@@ -28,116 +56,860 @@ impl<'a> PubSubServer {
 
     pub fn with_service(client: Box<Subscribers + 'a>) -> PubSubServer {
         PubSubServer {
-            subs_service: client,
-            pending_subscribers: Arc::new(Mutex::new(HashMap::new())),
-            publishers: Arc::new(Mutex::new(HashMap::new())),
-            subscribers: Arc::new(Mutex::new(HashMap::new())),
-            topics: Arc::new(Mutex::new(HashMap::new())),
+            state: Arc::new(ServerState {
+                subs_service: client,
+                replicas_service: Box::new(ReplicaService::new()),
+                hubs_service: Box::new(HubService::new()),
+                nodes_service: Box::new(NodeService::new()),
+                clock: Box::new(SystemClock),
+                scheduler: Scheduler::new(),
+                batcher: Batcher::new(),
+                schemas: Schemas::new(),
+                storage: Box::new(MemoryStorage::new()),
+                writes: Mutex::new(()),
+                wal: Mutex::new(None),
+                replication: Replication::new(),
+                federation: Federation::new(),
+                cluster: Mutex::new(None),
+                callback_policy: Mutex::new(CallbackPolicy::default()),
+                audit: AuditLog::new(),
+            })
+        }
+    }
+
+    pub fn with_clock(self, clock: Box<Clock + 'static>) -> PubSubServer {
+        self.map_state(|state| ServerState { clock, ..state })
+    }
+
+    pub fn with_storage(self, storage: Box<Storage + 'static>) -> PubSubServer {
+        self.map_state(|state| ServerState { storage, ..state })
+    }
+
+    pub fn with_replicas(self, replicas_service: Box<Replicas + 'static>) -> PubSubServer {
+        self.map_state(|state| ServerState { replicas_service, ..state })
+    }
+
+    pub fn with_hubs(self, hubs_service: Box<Hubs + 'static>) -> PubSubServer {
+        self.map_state(|state| ServerState { hubs_service, ..state })
+    }
+
+    pub fn with_nodes(self, nodes_service: Box<Nodes + 'static>) -> PubSubServer {
+        self.map_state(|state| ServerState { nodes_service, ..state })
+    }
+
+    fn map_state<F: FnOnce(ServerState) -> ServerState>(self, f: F) -> PubSubServer {
+        let state = Arc::try_unwrap(self.state).ok().expect("server is configured before it is shared");
+        PubSubServer { state: Arc::new(f(state)) }
+    }
+
+    // start_ticker - runs tick on a background thread, so subjects expire, scheduled messages are
+    // published and batches are flushed while no requests come in. The thread stops once the
+    // server is dropped
+    pub fn start_ticker(&self, config: TickerConfig) {
+        println!("ticking every {:?}", config.interval);
+        let state = Arc::downgrade(&self.state);
+        let mut next = config.interval;
+        thread::spawn(move || loop {
+            thread::sleep(next);
+            match state.upgrade() {
+                Some(state) => {
+                    state.tick();
+                    next = state.next_tick(config.interval);
+                }
+                None => break
+            }
+        });
+    }
+
+    // start_replication - ships mutations to replicas on a background thread as soon as they are
+    // recorded, so neither requests nor ticks wait for replicas. The thread stops once the server
+    // is dropped
+    pub fn start_replication(&self) {
+        let state = Arc::downgrade(&self.state);
+        thread::spawn(move || loop {
+            match state.upgrade() {
+                Some(state) => {
+                    state.replication.wait(Duration::from_secs(REPLICATION_RETRY_SECS));
+                    state.replicate();
+                }
+                None => break
+            }
+        });
+    }
+}
+
+pub struct ServerState {
+    pub subs_service: Box<Subscribers + 'static>,
+    pub replicas_service: Box<Replicas + 'static>,
+    pub hubs_service: Box<Hubs + 'static>,
+    pub nodes_service: Box<Nodes + 'static>,
+    clock: Box<Clock + 'static>,
+    scheduler: Scheduler,
+    batcher: Batcher,
+    schemas: Schemas,
+    // storage - retained messages, publishers, subscriptions and topic configuration
+    storage: Box<Storage + 'static>,
+    // writes - serializes mutations of the storage, so the write-ahead log has them in the order
+    // they were applied
+    writes: Mutex<()>,
+    // wal - Some in durable mode, every mutation of the state above is appended to it
+    wal: Mutex<Option<Wal>>,
+    replication: Replication,
+    federation: Federation,
+    // cluster - Some when topics are sharded across nodes
+    cluster: Mutex<Option<Cluster>>,
+    callback_policy: Mutex<CallbackPolicy>,
+    audit: AuditLog,
+}
+
+unsafe impl<'a> Send for ServerState {}
+
+unsafe impl<'a> Sync for ServerState {}
+
+// stored - a storage that cannot read or write fails the request
+fn stored<T>(result: Result<T, String>) -> Result<T, PubSubError> {
+    result.map_err(PubSubError::StorageFailed)
+}
+
+impl ServerState {
+    pub fn tick(&self) {
+        // a replica gets expirations, scheduled publishes and bridged messages from its primary
+        let primary = self.replication.role() == Role::Primary;
+        if primary {
+            self.expire_messages();
+            self.publish_scheduled();
+        }
+        self.flush_batches();
+        self.maintain_wal();
+        if primary {
+            self.federate();
+        }
+    }
+
+    // next_tick - time until the next tick by the clock of the server, earlier than the interval
+    // when a scheduled message or a batch is due before
+    fn next_tick(&self, interval: Duration) -> Duration {
+        let now = self.clock.now();
+        let due = match (self.scheduler.next_due(), self.batcher.next_due()) {
+            (Some(scheduled), Some(batch)) => Some(cmp::min(scheduled, batch)),
+            (scheduled, batch) => scheduled.or(batch)
+        };
+        due.map(|due| (due - now).to_std().unwrap_or(Duration::from_millis(0)))
+            .map_or(interval, |until| cmp::min(until, interval))
+    }
+
+    pub fn configure_replication(&self, config: ReplicationConfig) {
+        println!("replication role {:?}, replicas {:?}", config.role, config.replicas);
+        self.replication.configure(config)
+    }
+
+    pub fn replication_status(&self) -> ReplicationStatus {
+        self.replication.status()
+    }
+
+    // promote - makes a replica the primary, it accepts writes from now on
+    pub fn promote(&self) {
+        if self.replication.promote() {
+            println!("promoted replica to primary");
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), PubSubError> {
+        match self.replication.role() {
+            Role::Primary => Ok(()),
+            Role::Replica => Err(PubSubError::ReadOnlyReplica)
+        }
+    }
+
+    fn check_replica(&self) -> Result<(), PubSubError> {
+        match self.replication.role() {
+            Role::Replica => Ok(()),
+            Role::Primary => Err(PubSubError::NotReplica)
+        }
+    }
+
+    // replicate_snapshot - replaces the state of a replica with the snapshot sent by its primary
+    pub fn replicate_snapshot(&self, snapshot: Snapshot) -> Result<(), PubSubError> {
+        self.check_replica()?;
+        self.restore(snapshot).map_err(|e| PubSubError::SnapshotFailed(format!("{}", e)))
+    }
+
+    // replicate_entries - applies mutations sent by the primary, subscribers are not notified
+    pub fn replicate_entries(&self, entries: Vec<WalEntry>) -> Result<(), PubSubError> {
+        self.check_replica()?;
+        println!("applying {} replicated entries", entries.len());
+        self.commit(entries).map(|_| ())
+    }
+
+    pub fn configure_audit(&self, config: AuditConfig) -> io::Result<()> {
+        println!("audit log {}", config.path.display());
+        self.audit.open(config)
+    }
+
+    // audit - records an event caused by the actor in the audit log
+    pub fn audit(&self, actor: &Actor, action: AuditAction, target: String, detail: Option<String>) {
+        self.audit.record(AuditEvent::new(self.clock.now(), actor, action, target, detail))
+    }
+
+    pub fn audit_events(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, PubSubError> {
+        self.audit.query(query)
+    }
+
+    pub fn configure_federation(&self, config: FederationConfig) {
+        println!("federation instance {} with {} bridges", config.instance, config.bridges.len());
+        self.federation.configure(config)
+    }
+
+    pub fn configure_cluster(&self, config: ClusterConfig) -> Result<(), String> {
+        let cluster = Cluster::new(config)?;
+        println!("cluster of nodes {:?}", cluster.nodes());
+        *self.cluster.lock().unwrap() = Some(cluster);
+        Ok(())
+    }
+
+    // topic_owner - node owning the topic by consistent hashing, None when it is this node or
+    // topics are not sharded
+    pub fn topic_owner(&self, topic: &str) -> Option<Node> {
+        match *self.cluster.lock().unwrap() {
+            Some(ref cluster) => {
+                let owner = cluster.owner(topic);
+                if cluster.is_local(owner) { None } else { Some(owner.clone()) }
+            }
+            None => None
+        }
+    }
+
+    // subscriber_owner - node the subscriber was created on, None when it is this node or topics
+    // are not sharded
+    pub fn subscriber_owner(&self, id: &Uuid) -> Option<Node> {
+        self.topic_owner(&format!("{}", id.hyphenated()))
+    }
+
+    // new_subscriber_id - an id owned by this node, so calls with the id alone are redirected here
+    fn new_subscriber_id(&self) -> Uuid {
+        loop {
+            let id = Uuid::new_v4();
+            if self.subscriber_owner(&id).is_none() {
+                return id;
+            }
+        }
+    }
+
+    // share_publisher - registers the publisher on the other nodes, a failure undoes it on the
+    // nodes it was registered on: a new publisher is removed, a publisher registered without a
+    // token loses the token again
+    fn share_publisher(&self, id: &Uuid, token: &String, created: bool) -> Result<(), PubSubError> {
+        let (others, api_key) = match *self.cluster.lock().unwrap() {
+            Some(ref cluster) => (cluster.others(), cluster.api_key()),
+            None => return Ok(())
+        };
+        for (i, node) in others.iter().enumerate() {
+            if let Err(e) = self.nodes_service.add_publisher(node, api_key.as_ref(), id, token) {
+                others[..i].iter().for_each(|n| {
+                    let undone = if created {
+                        self.nodes_service.remove_publisher(n, api_key.as_ref(), id)
+                    } else {
+                        self.nodes_service.clear_publisher_token(n, api_key.as_ref(), id)
+                    };
+                    if let Err(e) = undone {
+                        println!("failed to undo registration of publisher {} on node {}: {}", id, n.name, e);
+                    }
+                });
+                return Err(PubSubError::ClusterFailed(format!("node {}: {}", node.name, e)));
+            }
+        }
+        Ok(())
+    }
+
+    // unshare_publisher - removes the publisher from the other nodes
+    fn unshare_publisher(&self, id: &Uuid) -> Result<(), PubSubError> {
+        let (others, api_key) = match *self.cluster.lock().unwrap() {
+            Some(ref cluster) => (cluster.others(), cluster.api_key()),
+            None => return Ok(())
+        };
+        let failed: Vec<String> = others.iter()
+            .filter_map(|node| self.nodes_service.remove_publisher(node, api_key.as_ref(), id).err()
+                .map(|e| format!("node {}: {}", node.name, e)))
+            .collect();
+        if failed.is_empty() { Ok(()) } else { Err(PubSubError::ClusterFailed(failed.join(", "))) }
+    }
+
+    // federate - subscribes bridged topics which are not subscribed yet. Inbound topics are
+    // subscribed on the remote hub, outbound ones locally with the remote hub as the callback.
+    // An outbound subscriber removed after failed deliveries is subscribed again
+    fn federate(&self) {
+        let config = match self.federation.config() {
+            Some(config) => config,
+            None => return
+        };
+
+        for link in self.federation.links() {
+            if link.inbound {
+                if link.subscription.is_some() {
+                    continue;
+                }
+                let hub = &link.bridge.url;
+                let api_key = link.bridge.api_key.as_ref();
+                let subscribed = self.hubs_service.subscribe(hub, api_key, &link.topic, &config.inbound_callback(&link.bridge))
+                    .and_then(|id| self.hubs_service.touch_subscriber(hub, api_key, &id).map(|_| id));
+                match subscribed {
+                    Ok(id) => {
+                        println!("bridge {} subscribed to topic {} as {}", link.bridge.name, link.topic, id);
+                        self.federation.set_subscription(&link, Some(id));
+                    }
+                    Err(e) => println!("bridge {} failed to subscribe to topic {}: {}", link.bridge.name, link.topic, e)
+                }
+            } else {
+                let callback = config.outbound_callback(&link.bridge);
+                match self.topic_subscribers(&link.topic) {
+                    Ok(ref subs) if subs.iter().any(|s| s.callback == callback) => continue,
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("bridge {} cannot check forwarding of topic {}: {}", link.bridge.name, link.topic, e);
+                        continue;
+                    }
+                }
+                let subscribed = self
+                    .add_pending_subscriber(callback, link.topic.clone(), None, Delivery::Full, &Actor::server())
+                    .and_then(|id| self.touch_subscriber(id).map(|_| id));
+                match subscribed {
+                    Ok(id) => {
+                        println!("bridge {} forwards topic {} as {}", link.bridge.name, link.topic, id);
+                        self.federation.set_subscription(&link, Some(format!("{}", id)));
+                    }
+                    Err(e) => println!("bridge {} failed to forward topic {}: {}", link.bridge.name, link.topic, e)
+                }
+            }
+        }
+    }
+
+    // accept_bridged - checks the bridge is configured with the topic inbound
+    fn accept_bridged(&self, bridge: &str, topic: &Topic) -> Result<FederationConfig, PubSubError> {
+        let config = self.federation.config()
+            .ok_or(PubSubError::UnknownBridge(bridge.to_string()))?;
+        let accepted = match config.bridge(bridge) {
+            Some(b) => b.direction.inbound() && b.topics.contains(topic),
+            None => return Err(PubSubError::UnknownBridge(bridge.to_string()))
+        };
+        if !accepted {
+            return Err(PubSubError::BridgeNotAllowed { bridge: bridge.to_string(), topic: topic.clone() });
         }
+        Ok(config)
     }
 
-    pub fn add_pending_subscriber(&self, callback: String, topic: Topic) -> Uuid {
-        let sub = Subscriber::new(callback, topic);
+    pub fn configure_callbacks(&self, policy: CallbackPolicy) {
+        *self.callback_policy.lock().unwrap() = policy;
+    }
+
+    // check_callback - reason the callback of a new subscriber is rejected
+    pub fn check_callback(&self, callback: &str) -> Result<(), String> {
+        let policy = self.callback_policy.lock().unwrap().clone();
+        policy.check(callback)
+    }
+
+    // check_delivery - the callback is checked again before it is called, its host may resolve to
+    // an internal address by now. Returns the checked address the callback is called at, so a host
+    // can't resolve to another one on connect. Bridge callbacks are configured, so they are trusted
+    fn check_delivery(&self, callback: &String) -> Result<Option<IpAddr>, String> {
+        let bridged = self.federation.config().map_or(false, |config| config.callback_bridge(callback).is_some());
+        if bridged {
+            return Ok(None);
+        }
+        let policy = self.callback_policy.lock().unwrap().clone();
+        policy.resolve(callback)
+    }
+
+    // callback_key - API key sent with deliveries to the callback, the key of the bridge when it is
+    // a bridge callback on a remote hub
+    fn callback_key(&self, callback: &String) -> Option<String> {
+        self.federation.config().and_then(|config| config.callback_key(callback))
+    }
+
+    // receive_bridged - republishes a message delivered by a remote hub. Messages which originated
+    // here or made too many hops are dropped, so bridges in both directions don't loop
+    pub fn receive_bridged(&self, bridge: &str, m: Message) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let config = self.accept_bridged(bridge, &m.topic)?;
+        let route = Route::from_headers(&m.headers);
+        if route.origin.as_ref() == Some(&config.instance) {
+            println!("dropping bridged message {} which originated here", m);
+            return Ok(());
+        }
+        if route.hops >= config.max_hops {
+            println!("dropping bridged message {} after {} hops", m, route.hops);
+            return Ok(());
+        }
+
+        if !stored(self.storage.has_publisher(&m.publisher))? {
+            match self.add_publisher(m.publisher, &Actor::server()) {
+                Ok(_) | Err(PubSubError::PublisherExists(_)) => (),
+                Err(e) => return Err(e)
+            }
+        }
+        let origin = route.origin.unwrap_or(bridge.to_string());
+        let headers = republished_headers(&m.headers, &origin, route.hops + 1);
+        println!("republishing message {} from bridge {}", m, bridge);
+        self.publish_message(m.with_headers(headers))
+    }
+
+    // remove_bridged - removes a subject removed on a remote hub, unknown subjects are ignored
+    pub fn remove_bridged(&self, bridge: &str, m: Message) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        self.accept_bridged(bridge, &m.topic)?;
+        if !self.is_known_subject(&m)? {
+            return Ok(());
+        }
+        println!("removing message {} from bridge {}", m, bridge);
+        self.remove(m)
+    }
+
+    // replicate - sends a snapshot to replicas that are not synced and queued mutations to the rest.
+    // A replica that failed to receive anything is synced again by a snapshot
+    pub fn replicate(&self) {
+        let _shipping = self.replication.shipping().lock().unwrap();
+        let api_key = self.replication.api_key();
+        for url in self.replication.unsynced() {
+            let snapshot = self.with_snapshot(|snapshot| {
+                self.replication.mark_synced(&url);
+                snapshot
+            });
+            let sent = snapshot.map_err(|e| format!("{}", e))
+                .and_then(|snapshot| self.replicas_service.send_snapshot(&url, api_key.as_ref(), &snapshot));
+            if let Err(e) = sent {
+                println!("failed to send snapshot to replica {}: {}", url, e);
+                self.replication.mark_unsynced(&url);
+            }
+        }
+        for (url, entries) in self.replication.take_pending() {
+            if let Err(e) = self.replicas_service.send_entries(&url, api_key.as_ref(), &entries) {
+                println!("failed to send {} entries to replica {}: {}", entries.len(), url, e);
+                self.replication.mark_unsynced(&url);
+            }
+        }
+    }
+
+    // open_wal - rebuilds the state from the write-ahead log, compacts it and logs all further
+    // mutations to it
+    pub fn open_wal(&self, config: WalConfig) -> io::Result<()> {
+        let path = config.path.clone();
+        let (wal, entries) = Wal::open(config, self.clock.now())?;
+        println!("replaying {} write-ahead log entries from {:?}", entries.len(), path);
+        self.commit(entries).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+        *self.wal.lock().unwrap() = Some(wal);
+        self.compact_wal()
+    }
+
+    // log - appends entries to the write-ahead log before they are applied
+    fn log(&self, entries: &[WalEntry]) -> Result<(), PubSubError> {
+        match *self.wal.lock().unwrap() {
+            Some(ref mut wal) => wal.append(entries, self.clock.now())
+                .map_err(|e| PubSubError::WalFailed(format!("{}", e))),
+            None => Ok(())
+        }
+    }
+
+    // commit - logs entries and applies them to the storage, returns removed messages
+    fn commit(&self, entries: Vec<WalEntry>) -> Result<Vec<Message>, PubSubError> {
+        let _writes = self.writes.lock().unwrap();
+        self.write(entries)
+    }
+
+    // write - commit for callers holding the writes lock. Nothing is applied if the entries cannot
+    // be logged, consecutive message entries are applied to the storage at once
+    fn write(&self, entries: Vec<WalEntry>) -> Result<Vec<Message>, PubSubError> {
+        if entries.is_empty() {
+            return Ok(vec![]);
+        }
+        self.log(&entries)?;
+
+        let mut removed = vec![];
+        let mut ops = vec![];
+        for entry in &entries {
+            match *entry {
+                WalEntry::PutMessage { ref message } => ops.push(MessageOp::Put(message.clone())),
+                WalEntry::RemoveMessage { ref topic, publisher, ref subject } =>
+                    ops.push(MessageOp::Remove { topic: topic.clone(), publisher, subject: subject.clone() }),
+                ref other => {
+                    if !ops.is_empty() {
+                        removed.extend(stored(self.storage.apply(mem::replace(&mut ops, vec![])))?);
+                    }
+                    removed.extend(self.apply(other)?);
+                }
+            }
+        }
+        if !ops.is_empty() {
+            removed.extend(stored(self.storage.apply(ops))?);
+        }
+        entries.iter().for_each(|e| self.replication.record(e));
+        Ok(removed)
+    }
+
+    // apply - applies an entry other than a message change without notifying subscribers
+    fn apply(&self, entry: &WalEntry) -> Result<Vec<Message>, PubSubError> {
+        match *entry {
+            WalEntry::AddPublisher { id, ref token_hash } => {
+                stored(self.storage.add_publisher(id, token_hash.clone()))?;
+            }
+            WalEntry::SetPublisherToken { id, ref token_hash } => {
+                stored(self.storage.set_publisher_token_hash(&id, token_hash.clone()))?;
+            }
+            WalEntry::RemovePublisher { id } => {
+                self.scheduler.cancel_all(&id);
+                return stored(self.storage.remove_publisher(&id)).map(|removed| removed.unwrap_or(vec![]));
+            }
+            WalEntry::AddPendingSubscriber { ref subscriber } =>
+                stored(self.storage.add_pending_subscriber(subscriber.clone()))?,
+            WalEntry::ActivateSubscriber { id } => {
+                stored(self.storage.activate_subscriber(&id))?;
+            }
+            WalEntry::RemoveSubscriber { id } => {
+                stored(self.storage.remove_subscriber(&id))?;
+            }
+            WalEntry::PutMessage { ref message } => {
+                stored(self.storage.apply(vec![MessageOp::Put(message.clone())]))?;
+            }
+            WalEntry::RemoveMessage { ref topic, publisher, ref subject } => return stored(self.storage.apply(vec![
+                MessageOp::Remove { topic: topic.clone(), publisher, subject: subject.clone() }])),
+            WalEntry::ConfigureTopic { ref topic, ref config } =>
+                stored(self.storage.configure_topic(topic.clone(), config.clone()))?,
+            WalEntry::RemoveTopic { ref topic } => {
+                self.schemas.remove(topic);
+                return stored(self.storage.remove_topic(topic));
+            }
+            WalEntry::AutoCreateTopics { enabled } => stored(self.storage.set_auto_create_topics(enabled))?,
+            WalEntry::RegisterSchema { ref topic, ref schema } => {
+                if let Err(e) = self.schemas.register(topic, schema.clone()) {
+                    println!("cannot register schema of topic {}: {}", topic, e);
+                }
+            }
+            WalEntry::ActivateSchema { ref topic, version } => {
+                self.schemas.activate(topic, version);
+            }
+            WalEntry::DeactivateSchema { ref topic } => {
+                self.schemas.deactivate(topic);
+            }
+            WalEntry::ScheduleMessage { ref scheduled } => self.scheduler.add(scheduled.clone()),
+            WalEntry::Unschedule { id } => {
+                self.scheduler.remove(&id);
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn maintain_wal(&self) {
+        let needs_compaction = match *self.wal.lock().unwrap() {
+            Some(ref mut wal) => {
+                if let Err(e) = wal.sync_due(self.clock.now()) {
+                    println!("failed to sync write-ahead log: {}", e);
+                }
+                wal.needs_compaction()
+            }
+            None => false
+        };
+
+        if needs_compaction {
+            if let Err(e) = self.compact_wal() {
+                println!("failed to compact write-ahead log: {}", e);
+            }
+        }
+    }
+
+    // compact_wal - rewrites the log as the minimal list of entries rebuilding the current state
+    fn compact_wal(&self) -> io::Result<()> {
+        self.with_snapshot(|snapshot| match *self.wal.lock().unwrap() {
+            Some(ref mut wal) => {
+                let entries = snapshot.wal_entries();
+                println!("compacting write-ahead log to {} entries", entries.len());
+                wal.compact(&entries, self.clock.now())
+            }
+            None => Ok(())
+        }).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot, PubSubError> {
+        self.with_snapshot(|snapshot| snapshot)
+    }
+
+    // with_snapshot - captures the state and runs f while it is still locked. Locks are taken in
+    // the order mutations nest them and before the log lock, so no mutation can happen between
+    // the capture and whatever f does with it
+    fn with_snapshot<T, F: FnOnce(Snapshot) -> T>(&self, f: F) -> Result<T, PubSubError> {
+        let _writes = self.writes.lock().unwrap();
+        let publishers = stored(self.storage.publishers())?;
+        let mut publisher_token_hashes = HashMap::new();
+        for id in &publishers {
+            if let Some(token_hash) = stored(self.storage.publisher_token_hash(id))? {
+                publisher_token_hashes.insert(*id, token_hash);
+            }
+        }
+        let snapshot = Snapshot {
+            auto_create_topics: stored(self.storage.auto_create_topics())?,
+            topic_configs: stored(self.storage.topic_configs())?,
+            publishers,
+            publisher_token_hashes,
+            pending_subscribers: stored(self.storage.pending_subscribers())?,
+            subscribers: stored(self.storage.subscribers())?,
+            messages: stored(self.storage.messages())?,
+            schemas: self.schemas.all(),
+            scheduled: self.scheduler.all(),
+            ..Snapshot::new(self.clock.now())
+        };
+        Ok(f(snapshot))
+    }
+
+    // restore - replaces the whole state with the snapshot, subscribers are not notified. In durable
+    // mode the write-ahead log is rewritten to the restored state
+    pub fn restore(&self, snapshot: Snapshot) -> io::Result<()> {
+        println!("restoring snapshot taken at {} with {} publishers, {} subscribers and {} messages",
+                 snapshot.taken_at, snapshot.publishers.len(),
+                 snapshot.pending_subscribers.len() + snapshot.subscribers.len(), snapshot.messages.len());
+        let wal = self.wal.lock().unwrap().take();
+        let restored = stored(self.storage.clear()).and_then(|_| {
+            self.schemas.clear();
+            self.scheduler.clear();
+            self.commit(snapshot.wal_entries())
+        });
+
+        *self.wal.lock().unwrap() = wal;
+        restored.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+        self.compact_wal()
+    }
+
+    pub fn add_pending_subscriber(&self, callback: String, topic: Topic, batching: Option<BatchPolicy>,
+                                  delivery: Delivery, actor: &Actor) -> Result<Uuid, PubSubError> {
+        self.check_writable()?;
+        if !self.topic_exists(&topic)? {
+            println!("rejecting subscription on unknown topic {}", topic);
+            return Err(PubSubError::UnknownTopic(topic));
+        }
+
+        let sub = Subscriber { id: self.new_subscriber_id(), ..Subscriber::new(callback, topic) }
+            .with_batching(batching)
+            .with_delivery(delivery);
         let id = sub.id.clone();
-        println!("adding {} to pending", sub);
-        self.pending_subscribers.lock().unwrap().insert(sub.id, sub);
-        id
+        let detail = format!("pending on topic {} with callback {}", sub.topic, sub.callback);
+        self.commit(vec![WalEntry::AddPendingSubscriber { subscriber: sub }])?;
+        self.audit(actor, AuditAction::Subscribe, format!("{}", id), Some(detail));
+        Ok(id)
+    }
+
+    pub fn remove_subscriber(&self, id: Uuid, actor: &Actor) -> Result<(), PubSubError> {
+        self.drop_subscriber(id)?;
+        self.audit(actor, AuditAction::Unsubscribe, format!("{}", id), None);
+        Ok(())
     }
 
-    pub fn remove_subscriber(&self, id: Uuid) {
-        for (_, subs) in self.subscribers.lock().unwrap().iter_mut() {
-            subs.retain(|s| s.id != id);
+    fn drop_subscriber(&self, id: Uuid) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        self.batcher.discard(&id);
+        let _writes = self.writes.lock().unwrap();
+        if !stored(self.storage.is_active_subscriber(&id))? && self.pending_subscriber(&id)?.is_none() {
+            return Err(PubSubError::UnknownSubscriber(id));
         }
+        self.write(vec![WalEntry::RemoveSubscriber { id }]).map(|_| ())
     }
 
-    pub fn touch_subscriber(&self, id: Uuid) {
-        self.pending_subscribers.lock().unwrap().get(&id).iter()
-            .for_each(|s| println!("Found subscriber {}", s));
+    // subscriber_topic - topic of a pending or active subscriber
+    pub fn subscriber_topic(&self, id: &Uuid) -> Result<Topic, PubSubError> {
+        let active = stored(self.storage.subscribers())?.into_iter().find(|s| &s.id == id);
+        match active.map_or_else(|| self.pending_subscriber(id), |s| Ok(Some(s)))? {
+            Some(s) => Ok(s.topic),
+            None => Err(PubSubError::UnknownSubscriber(*id))
+        }
+    }
 
-        self.pending_subscribers.lock().unwrap()
-            .remove(&id)
-            .into_iter()
-            .for_each(|s| self.add_subscriber(s))
+    fn pending_subscriber(&self, id: &Uuid) -> Result<Option<Subscriber>, PubSubError> {
+        Ok(stored(self.storage.pending_subscribers())?.into_iter().find(|s| &s.id == id))
     }
 
-    fn add_subscriber(&self, s: Subscriber) {
-        self.subscribers.lock().unwrap()
-            .entry(s.topic.clone())
-            .or_insert(vec![])
-            .push(s.clone());
+    // evict - removes a subscriber whose callback failed, it has to subscribe again
+    fn evict(&self, sub: &Subscriber, reason: String) {
+        if self.drop_subscriber(sub.id).is_ok() {
+            let detail = format!("topic {} callback {}: {}", sub.topic, sub.callback, reason);
+            self.audit(&Actor::server(), AuditAction::Eviction, format!("{}", sub.id), Some(detail));
+        }
+    }
 
-        self.publish_all_messages(s)
+    pub fn touch_subscriber(&self, id: Uuid) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let activated = {
+            let _writes = self.writes.lock().unwrap();
+            let pending = self.pending_subscriber(&id)?;
+            if pending.is_some() {
+                self.write(vec![WalEntry::ActivateSubscriber { id }])?;
+            }
+            pending
+        };
+
+        match activated {
+            Some(s) => {
+                println!("Found subscriber {}", s);
+                self.publish_all_messages(s)
+            }
+            None if stored(self.storage.is_active_subscriber(&id))? => Ok(()),
+            None => Err(PubSubError::UnknownSubscriber(id))
+        }
     }
 
-    fn publish_all_messages(&self, s: Subscriber) {
+    fn publish_all_messages(&self, s: Subscriber) -> Result<(), PubSubError> {
+        if self.topic_retention(&s.topic)? == Retention::Transient {
+            println!("topic {} is transient, no messages to publish for subscriber {}", s.topic, s);
+            return Ok(());
+        }
+
         println!("publishing all message for subscriber {}", s);
-        self.topics.lock().unwrap()
-            .entry(s.topic.clone())
-            .or_insert(HashMap::new())
-            .values()
-            .into_iter()
-            .flat_map(|m| m.values())
-            .into_iter()
-            .for_each(|m| self.publish(&m, &s))
+        let now = self.clock.now();
+        stored(self.storage.topic_messages(&s.topic))?.iter()
+            .filter(|m| !m.is_expired(&now))
+            .for_each(|m| self.publish(&m, &s));
+        Ok(())
     }
 
     fn publish(&self, m: &Message, sub: &Subscriber) {
         println!("publish message: {} for subscriber: {}", &m, &sub);
-        let msg = Message {
-            publisher: m.publisher.clone(),
-            topic: sub.topic.clone(),
-            subject: m.subject.clone(),
-            headers: m.headers.clone(),
-            body: m.body.clone(),
-        };
+        let msg = Message { topic: sub.topic.clone(), ..m.clone() };
+        if sub.batching.is_some() {
+            return self.enqueue(sub, BatchEntry::Publish(msg));
+        }
 
+        let address = match self.check_delivery(&sub.callback) {
+            Ok(address) => address,
+            Err(reason) => return self.evict(sub, format!("callback rejected: {}", reason))
+        };
         let c = self.subs_service.as_ref();
-        let res = c.publish_message(&sub.callback, &msg);
+        let res = c.publish_message(&sub.callback, address.as_ref(), self.callback_key(&sub.callback).as_ref(), &msg);
 
         match res {
             Ok(_) =>
                 println!("message publishing for {} returned Ok", &sub),
-            Err(s) => {
-                self.remove_subscriber(sub.id);
-                println!("message publishing failed for {} with status: {:?}", &sub, s);
+            Err(s) =>
+                self.evict(sub, format!("message publishing failed with status: {:?}", s))
+        }
+    }
+
+    // add_publisher - registers the publisher and returns its token, required by every further
+    // call of the publisher. A publisher registered without a token gets one now. In a
+    // cluster the publisher is registered on every node with the same token, or on none of them
+    // when a node fails
+    pub fn add_publisher(&self, id: Uuid, actor: &Actor) -> Result<String, PubSubError> {
+        let token = format!("{}", Uuid::new_v4().simple());
+        let created = self.register_publisher(id, &token)?;
+        if let Err(e) = self.share_publisher(&id, &token, created) {
+            if created {
+                self.drop_publisher(id)?;
+            } else {
+                self.clear_publisher_token(id)?;
+            }
+            return Err(e);
+        }
+
+        let detail = if created { None } else { Some("token issued to a publisher without one".to_string()) };
+        self.audit(actor, AuditAction::AddPublisher, format!("{}", id), detail);
+        Ok(token)
+    }
+
+    // join_publisher - registers a publisher shared by another node of the cluster. A publisher
+    // registered with the same token already is accepted, so the other node can retry
+    pub fn join_publisher(&self, id: Uuid, token: String, actor: &Actor) -> Result<(), PubSubError> {
+        match self.register_publisher(id, &token) {
+            Err(PubSubError::PublisherExists(_)) => self.verify_publisher(id, Some(&token), actor)
+                .map_err(|_| PubSubError::PublisherExists(id)),
+            Ok(_) => {
+                self.audit(actor, AuditAction::AddPublisher, format!("{}", id), Some("shared by another node".to_string()));
+                Ok(())
             }
+            Err(e) => Err(e)
+        }
+    }
+
+    // register_publisher - stores the publisher with the hash of its token, true if it is new. A
+    // publisher registered without a token gets the token instead, any other existing
+    // publisher is a conflict
+    fn register_publisher(&self, id: Uuid, token: &String) -> Result<bool, PubSubError> {
+        self.check_writable()?;
+        let _writes = self.writes.lock().unwrap();
+        let token_hash = Some(hash_token(token));
+        if !stored(self.storage.has_publisher(&id))? {
+            return self.write(vec![WalEntry::AddPublisher { id, token_hash }]).map(|_| true);
+        }
+        if stored(self.storage.publisher_token_hash(&id))?.is_some() {
+            return Err(PubSubError::PublisherExists(id));
         }
+        self.write(vec![WalEntry::SetPublisherToken { id, token_hash }]).map(|_| false)
     }
 
-    pub fn add_publisher(&self, id: Uuid) {
-        self.publishers.lock().unwrap().insert(id, Publisher::new(id));
-        match self.publishers.lock().unwrap().get(&id) {
-            Some(p) => println!("added publisher {}", p),
-            None => println!("WARNING: publisher with id = {} is not found", id)
+    // clear_publisher_token - undoes a token issued to a publisher registered without a token, when
+    // sharing it with the other nodes failed
+    pub fn clear_publisher_token(&self, id: Uuid) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let _writes = self.writes.lock().unwrap();
+        if !stored(self.storage.has_publisher(&id))? {
+            return Err(PubSubError::UnknownPublisher(id));
         }
+        self.write(vec![WalEntry::SetPublisherToken { id, token_hash: None }]).map(|_| ())
     }
 
-    pub fn remove_publisher(&self, id: Uuid) {
-        match self.publishers.lock().unwrap()
-            .remove(&id) {
-            Some(p) => {
-                self.remove_publisher_topics(&id);
-                println!("removed publisher {}", p)
+    // verify_publisher - checks the token of the publisher against the stored hash, publishers
+    // registered without a token are rejected until they register again
+    pub fn verify_publisher(&self, id: Uuid, token: Option<&str>, actor: &Actor) -> Result<(), PubSubError> {
+        if !stored(self.storage.has_publisher(&id))? {
+            return Err(PubSubError::UnknownPublisher(id));
+        }
+        let expected = match stored(self.storage.publisher_token_hash(&id))? {
+            Some(expected) => expected,
+            None => {
+                self.audit(actor, AuditAction::AuthFailure, format!("{}", id), Some("publisher without a token".to_string()));
+                return Err(PubSubError::Unauthorized(format!("publisher {} has no token, register it again to get one", id)));
             }
-            None => println!("publisher not found. Doing nothing")
+        };
+        if !token.map_or(false, |t| constant_time_eq(expected.as_bytes(), hash_token(t).as_bytes())) {
+            let detail = if token.is_some() { "invalid publisher token" } else { "missing publisher token" };
+            self.audit(actor, AuditAction::AuthFailure, format!("{}", id), Some(detail.to_string()));
+            return Err(PubSubError::InvalidPublisherToken(id));
         }
+        Ok(())
     }
 
-    fn remove_publisher_topics(&self, id: &Uuid) {
-        self.topics.lock().unwrap()
-            .iter_mut()
-            .for_each(|(_, pubs)| {
-                pubs.remove(id).iter()
-                    .for_each(|msgs| {
-                        &msgs.iter()
-                            .for_each(|(_, msg)| {
-                                self.subscribers.lock().unwrap()
-                                    .get(msg.topic.as_str())
-                                    .iter()
-                                    .for_each(|s| self.remove_message(&msg, s))
-                            });
-                    });
-            });
+    // publisher_topics - topics the publisher has retained messages on
+    pub fn publisher_topics(&self, id: &Uuid) -> Result<Vec<Topic>, PubSubError> {
+        let mut topics: Vec<Topic> = stored(self.storage.messages())?.into_iter()
+            .filter(|m| &m.publisher == id)
+            .map(|m| m.topic)
+            .collect();
+        topics.sort();
+        topics.dedup();
+        Ok(topics)
+    }
+
+    // remove_publisher - removes the publisher with its retained messages, in a cluster from the
+    // other nodes first, so a failed removal can be retried here
+    pub fn remove_publisher(&self, id: Uuid, actor: &Actor) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        if !stored(self.storage.has_publisher(&id))? {
+            return Err(PubSubError::UnknownPublisher(id));
+        }
+        self.unshare_publisher(&id)?;
+        self.leave_publisher(id, actor)
+    }
+
+    // leave_publisher - removes the publisher with its retained messages from this node only
+    pub fn leave_publisher(&self, id: Uuid, actor: &Actor) -> Result<(), PubSubError> {
+        let msgs = self.drop_publisher(id)?;
+        for msg in &msgs {
+            self.remove_message(msg, &self.topic_subscribers(&msg.topic)?);
+        }
+        self.audit(actor, AuditAction::RemovePublisher, format!("{}", id),
+                   Some(format!("{} retained messages removed", msgs.len())));
+        Ok(())
+    }
+
+    fn drop_publisher(&self, id: Uuid) -> Result<Vec<Message>, PubSubError> {
+        self.check_writable()?;
+        let _writes = self.writes.lock().unwrap();
+        if !stored(self.storage.has_publisher(&id))? {
+            return Err(PubSubError::UnknownPublisher(id));
+        }
+        self.write(vec![WalEntry::RemovePublisher { id }])
     }
 
     fn remove_message(&self, m: &Message, subscribers: &Vec<Subscriber>) {
@@ -145,15 +917,17 @@ impl<'a> PubSubServer {
             println!("remove message for subscriber = {} on callback = {} and topic = {}", s,
                      &s.callback, &s.topic);
             let c = self.subs_service.as_ref();
-            let msg = Message {
-                publisher: m.publisher,
-                topic: s.topic.clone(),
-                subject: m.subject.clone(),
-                headers: m.headers.clone(),
-                body: "".to_string(),
+            let msg = Message::new(m.publisher, s.topic.clone(), m.subject.clone(),
+                                   m.headers.clone(), Vec::new());
+            if s.batching.is_some() {
+                return self.enqueue(s, BatchEntry::Remove(msg));
+            }
+            let address = match self.check_delivery(&s.callback) {
+                Ok(address) => address,
+                Err(reason) => return self.evict(s, format!("callback rejected: {}", reason))
             };
 
-            match c.remove_message(&s.callback, &msg) {
+            match c.remove_message(&s.callback, address.as_ref(), self.callback_key(&s.callback).as_ref(), &msg) {
                 Ok(cs) => println!("removed result {}", cs),
                 Err(e) => println!("problem on message remove callback = '{}' and topic = '{}' for \
                 subscriber: '{:?}', error: {:?}", &s.callback, &s.topic, s, e)
@@ -161,73 +935,622 @@ impl<'a> PubSubServer {
         });
     }
 
-    pub fn touch_publisher(&self, id: Uuid) -> Result<(), String> {
-        match self.publishers.lock().unwrap().get_mut(&id) {
-            Some(p) => {
-                println!("touching publisher {}", &p);
-                p.touch();
-                Ok(())
+    pub fn touch_publisher(&self, id: Uuid) -> Result<(), PubSubError> {
+        if !stored(self.storage.touch_publisher(&id))? {
+            return Err(PubSubError::UnknownPublisher(id));
+        }
+        println!("touched publisher {}", id);
+        Ok(())
+    }
+
+    pub fn publish_message(&self, m: Message) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let headers = &m.headers.clone();
+        let msg = m.with_headers(unformat_headers(headers));
+
+        self.touch_publisher(msg.publisher)?;
+        let msg = {
+            let _writes = self.writes.lock().unwrap();
+            let msg = self.admit(msg)?.expiring_from(self.clock.now());
+            self.check_subject_limits(slice::from_ref(&msg))?;
+            self.store(slice::from_ref(&msg))?;
+            msg
+        };
+        self.fire_receive(msg)
+    }
+
+    // patch_message - applies the body of m as a JSON merge patch to the retained subject. The merged
+    // document is stored and published, subscribers with patch delivery receive just the patch
+    pub fn patch_message(&self, m: Message) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        self.touch_publisher(m.publisher)?;
+
+        let patch: Value = serde_json::from_slice(&m.body)
+            .map_err(|e| PubSubError::MalformedBody(format!("invalid merge patch: {}", e)))?;
+        let headers = unformat_headers(&m.headers);
+        let patch_msg = m.with_headers(headers)
+            .with_content(Some(MERGE_PATCH_CONTENT_TYPE.to_string()), None);
+
+        let merged = {
+            // the merge is a read-modify-write of the subject, so concurrent patches must not interleave
+            let _writes = self.writes.lock().unwrap();
+            let existing = match stored(self.storage.message(&patch_msg.topic, &patch_msg.publisher, &patch_msg.subject))? {
+                Some(existing) => existing,
+                None => return Err(PubSubError::UnknownSubject {
+                    topic: patch_msg.topic.clone(),
+                    subject: patch_msg.subject.clone(),
+                })
+            };
+
+            let mut doc: Value = serde_json::from_slice(&existing.body)
+                .map_err(|_| PubSubError::InvalidPatch("retained body is not a JSON document".to_string()))?;
+            merge_patch(&mut doc, &patch);
+            let body = serde_json::to_vec(&doc)
+                .map_err(|e| PubSubError::InvalidPatch(format!("{}", e)))?;
+
+            let ttl = patch_msg.ttl.or(existing.ttl);
+            let merged = Message::new(existing.publisher, existing.topic.clone(), existing.subject.clone(),
+                                      patch_msg.headers.clone(), body)
+                .with_content(existing.content_type.clone(), existing.content_encoding.clone())
+                .with_ttl(ttl);
+            let merged = self.admit(merged)?.expiring_from(self.clock.now());
+            self.write(vec![WalEntry::PutMessage { message: merged.clone() }])?;
+            merged
+        };
+
+        println!("patched message {}", merged);
+        self.topic_subscribers(&merged.topic)?.iter()
+            .for_each(|s| match s.delivery {
+                Delivery::Full => self.publish(&merged, s),
+                Delivery::Patch => self.publish(&patch_msg, s),
+            });
+        Ok(())
+    }
+
+    // publish_messages - publishes all messages or none of them. All messages are validated before
+    // anything is changed and retained ones are stored at once, every subscriber receives one
+    // batch per topic
+    pub fn publish_messages(&self, publisher: Uuid, msgs: Vec<Message>) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        self.touch_publisher(publisher)?;
+
+        let msgs = {
+            let _writes = self.writes.lock().unwrap();
+            let now = self.clock.now();
+            let admitted = msgs.into_iter()
+                .map(|m| {
+                    let headers = unformat_headers(&m.headers);
+                    self.admit(m.with_headers(headers)).map(|m| m.expiring_from(now))
+                })
+                .collect::<Result<Vec<Message>, PubSubError>>()?;
+            self.check_subject_limits(&admitted)?;
+            self.store(&admitted)?;
+            admitted
+        };
+        println!("publishing {} messages of publisher {}", msgs.len(), publisher);
+        self.fire_receive_all(&msgs)
+    }
+
+    // commit_transaction - validates all operations first, then applies them to the storage at
+    // once, both under the writes lock. Every subscriber receives its topic's part of the
+    // transaction as one batch
+    pub fn commit_transaction(&self, publisher: Uuid, ops: Vec<BatchEntry>) -> Result<Uuid, PubSubError> {
+        self.check_writable()?;
+        self.touch_publisher(publisher)?;
+
+        let id = Uuid::new_v4();
+        let entries = {
+            let _writes = self.writes.lock().unwrap();
+            let now = self.clock.now();
+            let mut published: HashSet<(Topic, Subject)> = HashSet::new();
+            let mut admitted = vec![];
+            for op in ops {
+                match op {
+                    BatchEntry::Publish(m) => {
+                        let headers = unformat_headers(&m.headers);
+                        let m = self.admit(m.with_headers(headers))?.expiring_from(now);
+                        published.insert((m.topic.clone(), m.subject.clone()));
+                        admitted.push(BatchEntry::Publish(m));
+                    }
+                    BatchEntry::Remove(m) => {
+                        let key = (m.topic.clone(), m.subject.clone());
+                        if !self.is_known_subject(&m)? && !published.contains(&key) {
+                            return Err(PubSubError::UnknownSubject { topic: key.0, subject: key.1 });
+                        }
+                        admitted.push(BatchEntry::Remove(m));
+                    }
+                }
             }
-            None => {
-                println!("touching unknown publisher {}", id);
-                Err(format!("Touching unknown publisher with id: {}", id))
+
+            let msgs: Vec<Message> = admitted.iter()
+                .filter_map(|e| match *e {
+                    BatchEntry::Publish(ref m) => Some(m.clone()),
+                    BatchEntry::Remove(_) => None
+                })
+                .collect();
+            self.check_subject_limits(&msgs)?;
+
+            println!("committing transaction {} of publisher {} with {} operations", id, publisher,
+                     admitted.len());
+            self.apply_entries(&admitted)?;
+            admitted
+        };
+        self.fire_transaction(id, &entries)?;
+        Ok(id)
+    }
+
+    // apply_entries - creates the auto-created topics of the entries and applies them, callers
+    // hold the writes lock
+    fn apply_entries(&self, entries: &[BatchEntry]) -> Result<(), PubSubError> {
+        let mut changes = self.topic_creations(entries.iter().filter_map(|e| match *e {
+            BatchEntry::Publish(ref m) => Some(m),
+            BatchEntry::Remove(_) => None
+        }))?;
+        for e in entries {
+            match *e {
+                BatchEntry::Publish(ref m) => if self.is_retained(m)? {
+                    changes.push(WalEntry::PutMessage { message: m.clone() })
+                },
+                BatchEntry::Remove(ref m) => changes.push(WalEntry::removal(m))
+            }
+        }
+        self.write(changes).map(|_| ())
+    }
+
+    fn fire_transaction(&self, id: Uuid, entries: &[BatchEntry]) -> Result<(), PubSubError> {
+        let mut topics: Vec<&Topic> = vec![];
+        entries.iter().for_each(|e| {
+            let topic = match *e {
+                BatchEntry::Publish(ref m) | BatchEntry::Remove(ref m) => &m.topic
+            };
+            if !topics.contains(&topic) {
+                topics.push(topic);
             }
+        });
+
+        for topic in topics {
+            let batch = Batch {
+                entries: entries.iter()
+                    .filter(|e| match **e {
+                        BatchEntry::Publish(ref m) | BatchEntry::Remove(ref m) => &m.topic == topic
+                    })
+                    .cloned()
+                    .collect(),
+                transaction: Some(id),
+                ..Batch::new(topic.clone())
+            };
+
+            self.topic_subscribers(topic)?.iter().for_each(|s| {
+                // entries accumulated before the transaction go first to keep the order
+                if let Some(pending) = self.batcher.take(&s.id) {
+                    self.send_batch(s, &pending);
+                }
+                self.send_batch(s, &batch);
+            })
         }
+        Ok(())
     }
 
-    pub fn publish_message(&self, m: Message) {
-        let publisher = &m.publisher.clone();
+    // admit - checks a message against its topic configuration and applies topic defaults. It
+    // changes nothing, a topic to be auto-created is checked against the default configuration
+    // and created by store
+    fn admit(&self, m: Message) -> Result<Message, PubSubError> {
+        let config = match stored(self.storage.topic_config(&m.topic))? {
+            Some(c) => c,
+            None if stored(self.storage.auto_create_topics())? => TopicConfig::default(),
+            None => return Err(PubSubError::UnknownTopic(m.topic.clone()))
+        };
+
+        if let Some(ref allowed) = config.allowed_publishers {
+            if !allowed.contains(&m.publisher) {
+                return Err(PubSubError::PublisherNotAllowed {
+                    publisher: m.publisher,
+                    topic: m.topic.clone(),
+                });
+            }
+        }
+
+        if let Some(max) = config.max_message_size {
+            if m.body.len() > max {
+                return Err(PubSubError::MessageTooLarge { size: m.body.len(), limit: max });
+            }
+        }
+
+        let violations = self.schemas.validate(&m.topic, &m.body);
+        if !violations.is_empty() {
+            return Err(PubSubError::SchemaViolation { topic: m.topic.clone(), violations });
+        }
+
+        let ttl = m.ttl.or(config.default_ttl);
+        Ok(m.with_ttl(ttl))
+    }
+
+    // store - auto-creates the unknown topics of admitted messages and stores the retained ones.
+    // Callers hold the writes lock since admitting, so nothing changed in between
+    fn store(&self, msgs: &[Message]) -> Result<(), PubSubError> {
+        let entries = self.store_entries(msgs)?;
+        self.write(entries).map(|_| ())
+    }
+
+    fn store_entries(&self, msgs: &[Message]) -> Result<Vec<WalEntry>, PubSubError> {
+        let mut entries = self.topic_creations(msgs.iter())?;
+        for m in msgs {
+            if self.is_retained(m)? {
+                entries.push(WalEntry::PutMessage { message: m.clone() });
+            }
+        }
+        Ok(entries)
+    }
+
+    // topic_creations - entries creating the unknown topics of the messages with the default
+    // configuration
+    fn topic_creations<'m, I: Iterator<Item = &'m Message>>(&self, msgs: I) -> Result<Vec<WalEntry>, PubSubError> {
+        let mut created: Vec<&Topic> = vec![];
+        for m in msgs {
+            if !created.contains(&&m.topic) && stored(self.storage.topic_config(&m.topic))?.is_none() {
+                created.push(&m.topic);
+            }
+        }
+        Ok(created.into_iter()
+            .map(|topic| {
+                println!("auto-creating topic {}", topic);
+                WalEntry::ConfigureTopic { topic: topic.clone(), config: TopicConfig::default() }
+            })
+            .collect())
+    }
+
+    // check_subject_limits - new retained subjects of all messages must fit into max_subjects
+    // of their topics
+    fn check_subject_limits(&self, msgs: &[Message]) -> Result<(), PubSubError> {
+        let mut new_subjects: HashMap<&Topic, HashSet<(Uuid, &Subject)>> = HashMap::new();
+        for m in msgs {
+            if self.is_retained(m)? && !self.is_known_subject(m)? {
+                new_subjects.entry(&m.topic)
+                    .or_insert(HashSet::new())
+                    .insert((m.publisher, &m.subject));
+            }
+        }
+
+        for (topic, subjects) in new_subjects {
+            let limit = stored(self.storage.topic_config(topic))?.and_then(|c| c.max_subjects);
+            if let Some(max) = limit {
+                if stored(self.storage.subjects_count(topic))? + subjects.len() > max {
+                    return Err(PubSubError::SubjectLimitReached { topic: topic.clone(), limit: max });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_known_subject(&self, m: &Message) -> Result<bool, PubSubError> {
+        stored(self.storage.message(&m.topic, &m.publisher, &m.subject)).map(|m| m.is_some())
+    }
+
+    fn topic_exists(&self, topic: &Topic) -> Result<bool, PubSubError> {
+        Ok(stored(self.storage.auto_create_topics())? || stored(self.storage.topic_config(topic))?.is_some())
+    }
+
+    pub fn topic_config(&self, topic: &Topic) -> Result<TopicConfig, PubSubError> {
+        stored(self.storage.topic_config(topic))?
+            .ok_or(PubSubError::UnknownTopic(topic.clone()))
+    }
+
+    pub fn configure_topic(&self, topic: Topic, config: TopicConfig) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        if config.max_subjects == Some(0) || config.max_message_size == Some(0) {
+            return Err(PubSubError::InvalidTopicConfig(
+                "max_subjects and max_message_size must be greater than zero".to_string()));
+        }
+
+        println!("configuring topic {} with {:?}", topic, config);
+        self.commit(vec![WalEntry::ConfigureTopic { topic, config }]).map(|_| ())
+    }
+
+    pub fn remove_topic(&self, topic: &Topic, actor: &Actor) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let removed = {
+            let _writes = self.writes.lock().unwrap();
+            if stored(self.storage.topic_config(topic))?.is_none() {
+                return Err(PubSubError::UnknownTopic(topic.clone()));
+            }
+            self.write(vec![WalEntry::RemoveTopic { topic: topic.clone() }])?
+        };
+
+        self.audit(actor, AuditAction::RemoveTopic, topic.clone(),
+                   Some(format!("{} retained messages removed", removed.len())));
+
+        let subs = self.topic_subscribers(topic)?;
+        removed.iter().for_each(|m| self.remove_message(m, &subs));
+        Ok(())
+    }
+
+    // register_schema - adds a new schema version to the topic and validates all further messages
+    // against it
+    pub fn register_schema(&self, topic: &Topic, schema: Value) -> Result<u32, PubSubError> {
+        self.check_writable()?;
+        if !self.topic_exists(topic)? {
+            return Err(PubSubError::UnknownTopic(topic.clone()));
+        }
+        Schemas::check(&schema).map_err(PubSubError::InvalidSchema)?;
+        let version = {
+            let _writes = self.writes.lock().unwrap();
+            self.write(vec![WalEntry::RegisterSchema { topic: topic.clone(), schema }])?;
+            self.schemas.active(topic).map_or(0, |s| s.version)
+        };
+        println!("registered schema version {} of topic {}", version, topic);
+        Ok(version)
+    }
+
+    pub fn topic_schema(&self, topic: &Topic, version: Option<u32>) -> Result<TopicSchema, PubSubError> {
+        let schema = match version {
+            Some(v) => self.schemas.get(topic, v),
+            None => self.schemas.active(topic)
+        };
+        schema.ok_or(PubSubError::UnknownSchema { topic: topic.clone(), version })
+    }
+
+    pub fn activate_schema(&self, topic: &Topic, version: u32) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let _writes = self.writes.lock().unwrap();
+        if self.schemas.get(topic, version).is_none() {
+            return Err(PubSubError::UnknownSchema { topic: topic.clone(), version: Some(version) });
+        }
+        self.write(vec![WalEntry::ActivateSchema { topic: topic.clone(), version }])?;
+        println!("activated schema version {} of topic {}", version, topic);
+        Ok(())
+    }
+
+    pub fn deactivate_schema(&self, topic: &Topic) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let _writes = self.writes.lock().unwrap();
+        if self.schemas.active(topic).is_none() {
+            return Err(PubSubError::UnknownSchema { topic: topic.clone(), version: None });
+        }
+        self.write(vec![WalEntry::DeactivateSchema { topic: topic.clone() }])?;
+        println!("deactivated schema of topic {}", topic);
+        Ok(())
+    }
+
+    pub fn set_auto_create_topics(&self, enabled: bool) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        println!("setting topics auto-creation to {}", enabled);
+        self.commit(vec![WalEntry::AutoCreateTopics { enabled }]).map(|_| ())
+    }
+
+    fn is_retained(&self, m: &Message) -> Result<bool, PubSubError> {
+        Ok(m.retention == Retention::Retained && self.topic_retention(&m.topic)? == Retention::Retained)
+    }
+
+    fn topic_retention(&self, topic: &Topic) -> Result<Retention, PubSubError> {
+        Ok(stored(self.storage.topic_config(topic))?.map_or(Retention::Retained, |c| c.retention))
+    }
+
+    pub fn set_topic_retention(&self, topic: Topic, retention: Retention) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        println!("setting retention {:?} for topic {}", retention, topic);
+        let _writes = self.writes.lock().unwrap();
+        let existing = stored(self.storage.topic_config(&topic))?;
+        let config = TopicConfig { retention, ..existing.unwrap_or_default() };
+        self.write(vec![WalEntry::ConfigureTopic { topic, config }]).map(|_| ())
+    }
+
+    pub fn schedule_message(&self, m: Message, schedule: Schedule) -> Result<Uuid, PubSubError> {
+        self.check_writable()?;
         let headers = &m.headers.clone();
         let msg = m.with_headers(unformat_headers(headers));
 
-        match self.publishers.lock().unwrap().get_mut(publisher) {
-            Some(p) => {
-                p.touch();
-                self.register_message(msg.clone());
-                self.fire_receive(msg);
-            }
-            None => println!("Ignoring unknown publisher at message: {}", &msg)
-        }
-    }
-
-    fn register_message(&self, m: Message) {
-        self.topics.lock().unwrap()
-            .entry(m.topic.clone())
-            .or_insert(HashMap::new())
-            .entry(m.publisher.clone())
-            .or_insert(HashMap::new())
-            .insert(m.subject.clone(), m);
-    }
-
-    fn fire_receive(&self, m: Message) {
-        self.subscribers.lock().unwrap()
-            .entry(m.topic.clone())
-            .or_insert(vec![])
-            .iter()
-            .for_each(|s| self.publish(&m, s))
-    }
-
-    pub fn remove(&self, m: Message) {
-        self.publishers.lock().unwrap()
-            .get_mut(&m.publisher)
-            .iter_mut()
-            .for_each(|ref mut p| {
-                p.touch();
-                println!("publisher remove {:?}", &m);
-                self.remove_messages(&m);
-                self.subscribers.lock().unwrap()
-                    .get(m.topic.as_str())
-                    .iter()
-                    .for_each(|subs| self.remove_message(&m, subs));
-            })
+        self.touch_publisher(msg.publisher)?;
+        let now = self.clock.now();
+        let due = schedule.due(now);
+        if due < now {
+            return Err(PubSubError::ScheduleInPast(due.to_rfc3339()));
+        }
+        let id = Uuid::new_v4();
+        {
+            let _writes = self.writes.lock().unwrap();
+            let msg = self.admit(msg)?;
+            self.check_subject_limits(slice::from_ref(&msg))?;
+            self.write(vec![WalEntry::ScheduleMessage { scheduled: ScheduledMessage { id, due, message: msg } }])?;
+        }
+        println!("scheduled message {} at {}", id, due);
+        Ok(id)
+    }
+
+    pub fn scheduled_messages(&self, publisher: Uuid) -> Result<Vec<ScheduledMessage>, PubSubError> {
+        if !stored(self.storage.has_publisher(&publisher))? {
+            return Err(PubSubError::UnknownPublisher(publisher));
+        }
+        Ok(self.scheduler.list(&publisher))
+    }
+
+    pub fn cancel_scheduled(&self, publisher: Uuid, id: Uuid) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        let _writes = self.writes.lock().unwrap();
+        match self.scheduler.get(&id) {
+            Some(ref s) if s.message.publisher == publisher => {
+                self.write(vec![WalEntry::Unschedule { id }])?;
+                println!("cancelled scheduled message {}", id);
+                Ok(())
+            }
+            _ => Err(PubSubError::UnknownScheduled(id))
+        }
+    }
+
+    // publish_scheduled - due messages are admitted again, the topic may have changed since they were
+    // scheduled. A message the topic rejects now is dropped
+    fn publish_scheduled(&self) {
+        let now = self.clock.now();
+        self.scheduler.due(&now).into_iter().for_each(|s| {
+            let published = {
+                let _writes = self.writes.lock().unwrap();
+                // it may have been cancelled since
+                if self.scheduler.get(&s.id).is_none() {
+                    return;
+                }
+                let admitted = self.admit(s.message.clone())
+                    .map(|msg| msg.expiring_from(now))
+                    .and_then(|msg| self.check_subject_limits(slice::from_ref(&msg)).map(|_| msg));
+                let msg = match admitted {
+                    Ok(msg) => msg,
+                    Err(PubSubError::StorageFailed(e)) => return println!("cannot admit scheduled message {}: {}", s.id, e),
+                    Err(e) => {
+                        println!("dropping scheduled message {} rejected by topic {}: {}", s.id, s.message.topic, e);
+                        if let Err(e) = self.write(vec![WalEntry::Unschedule { id: s.id }]) {
+                            println!("failed to drop scheduled message {}: {}", s.id, e);
+                        }
+                        return;
+                    }
+                };
+                self.store_entries(slice::from_ref(&msg))
+                    .and_then(|stored| {
+                        let mut entries = vec![WalEntry::Unschedule { id: s.id }];
+                        entries.extend(stored);
+                        self.write(entries)
+                    })
+                    .map(|_| msg)
+            };
+            let fired = published.and_then(|msg| {
+                println!("publishing scheduled message {} due at {}", s.id, s.due);
+                self.fire_receive(msg)
+            });
+            if let Err(e) = fired {
+                println!("failed to publish scheduled message {}: {}", s.id, e);
+            }
+        })
+    }
+
+    fn fire_receive_all(&self, msgs: &[Message]) -> Result<(), PubSubError> {
+        let mut by_topic: HashMap<&Topic, Vec<Message>> = HashMap::new();
+        msgs.iter().for_each(|m| by_topic.entry(&m.topic).or_insert(vec![]).push(m.clone()));
+
+        for (topic, msgs) in by_topic.iter() {
+            self.topic_subscribers(topic)?.iter()
+                .for_each(|s| self.publish_batch(msgs, s))
+        }
+        Ok(())
+    }
+
+    fn topic_subscribers(&self, topic: &Topic) -> Result<Vec<Subscriber>, PubSubError> {
+        stored(self.storage.topic_subscribers(topic))
+    }
+
+    fn publish_batch(&self, msgs: &Vec<Message>, sub: &Subscriber) {
+        println!("publish {} messages for subscriber: {}", msgs.len(), &sub);
+        let entries = msgs.iter()
+            .map(|m| BatchEntry::Publish(Message { topic: sub.topic.clone(), ..m.clone() }));
+
+        if sub.batching.is_some() {
+            entries.for_each(|e| self.enqueue(sub, e));
+        } else {
+            let batch = Batch { entries: entries.collect(), ..Batch::new(sub.topic.clone()) };
+            self.send_batch(sub, &batch);
+        }
+    }
+
+    fn enqueue(&self, sub: &Subscriber, entry: BatchEntry) {
+        if let Some(batch) = self.batcher.add(sub, entry, self.clock.now()) {
+            self.send_batch(sub, &batch);
+        }
     }
 
-    fn remove_messages(&self, m: &Message) {
-        self.topics.lock().unwrap()
-            .entry(m.topic.clone())
-            .or_insert(HashMap::new())
-            .entry(m.publisher)
-            .or_insert(HashMap::new())
-            .remove(&m.subject);
+    fn flush_batches(&self) {
+        let now = self.clock.now();
+        self.batcher.take_due(&now).iter()
+            .for_each(|&(ref sub, ref batch)| self.send_batch(sub, batch))
     }
-}
\ No newline at end of file
+
+    fn send_batch(&self, sub: &Subscriber, batch: &Batch) {
+        println!("sending batch of {} entries to subscriber: {}", batch.entries.len(), &sub);
+        let address = match self.check_delivery(&sub.callback) {
+            Ok(address) => address,
+            Err(reason) => return self.evict(sub, format!("callback rejected: {}", reason))
+        };
+        let c = self.subs_service.as_ref();
+
+        match c.deliver_batch(&sub.callback, address.as_ref(), self.callback_key(&sub.callback).as_ref(), batch) {
+            Ok(_) =>
+                println!("batch delivery for {} returned Ok", &sub),
+            Err(s) =>
+                self.evict(sub, format!("batch delivery failed with status: {:?}", s))
+        }
+    }
+
+    fn fire_receive(&self, m: Message) -> Result<(), PubSubError> {
+        self.topic_subscribers(&m.topic)?.iter()
+            .for_each(|s| self.publish(&m, s));
+        Ok(())
+    }
+
+    pub fn remove(&self, m: Message) -> Result<(), PubSubError> {
+        self.check_writable()?;
+        self.touch_publisher(m.publisher)?;
+        {
+            let _writes = self.writes.lock().unwrap();
+            if !self.is_known_subject(&m)? {
+                return Err(PubSubError::UnknownSubject {
+                    topic: m.topic.clone(),
+                    subject: m.subject.clone(),
+                });
+            }
+            println!("publisher remove {:?}", &m);
+            self.write(vec![WalEntry::removal(&m)])?;
+        }
+        self.remove_message(&m, &self.topic_subscribers(&m.topic)?);
+        Ok(())
+    }
+
+    // remove_matching - removes all subjects of the publisher in the topic matching the filter,
+    // returns the removed subjects
+    pub fn remove_matching(&self, publisher: Uuid, topic: &Topic, filter: &SubjectFilter)
+                           -> Result<Vec<Subject>, PubSubError> {
+        self.check_writable()?;
+        self.touch_publisher(publisher)?;
+
+        let mut removed = {
+            let _writes = self.writes.lock().unwrap();
+            let matching = stored(self.storage.publisher_messages(topic, &publisher))?.iter()
+                .filter(|m| filter.matches(&m.subject))
+                .map(WalEntry::removal)
+                .collect();
+            self.write(matching)?
+        };
+        removed.sort_by(|a, b| a.subject.cmp(&b.subject));
+        println!("publisher {} removed {} subjects of topic {} matching {:?}", publisher,
+                 removed.len(), topic, filter);
+
+        let subs = self.topic_subscribers(topic)?;
+        removed.iter().for_each(|m| self.remove_message(m, &subs));
+        Ok(removed.into_iter().map(|m| m.subject).collect())
+    }
+
+    fn expire_messages(&self) {
+        let now = self.clock.now();
+        let candidates: Vec<Message> = match self.storage.messages() {
+            Ok(msgs) => msgs.into_iter().filter(|m| m.is_expired(&now)).collect(),
+            Err(e) => return println!("cannot look for expired messages: {}", e)
+        };
+
+        candidates.iter().for_each(|m| {
+            // the subject may have been republished since it was collected, it is removed only
+            // if the stored message is still expired
+            let expired = {
+                let _writes = self.writes.lock().unwrap();
+                match stored(self.storage.message(&m.topic, &m.publisher, &m.subject)) {
+                    Ok(Some(ref current)) if current.is_expired(&now) => self.write(vec![WalEntry::removal(current)]),
+                    Ok(_) => Ok(vec![]),
+                    Err(e) => Err(e)
+                }
+            };
+            let notified = expired.and_then(|expired| {
+                for m in &expired {
+                    println!("message expired {}", m);
+                    self.remove_message(m, &self.topic_subscribers(&m.topic)?);
+                }
+                Ok(())
+            });
+            if let Err(e) = notified {
+                println!("failed to expire message {}: {}", m, e);
+            }
+        })
+    }
+}
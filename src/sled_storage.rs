@@ -0,0 +1,409 @@
+use chrono::prelude::*;
+use models::{Message, Subject, Subscriber, Topic, TopicConfig};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use sled::{ConfigBuilder, Tree};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use storage::{MessageOp, Storage};
+use uuid::Uuid;
+
+const PUBLISHER_PREFIX: &[u8] = b"p\0";
+const PENDING_SUBSCRIBER_PREFIX: &[u8] = b"s\0";
+const SUBSCRIBER_PREFIX: &[u8] = b"a\0";
+const MESSAGE_PREFIX: &[u8] = b"m\0";
+const PUBLISHER_TOKEN_HASH_PREFIX: &[u8] = b"h\0";
+const PUBLISHER_SEEN_PREFIX: &[u8] = b"l\0";
+// PUBLISHER_MESSAGE_PREFIX - topic and subject of the retained messages of a publisher
+const PUBLISHER_MESSAGE_PREFIX: &[u8] = b"r\0";
+// SUBSCRIBER_TOPIC_PREFIX - topic of an active subscriber
+const SUBSCRIBER_TOPIC_PREFIX: &[u8] = b"i\0";
+const TOPIC_CONFIG_PREFIX: &[u8] = b"c\0";
+const AUTO_CREATE_TOPICS_KEY: &[u8] = b"o\0auto_create_topics\0";
+
+// SledStorage - keeps the state in an embedded on-disk key-value store, so it survives restarts
+// without a write-ahead log. Keys are prefixed by the kind of the record, values are JSON.
+// Writes take the lock exclusively, so readers never observe half applied ops. The writes of a call
+// are committed together, a failed one rolls back those before it. A failed read or write fails
+// the whole call, records that cannot be decoded fail it too
+pub struct SledStorage {
+    tree: Tree,
+    lock: RwLock<()>,
+}
+
+// Write - a staged change of a key, None deletes it
+type Write = (Vec<u8>, Option<Vec<u8>>);
+
+impl SledStorage {
+    pub fn open(path: &Path) -> Result<SledStorage, String> {
+        let config = ConfigBuilder::new().path(path).build();
+        let tree = Tree::start(config).map_err(|e| format!("{:?}", e))?;
+        Ok(SledStorage { tree, lock: RwLock::new(()) })
+    }
+
+    fn get<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>, String> {
+        match self.tree.get(key) {
+            Ok(Some(v)) => decode(key, &v).map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(format!("read of {:?} failed: {:?}", String::from_utf8_lossy(key), e))
+        }
+    }
+
+    fn set<T: Serialize>(&self, key: Vec<u8>, value: &T) -> Result<(), String> {
+        let v = serde_json::to_vec(value)
+            .map_err(|e| format!("cannot encode {:?}: {}", String::from_utf8_lossy(&key), e))?;
+        self.tree.set(key.clone(), v)
+            .map(|_| ())
+            .map_err(|e| format!("write of {:?} failed: {:?}", String::from_utf8_lossy(&key), e))
+    }
+
+    fn del(&self, key: &[u8]) -> Result<bool, String> {
+        self.tree.del(key)
+            .map(|removed| removed.is_some())
+            .map_err(|e| format!("delete of {:?} failed: {:?}", String::from_utf8_lossy(key), e))
+    }
+
+    fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.tree.get(key)
+            .map(|v| v.map(|v| v.to_vec()))
+            .map_err(|e| format!("read of {:?} failed: {:?}", String::from_utf8_lossy(key), e))
+    }
+
+    fn write_raw(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), String> {
+        match value {
+            Some(v) => self.tree.set(key.to_vec(), v)
+                .map(|_| ())
+                .map_err(|e| format!("write of {:?} failed: {:?}", String::from_utf8_lossy(key), e)),
+            None => self.del(key).map(|_| ())
+        }
+    }
+
+    // commit - applies the writes in order, a failure restores the previous values of the keys
+    // written before it
+    fn commit(&self, writes: Vec<Write>) -> Result<(), String> {
+        let mut undo: Vec<Write> = vec![];
+        for (k, v) in writes {
+            let written = self.get_raw(&k).and_then(|previous| self.write_raw(&k, v).map(|_| previous));
+            match written {
+                Ok(previous) => undo.push((k, previous)),
+                Err(e) => {
+                    for (k, previous) in undo.into_iter().rev() {
+                        if let Err(e) = self.write_raw(&k, previous) {
+                            println!("rollback of {:?} failed: {}", String::from_utf8_lossy(&k), e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan<T: DeserializeOwned>(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, T)>, String> {
+        let mut found = vec![];
+        for r in self.tree.scan(prefix) {
+            let (k, v) = r.map_err(|e| format!("scan of {:?} failed: {:?}", String::from_utf8_lossy(prefix), e))?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            let t = decode(&k, &v)?;
+            found.push((k, t));
+        }
+        Ok(found)
+    }
+
+    fn values<T: DeserializeOwned>(&self, prefix: &[u8]) -> Result<Vec<T>, String> {
+        Ok(self.scan(prefix)?.into_iter().map(|(_, t)| t).collect())
+    }
+}
+
+fn put<T: Serialize>(key: Vec<u8>, value: &T) -> Result<Write, String> {
+    match serde_json::to_vec(value) {
+        Ok(v) => Ok((key, Some(v))),
+        Err(e) => Err(format!("cannot encode {:?}: {}", String::from_utf8_lossy(&key), e))
+    }
+}
+
+fn delete(key: Vec<u8>) -> Write {
+    (key, None)
+}
+
+fn decode<T: DeserializeOwned>(key: &[u8], value: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(value)
+        .map_err(|e| format!("cannot decode stored {:?}: {}", String::from_utf8_lossy(key), e))
+}
+
+fn key(prefix: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut k = prefix.to_vec();
+    parts.iter().for_each(|p| {
+        k.extend_from_slice(p);
+        k.push(0);
+    });
+    k
+}
+
+fn id_key(prefix: &[u8], id: &Uuid) -> Vec<u8> {
+    key(prefix, &[id.as_bytes()])
+}
+
+fn message_key(topic: &Topic, publisher: &Uuid, subject: &Subject) -> Vec<u8> {
+    key(MESSAGE_PREFIX, &[topic.as_bytes(), publisher.as_bytes(), subject.as_bytes()])
+}
+
+fn publisher_message_key(publisher: &Uuid, topic: &Topic, subject: &Subject) -> Vec<u8> {
+    key(PUBLISHER_MESSAGE_PREFIX, &[publisher.as_bytes(), topic.as_bytes(), subject.as_bytes()])
+}
+
+fn subscriber_key(topic: &Topic, id: &Uuid) -> Vec<u8> {
+    key(SUBSCRIBER_PREFIX, &[topic.as_bytes(), id.as_bytes()])
+}
+
+fn topic_config_key(topic: &Topic) -> Vec<u8> {
+    key(TOPIC_CONFIG_PREFIX, &[topic.as_bytes()])
+}
+
+impl Storage for SledStorage {
+    fn add_publisher(&self, id: Uuid, token_hash: Option<String>) -> Result<bool, String> {
+        let _w = self.lock.write().unwrap();
+        let k = id_key(PUBLISHER_PREFIX, &id);
+        if self.get::<Uuid>(&k)?.is_some() {
+            return Ok(false);
+        }
+        let mut writes = vec![];
+        if let Some(token_hash) = token_hash {
+            writes.push(put(id_key(PUBLISHER_TOKEN_HASH_PREFIX, &id), &token_hash)?);
+        }
+        writes.push(put(id_key(PUBLISHER_SEEN_PREFIX, &id), &Local::now())?);
+        writes.push(put(k, &id)?);
+        self.commit(writes)?;
+        Ok(true)
+    }
+
+    fn remove_publisher(&self, id: &Uuid) -> Result<Option<Vec<Message>>, String> {
+        let _w = self.lock.write().unwrap();
+        let k = id_key(PUBLISHER_PREFIX, id);
+        if self.get::<Uuid>(&k)?.is_none() {
+            return Ok(None);
+        }
+        let mut writes = vec![delete(k), delete(id_key(PUBLISHER_TOKEN_HASH_PREFIX, id)),
+                              delete(id_key(PUBLISHER_SEEN_PREFIX, id))];
+        let mut removed = vec![];
+        for (index_key, (topic, subject)) in self.scan::<(Topic, Subject)>(&id_key(PUBLISHER_MESSAGE_PREFIX, id))? {
+            let msg_key = message_key(&topic, id, &subject);
+            if let Some(m) = self.get::<Message>(&msg_key)? {
+                removed.push(m);
+            }
+            writes.push(delete(msg_key));
+            writes.push(delete(index_key));
+        }
+        self.commit(writes)?;
+        Ok(Some(removed))
+    }
+
+    fn touch_publisher(&self, id: &Uuid) -> Result<bool, String> {
+        let _w = self.lock.write().unwrap();
+        if self.get::<Uuid>(&id_key(PUBLISHER_PREFIX, id))?.is_none() {
+            return Ok(false);
+        }
+        self.set(id_key(PUBLISHER_SEEN_PREFIX, id), &Local::now())?;
+        Ok(true)
+    }
+
+    fn has_publisher(&self, id: &Uuid) -> Result<bool, String> {
+        let _r = self.lock.read().unwrap();
+        Ok(self.get::<Uuid>(&id_key(PUBLISHER_PREFIX, id))?.is_some())
+    }
+
+    fn publisher_token_hash(&self, id: &Uuid) -> Result<Option<String>, String> {
+        let _r = self.lock.read().unwrap();
+        self.get(&id_key(PUBLISHER_TOKEN_HASH_PREFIX, id))
+    }
+
+    fn set_publisher_token_hash(&self, id: &Uuid, token_hash: Option<String>) -> Result<bool, String> {
+        let _w = self.lock.write().unwrap();
+        if self.get::<Uuid>(&id_key(PUBLISHER_PREFIX, id))?.is_none() {
+            return Ok(false);
+        }
+        match token_hash {
+            Some(token_hash) => self.set(id_key(PUBLISHER_TOKEN_HASH_PREFIX, id), &token_hash)?,
+            None => {
+                self.del(&id_key(PUBLISHER_TOKEN_HASH_PREFIX, id))?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn publishers(&self) -> Result<Vec<Uuid>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(PUBLISHER_PREFIX)
+    }
+
+    fn add_pending_subscriber(&self, s: Subscriber) -> Result<(), String> {
+        let _w = self.lock.write().unwrap();
+        self.set(id_key(PENDING_SUBSCRIBER_PREFIX, &s.id), &s)
+    }
+
+    fn activate_subscriber(&self, id: &Uuid) -> Result<Option<Subscriber>, String> {
+        let _w = self.lock.write().unwrap();
+        let pending_key = id_key(PENDING_SUBSCRIBER_PREFIX, id);
+        let s = match self.get::<Subscriber>(&pending_key)? {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        self.commit(vec![
+            put(subscriber_key(&s.topic, &s.id), &s)?,
+            put(id_key(SUBSCRIBER_TOPIC_PREFIX, &s.id), &s.topic)?,
+            delete(pending_key),
+        ])?;
+        Ok(Some(s))
+    }
+
+    fn remove_subscriber(&self, id: &Uuid) -> Result<bool, String> {
+        let _w = self.lock.write().unwrap();
+        let pending_key = id_key(PENDING_SUBSCRIBER_PREFIX, id);
+        let index_key = id_key(SUBSCRIBER_TOPIC_PREFIX, id);
+        let pending = self.get::<Subscriber>(&pending_key)?.is_some();
+        let topic = self.get::<Topic>(&index_key)?;
+        let mut writes = vec![];
+        if pending {
+            writes.push(delete(pending_key));
+        }
+        if let Some(ref topic) = topic {
+            writes.push(delete(subscriber_key(topic, id)));
+            writes.push(delete(index_key));
+        }
+        self.commit(writes)?;
+        Ok(pending || topic.is_some())
+    }
+
+    fn is_active_subscriber(&self, id: &Uuid) -> Result<bool, String> {
+        let _r = self.lock.read().unwrap();
+        Ok(self.get::<Topic>(&id_key(SUBSCRIBER_TOPIC_PREFIX, id))?.is_some())
+    }
+
+    fn topic_subscribers(&self, topic: &Topic) -> Result<Vec<Subscriber>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(&key(SUBSCRIBER_PREFIX, &[topic.as_bytes()]))
+    }
+
+    fn pending_subscribers(&self) -> Result<Vec<Subscriber>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(PENDING_SUBSCRIBER_PREFIX)
+    }
+
+    fn subscribers(&self) -> Result<Vec<Subscriber>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(SUBSCRIBER_PREFIX)
+    }
+
+    // apply - the ops are staged first, so later ops see the earlier ones, and committed together
+    fn apply(&self, ops: Vec<MessageOp>) -> Result<Vec<Message>, String> {
+        let _w = self.lock.write().unwrap();
+        let mut staged: HashMap<Vec<u8>, Option<Message>> = HashMap::new();
+        let mut writes = vec![];
+        let mut removed = vec![];
+        for op in ops {
+            match op {
+                MessageOp::Put(m) => {
+                    let k = message_key(&m.topic, &m.publisher, &m.subject);
+                    writes.push(put(k.clone(), &m)?);
+                    writes.push(put(publisher_message_key(&m.publisher, &m.topic, &m.subject), &(&m.topic, &m.subject))?);
+                    staged.insert(k, Some(m));
+                }
+                MessageOp::Remove { topic, publisher, subject } => {
+                    let k = message_key(&topic, &publisher, &subject);
+                    let current = match staged.get(&k) {
+                        Some(m) => m.clone(),
+                        None => self.get::<Message>(&k)?
+                    };
+                    if let Some(m) = current {
+                        writes.push(delete(k.clone()));
+                        writes.push(delete(publisher_message_key(&publisher, &topic, &subject)));
+                        staged.insert(k, None);
+                        removed.push(m);
+                    }
+                }
+            }
+        }
+        self.commit(writes)?;
+        Ok(removed)
+    }
+
+    fn message(&self, topic: &Topic, publisher: &Uuid, subject: &Subject) -> Result<Option<Message>, String> {
+        let _r = self.lock.read().unwrap();
+        self.get(&message_key(topic, publisher, subject))
+    }
+
+    fn publisher_messages(&self, topic: &Topic, publisher: &Uuid) -> Result<Vec<Message>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(&key(MESSAGE_PREFIX, &[topic.as_bytes(), publisher.as_bytes()]))
+    }
+
+    fn topic_messages(&self, topic: &Topic) -> Result<Vec<Message>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(&key(MESSAGE_PREFIX, &[topic.as_bytes()]))
+    }
+
+    fn messages(&self) -> Result<Vec<Message>, String> {
+        let _r = self.lock.read().unwrap();
+        self.values(MESSAGE_PREFIX)
+    }
+
+    fn subjects_count(&self, topic: &Topic) -> Result<usize, String> {
+        let _r = self.lock.read().unwrap();
+        Ok(self.scan::<Message>(&key(MESSAGE_PREFIX, &[topic.as_bytes()]))?.len())
+    }
+
+    fn configure_topic(&self, topic: Topic, config: TopicConfig) -> Result<(), String> {
+        let _w = self.lock.write().unwrap();
+        self.set(topic_config_key(&topic), &(topic, config))
+    }
+
+    fn topic_config(&self, topic: &Topic) -> Result<Option<TopicConfig>, String> {
+        let _r = self.lock.read().unwrap();
+        Ok(self.get::<(Topic, TopicConfig)>(&topic_config_key(topic))?.map(|(_, c)| c))
+    }
+
+    fn topic_configs(&self) -> Result<HashMap<Topic, TopicConfig>, String> {
+        let _r = self.lock.read().unwrap();
+        Ok(self.values::<(Topic, TopicConfig)>(TOPIC_CONFIG_PREFIX)?.into_iter().collect())
+    }
+
+    fn remove_topic(&self, topic: &Topic) -> Result<Vec<Message>, String> {
+        let _w = self.lock.write().unwrap();
+        let mut writes = vec![];
+        let mut removed = vec![];
+        for (k, m) in self.scan::<Message>(&key(MESSAGE_PREFIX, &[topic.as_bytes()]))? {
+            writes.push(delete(k));
+            writes.push(delete(publisher_message_key(&m.publisher, &m.topic, &m.subject)));
+            removed.push(m);
+        }
+        writes.push(delete(topic_config_key(topic)));
+        self.commit(writes)?;
+        Ok(removed)
+    }
+
+    fn auto_create_topics(&self) -> Result<bool, String> {
+        let _r = self.lock.read().unwrap();
+        Ok(self.get(AUTO_CREATE_TOPICS_KEY)?.unwrap_or(true))
+    }
+
+    fn set_auto_create_topics(&self, enabled: bool) -> Result<(), String> {
+        let _w = self.lock.write().unwrap();
+        self.set(AUTO_CREATE_TOPICS_KEY.to_vec(), &enabled)
+    }
+
+    fn clear(&self) -> Result<(), String> {
+        let _w = self.lock.write().unwrap();
+        let prefixes = [PUBLISHER_PREFIX, PUBLISHER_TOKEN_HASH_PREFIX, PUBLISHER_SEEN_PREFIX, PUBLISHER_MESSAGE_PREFIX,
+            PENDING_SUBSCRIBER_PREFIX, SUBSCRIBER_PREFIX, SUBSCRIBER_TOPIC_PREFIX, MESSAGE_PREFIX, TOPIC_CONFIG_PREFIX];
+        for prefix in prefixes.iter() {
+            for (k, _) in self.scan::<serde_json::Value>(prefix)? {
+                self.del(&k)?;
+            }
+        }
+        self.del(AUTO_CREATE_TOPICS_KEY).map(|_| ())
+    }
+}
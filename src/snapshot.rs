@@ -0,0 +1,155 @@
+use bincode;
+use chrono::prelude::*;
+use errors::PubSubError;
+use models::{Message, Subscriber, Topic, TopicConfig};
+use rocket::Config;
+use scheduler::ScheduledMessage;
+use schemas::TopicSchemas;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+use wal::WalEntry;
+
+const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotFormat {
+    Json,
+    Binary,
+}
+
+impl SnapshotFormat {
+    // from_path - files with .json extension are JSON snapshots, anything else is binary
+    pub fn from_path(path: &Path) -> SnapshotFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => SnapshotFormat::Json,
+            _ => SnapshotFormat::Binary,
+        }
+    }
+}
+
+// SnapshotConfig - snapshots are written on the server only into the directory set by snapshot_dir
+// in Rocket.toml extras or ROCKET_SNAPSHOT_DIR
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub dir: Option<PathBuf>,
+}
+
+impl SnapshotConfig {
+    pub fn from_config(config: &Config) -> SnapshotConfig {
+        SnapshotConfig { dir: config.get_str("snapshot_dir").ok().map(PathBuf::from) }
+    }
+
+    // path - the file of a snapshot in the directory, the name must not lead out of it
+    pub fn path(&self, name: &str) -> Result<PathBuf, PubSubError> {
+        let dir = self.dir.as_ref().ok_or(PubSubError::SnapshotDirDisabled)?;
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(dir.join(name)),
+            _ => Err(PubSubError::InvalidName {
+                field: "snapshot",
+                value: name.to_string(),
+                reason: "must be a file name without directories".to_string(),
+            })
+        }
+    }
+}
+
+// Snapshot - full state of a server at a point in time: retained messages, publishers,
+// subscriptions, topic configuration and schemas, and scheduled messages
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub taken_at: DateTime<Local>,
+    pub auto_create_topics: bool,
+    pub topic_configs: HashMap<Topic, TopicConfig>,
+    pub publishers: Vec<Uuid>,
+    pub pending_subscribers: Vec<Subscriber>,
+    pub subscribers: Vec<Subscriber>,
+    pub messages: Vec<Message>,
+    // publisher_token_hashes - see auth::hash_token
+    #[serde(default)]
+    pub publisher_token_hashes: HashMap<Uuid, String>,
+    pub schemas: HashMap<Topic, TopicSchemas>,
+    pub scheduled: Vec<ScheduledMessage>,
+}
+
+impl Snapshot {
+    pub fn new(taken_at: DateTime<Local>) -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at,
+            auto_create_topics: true,
+            topic_configs: HashMap::new(),
+            publishers: vec![],
+            pending_subscribers: vec![],
+            subscribers: vec![],
+            messages: vec![],
+            publisher_token_hashes: HashMap::new(),
+            schemas: HashMap::new(),
+            scheduled: vec![],
+        }
+    }
+
+    // wal_entries - the minimal list of log entries rebuilding the snapshot state
+    pub fn wal_entries(&self) -> Vec<WalEntry> {
+        let mut entries = vec![WalEntry::AutoCreateTopics { enabled: self.auto_create_topics }];
+        entries.extend(self.topic_configs.iter()
+            .map(|(topic, config)| WalEntry::ConfigureTopic { topic: topic.clone(), config: config.clone() }));
+        self.schemas.iter().for_each(|(topic, schemas)| {
+            entries.extend(schemas.versions.iter()
+                .map(|schema| WalEntry::RegisterSchema { topic: topic.clone(), schema: schema.clone() }));
+            entries.push(match schemas.active {
+                Some(version) => WalEntry::ActivateSchema { topic: topic.clone(), version },
+                None => WalEntry::DeactivateSchema { topic: topic.clone() }
+            });
+        });
+        entries.extend(self.publishers.iter()
+            .map(|id| WalEntry::AddPublisher { id: *id, token_hash: self.publisher_token_hashes.get(id).cloned() }));
+        entries.extend(self.pending_subscribers.iter()
+            .map(|s| WalEntry::AddPendingSubscriber { subscriber: s.clone() }));
+        self.subscribers.iter().for_each(|s| {
+            entries.push(WalEntry::AddPendingSubscriber { subscriber: s.clone() });
+            entries.push(WalEntry::ActivateSubscriber { id: s.id });
+        });
+        entries.extend(self.messages.iter().map(|m| WalEntry::PutMessage { message: m.clone() }));
+        entries.extend(self.scheduled.iter().map(|s| WalEntry::ScheduleMessage { scheduled: s.clone() }));
+        entries
+    }
+
+    pub fn encode(&self, format: SnapshotFormat) -> Result<Vec<u8>, String> {
+        match format {
+            SnapshotFormat::Json => serde_json::to_vec_pretty(self).map_err(|e| format!("{}", e)),
+            SnapshotFormat::Binary => bincode::serialize(self).map_err(|e| format!("{}", e)),
+        }
+    }
+
+    pub fn decode(bytes: &[u8], format: SnapshotFormat) -> Result<Snapshot, String> {
+        let snapshot: Snapshot = match format {
+            SnapshotFormat::Json => serde_json::from_slice(bytes).map_err(|e| format!("{}", e))?,
+            SnapshotFormat::Binary => bincode::deserialize(bytes).map_err(|e| format!("{}", e))?,
+        };
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version {}", snapshot.version));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let bytes = self.encode(SnapshotFormat::from_path(path))?;
+        File::create(path)
+            .and_then(|mut f| f.write_all(&bytes).and_then(|_| f.sync_all()))
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    pub fn read(path: &Path) -> Result<Snapshot, String> {
+        let mut bytes = vec![];
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Snapshot::decode(&bytes, SnapshotFormat::from_path(path))
+    }
+}
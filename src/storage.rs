@@ -0,0 +1,291 @@
+use models::{Message, Publisher, Subject, Subscriber, Topic, TopicConfig};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+// MessageOp - a change of retained messages, a list of them is applied by a storage atomically
+#[derive(Debug, Clone)]
+pub enum MessageOp {
+    Put(Message),
+    Remove { topic: Topic, publisher: Uuid, subject: Subject },
+}
+
+// Storage - keeps retained messages, publishers, subscriptions and topic configuration of a
+// server. Implementations synchronize internally, the server orders mutations itself. Every
+// method fails with a description of the error when the backend cannot read or write
+pub trait Storage {
+    // add_publisher - false if the publisher already exists
+    fn add_publisher(&self, id: Uuid, token_hash: Option<String>) -> Result<bool, String>;
+    // remove_publisher - removes the publisher with all its retained messages, None if unknown
+    fn remove_publisher(&self, id: &Uuid) -> Result<Option<Vec<Message>>, String>;
+    // touch_publisher - marks the publisher as seen, false if unknown
+    fn touch_publisher(&self, id: &Uuid) -> Result<bool, String>;
+    fn has_publisher(&self, id: &Uuid) -> Result<bool, String>;
+    fn publisher_token_hash(&self, id: &Uuid) -> Result<Option<String>, String>;
+    // set_publisher_token_hash - false if the publisher is unknown
+    fn set_publisher_token_hash(&self, id: &Uuid, token_hash: Option<String>) -> Result<bool, String>;
+    fn publishers(&self) -> Result<Vec<Uuid>, String>;
+
+    fn add_pending_subscriber(&self, s: Subscriber) -> Result<(), String>;
+    // activate_subscriber - moves a pending subscriber to the subscribers of its topic
+    fn activate_subscriber(&self, id: &Uuid) -> Result<Option<Subscriber>, String>;
+    // remove_subscriber - removes a pending or active subscriber, false if unknown
+    fn remove_subscriber(&self, id: &Uuid) -> Result<bool, String>;
+    fn is_active_subscriber(&self, id: &Uuid) -> Result<bool, String>;
+    fn topic_subscribers(&self, topic: &Topic) -> Result<Vec<Subscriber>, String>;
+    fn pending_subscribers(&self) -> Result<Vec<Subscriber>, String>;
+    fn subscribers(&self) -> Result<Vec<Subscriber>, String>;
+
+    // apply - applies all ops at once, returns removed messages
+    fn apply(&self, ops: Vec<MessageOp>) -> Result<Vec<Message>, String>;
+    fn message(&self, topic: &Topic, publisher: &Uuid, subject: &Subject) -> Result<Option<Message>, String>;
+    fn publisher_messages(&self, topic: &Topic, publisher: &Uuid) -> Result<Vec<Message>, String>;
+    fn topic_messages(&self, topic: &Topic) -> Result<Vec<Message>, String>;
+    fn messages(&self) -> Result<Vec<Message>, String>;
+    fn subjects_count(&self, topic: &Topic) -> Result<usize, String>;
+
+    fn configure_topic(&self, topic: Topic, config: TopicConfig) -> Result<(), String>;
+    // topic_config - None if the topic was never configured nor auto-created
+    fn topic_config(&self, topic: &Topic) -> Result<Option<TopicConfig>, String>;
+    fn topic_configs(&self) -> Result<HashMap<Topic, TopicConfig>, String>;
+    // remove_topic - removes the configuration and all retained messages of the topic, returns
+    // the messages
+    fn remove_topic(&self, topic: &Topic) -> Result<Vec<Message>, String>;
+    // auto_create_topics - whether a first publish to an unknown topic creates it, true unless set
+    fn auto_create_topics(&self) -> Result<bool, String>;
+    fn set_auto_create_topics(&self, enabled: bool) -> Result<(), String>;
+
+    fn clear(&self) -> Result<(), String>;
+}
+
+// MemoryStorage - keeps everything in memory, the state vanishes on restart unless the server
+// runs with a write-ahead log
+pub struct MemoryStorage {
+    publishers: Mutex<HashMap<Uuid, Publisher>>,
+    pending_subscribers: Mutex<HashMap<Uuid, Subscriber>>,
+    subscribers: Mutex<HashMap<Topic, Vec<Subscriber>>>,
+    // topics - main data container. A Subject can have only one message, i.e. Subject is a
+    // unique of a Message
+    topics: Mutex<HashMap<Topic, HashMap<Uuid, HashMap<Subject, Message>>>>,
+    topic_configs: Mutex<HashMap<Topic, TopicConfig>>,
+    auto_create_topics: Mutex<bool>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            publishers: Mutex::new(HashMap::new()),
+            pending_subscribers: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(HashMap::new()),
+            topics: Mutex::new(HashMap::new()),
+            topic_configs: Mutex::new(HashMap::new()),
+            auto_create_topics: Mutex::new(true),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn add_publisher(&self, id: Uuid, token_hash: Option<String>) -> Result<bool, String> {
+        let mut publishers = self.publishers.lock().unwrap();
+        if publishers.contains_key(&id) {
+            return Ok(false);
+        }
+        publishers.insert(id, Publisher::new(id, token_hash));
+        Ok(true)
+    }
+
+    fn remove_publisher(&self, id: &Uuid) -> Result<Option<Vec<Message>>, String> {
+        if self.publishers.lock().unwrap().remove(id).is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.topics.lock().unwrap()
+            .values_mut()
+            .filter_map(|pubs| pubs.remove(id))
+            .flat_map(|msgs| msgs.into_iter().map(|(_, m)| m))
+            .collect()))
+    }
+
+    fn touch_publisher(&self, id: &Uuid) -> Result<bool, String> {
+        match self.publishers.lock().unwrap().get_mut(id) {
+            Some(p) => {
+                p.touch();
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    fn has_publisher(&self, id: &Uuid) -> Result<bool, String> {
+        Ok(self.publishers.lock().unwrap().contains_key(id))
+    }
+
+    fn publisher_token_hash(&self, id: &Uuid) -> Result<Option<String>, String> {
+        Ok(self.publishers.lock().unwrap().get(id).and_then(|p| p.token_hash.clone()))
+    }
+
+    fn set_publisher_token_hash(&self, id: &Uuid, token_hash: Option<String>) -> Result<bool, String> {
+        match self.publishers.lock().unwrap().get_mut(id) {
+            Some(p) => {
+                p.token_hash = token_hash;
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    fn publishers(&self) -> Result<Vec<Uuid>, String> {
+        Ok(self.publishers.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn add_pending_subscriber(&self, s: Subscriber) -> Result<(), String> {
+        self.pending_subscribers.lock().unwrap().insert(s.id, s);
+        Ok(())
+    }
+
+    fn activate_subscriber(&self, id: &Uuid) -> Result<Option<Subscriber>, String> {
+        let s = match self.pending_subscribers.lock().unwrap().remove(id) {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        self.subscribers.lock().unwrap()
+            .entry(s.topic.clone())
+            .or_insert(vec![])
+            .push(s.clone());
+        Ok(Some(s))
+    }
+
+    fn remove_subscriber(&self, id: &Uuid) -> Result<bool, String> {
+        let pending = self.pending_subscribers.lock().unwrap().remove(id).is_some();
+        let mut active = false;
+        for (_, subs) in self.subscribers.lock().unwrap().iter_mut() {
+            let before = subs.len();
+            subs.retain(|s| &s.id != id);
+            active = active || subs.len() != before;
+        }
+        Ok(pending || active)
+    }
+
+    fn is_active_subscriber(&self, id: &Uuid) -> Result<bool, String> {
+        Ok(self.subscribers.lock().unwrap()
+            .values()
+            .any(|subs| subs.iter().any(|s| &s.id == id)))
+    }
+
+    fn topic_subscribers(&self, topic: &Topic) -> Result<Vec<Subscriber>, String> {
+        Ok(self.subscribers.lock().unwrap()
+            .get(topic)
+            .cloned()
+            .unwrap_or(vec![]))
+    }
+
+    fn pending_subscribers(&self) -> Result<Vec<Subscriber>, String> {
+        Ok(self.pending_subscribers.lock().unwrap().values().cloned().collect())
+    }
+
+    fn subscribers(&self) -> Result<Vec<Subscriber>, String> {
+        Ok(self.subscribers.lock().unwrap()
+            .values()
+            .flat_map(|subs| subs.iter())
+            .cloned()
+            .collect())
+    }
+
+    fn apply(&self, ops: Vec<MessageOp>) -> Result<Vec<Message>, String> {
+        let mut topics = self.topics.lock().unwrap();
+        Ok(ops.into_iter()
+            .filter_map(|op| match op {
+                MessageOp::Put(m) => {
+                    topics.entry(m.topic.clone())
+                        .or_insert(HashMap::new())
+                        .entry(m.publisher)
+                        .or_insert(HashMap::new())
+                        .insert(m.subject.clone(), m);
+                    None
+                }
+                MessageOp::Remove { topic, publisher, subject } =>
+                    topics.get_mut(&topic)
+                        .and_then(|pubs| pubs.get_mut(&publisher))
+                        .and_then(|msgs| msgs.remove(&subject))
+            })
+            .collect())
+    }
+
+    fn message(&self, topic: &Topic, publisher: &Uuid, subject: &Subject) -> Result<Option<Message>, String> {
+        Ok(self.topics.lock().unwrap()
+            .get(topic)
+            .and_then(|pubs| pubs.get(publisher))
+            .and_then(|msgs| msgs.get(subject))
+            .cloned())
+    }
+
+    fn publisher_messages(&self, topic: &Topic, publisher: &Uuid) -> Result<Vec<Message>, String> {
+        Ok(self.topics.lock().unwrap()
+            .get(topic)
+            .and_then(|pubs| pubs.get(publisher))
+            .map_or(vec![], |msgs| msgs.values().cloned().collect()))
+    }
+
+    fn topic_messages(&self, topic: &Topic) -> Result<Vec<Message>, String> {
+        Ok(self.topics.lock().unwrap()
+            .get(topic)
+            .map_or(vec![], |pubs| pubs.values().flat_map(|msgs| msgs.values()).cloned().collect()))
+    }
+
+    fn messages(&self) -> Result<Vec<Message>, String> {
+        Ok(self.topics.lock().unwrap()
+            .values()
+            .flat_map(|pubs| pubs.values())
+            .flat_map(|msgs| msgs.values())
+            .cloned()
+            .collect())
+    }
+
+    fn subjects_count(&self, topic: &Topic) -> Result<usize, String> {
+        Ok(self.topics.lock().unwrap()
+            .get(topic)
+            .map_or(0, |pubs| pubs.values().map(|msgs| msgs.len()).sum()))
+    }
+
+    fn configure_topic(&self, topic: Topic, config: TopicConfig) -> Result<(), String> {
+        self.topic_configs.lock().unwrap().insert(topic, config);
+        Ok(())
+    }
+
+    fn topic_config(&self, topic: &Topic) -> Result<Option<TopicConfig>, String> {
+        Ok(self.topic_configs.lock().unwrap().get(topic).cloned())
+    }
+
+    fn topic_configs(&self) -> Result<HashMap<Topic, TopicConfig>, String> {
+        Ok(self.topic_configs.lock().unwrap().clone())
+    }
+
+    fn remove_topic(&self, topic: &Topic) -> Result<Vec<Message>, String> {
+        self.topic_configs.lock().unwrap().remove(topic);
+        Ok(self.topics.lock().unwrap()
+            .remove(topic)
+            .into_iter()
+            .flat_map(|pubs| pubs.into_iter())
+            .flat_map(|(_, msgs)| msgs.into_iter())
+            .map(|(_, m)| m)
+            .collect())
+    }
+
+    fn auto_create_topics(&self) -> Result<bool, String> {
+        Ok(*self.auto_create_topics.lock().unwrap())
+    }
+
+    fn set_auto_create_topics(&self, enabled: bool) -> Result<(), String> {
+        *self.auto_create_topics.lock().unwrap() = enabled;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), String> {
+        self.publishers.lock().unwrap().clear();
+        self.pending_subscribers.lock().unwrap().clear();
+        self.subscribers.lock().unwrap().clear();
+        self.topics.lock().unwrap().clear();
+        self.topic_configs.lock().unwrap().clear();
+        *self.auto_create_topics.lock().unwrap() = true;
+        Ok(())
+    }
+}
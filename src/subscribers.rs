@@ -1,70 +1,162 @@
-extern crate rocket;
-
-use rocket::http::Header;
-use rocket::http::Method;
-use rocket::http::Status;
-use rocket::local::Client;
+use base64;
+use http::HttpClient;
+use hyper::method::Method;
 use std::collections::HashMap;
-use super::headers::format_headers;
+use std::net::IpAddr;
+use std::time::Duration;
+use super::headers::{AUTHORIZATION_HEADER, CONTENT_ENCODING_HEADER, CONTENT_TYPE_HEADER, HOST_HEADER, format_headers};
 use downcast_rs::Downcast;
-use models::Message;
+use models::{Batch, BatchEntry, Message};
+use serde_json;
+use url::Url;
+
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
 
+// Subscribers - deliveries to subscriber callbacks. The address is the one the callback host passed
+// the callback policy with, when set the callback is called there instead of resolving the host
+// again. The API key is sent to callbacks of remote hubs having authentication enabled
 pub trait Subscribers: Downcast {
-    fn publish_message(&self, callback: &String, msg: &Message) -> Result<&str, CodeReason>;
+    fn publish_message(&self, callback: &String, address: Option<&IpAddr>, api_key: Option<&String>,
+                       msg: &Message) -> Result<&str, CodeReason>;
     //TODO: return type must be Future of Result
 
-    fn remove_message(&self, callback: &String, msg: &Message) -> Result<&str, CodeReason>;
+    fn remove_message(&self, callback: &String, address: Option<&IpAddr>, api_key: Option<&String>,
+                      msg: &Message) -> Result<&str, CodeReason>;
+    //TODO: return type must be Future of Result
+
+    // deliver_batch - delivers publications and removals of one topic in a single call
+    fn deliver_batch(&self, callback: &String, address: Option<&IpAddr>, api_key: Option<&String>,
+                     batch: &Batch) -> Result<&str, CodeReason>;
     //TODO: return type must be Future of Result
 }
 
 impl_downcast!(Subscribers);
 
+// SubscriberService - delivers over HTTP, to subscribers as well as to bridge callbacks of remote
+// hubs
 pub struct SubscriberService {
-    client: Client,
+    client: HttpClient,
 }
 
 pub type CodeReason<'a> = (u16, &'a str);
 
+// Envelope - JSON body of a batched delivery, see README for the format
+#[derive(Serialize)]
+struct Envelope<'a> {
+    topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction: Option<String>,
+    entries: Vec<EnvelopeEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct EnvelopeEntry<'a> {
+    op: &'static str,
+    publisher: String,
+    subject: &'a str,
+    headers: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+}
+
+impl<'a> From<&'a BatchEntry> for EnvelopeEntry<'a> {
+    fn from(e: &'a BatchEntry) -> Self {
+        let (op, m, body) = match *e {
+            BatchEntry::Publish(ref m) => ("publish", m, Some(base64::encode(&m.body))),
+            BatchEntry::Remove(ref m) => ("remove", m, None),
+        };
+        EnvelopeEntry {
+            op,
+            publisher: format!("{}", m.publisher.hyphenated()),
+            subject: &m.subject,
+            headers: format_headers(&m.headers),
+            content_type: body.as_ref().and(m.content_type.as_ref()),
+            content_encoding: body.as_ref().and(m.content_encoding.as_ref()),
+            body,
+        }
+    }
+}
+
 impl Subscribers for SubscriberService {
-    fn publish_message(&self, callback: &String, msg: &Message) -> Result<&str, CodeReason> {
+    fn publish_message(&self, callback: &String, address: Option<&IpAddr>, api_key: Option<&String>,
+                       msg: &Message) -> Result<&str, CodeReason> {
         let url = format!("{}receive/{}/{}/{}", callback, msg.topic, msg.publisher, msg.subject);
-        self.call(Method::Post, url, &msg.headers, Some(&msg.body))
+        let mut headers = vec![];
+        if let Some(ref ct) = msg.content_type {
+            headers.push((CONTENT_TYPE_HEADER.to_string(), ct.clone()));
+        }
+        if let Some(ref ce) = msg.content_encoding {
+            headers.push((CONTENT_ENCODING_HEADER.to_string(), ce.clone()));
+        }
+        self.call(Method::Post, &url, address, headers, api_key, &msg.headers, &msg.body)
     }
 
-    fn remove_message(&self, callback: &String, msg: &Message) -> Result<&str, CodeReason> {
+    fn remove_message(&self, callback: &String, address: Option<&IpAddr>, api_key: Option<&String>,
+                      msg: &Message) -> Result<&str, CodeReason> {
         let url = format!("{}remove/{}/{}/{}", callback, msg.topic, msg.publisher, msg.subject);
-        self.call(Method::Delete, url, &msg.headers, None)
+        self.call(Method::Delete, &url, address, vec![], api_key, &msg.headers, &[])
+    }
+
+    fn deliver_batch(&self, callback: &String, address: Option<&IpAddr>, api_key: Option<&String>,
+                     batch: &Batch) -> Result<&str, CodeReason> {
+        let url = format!("{}batch/{}", callback, batch.topic);
+        let envelope = Envelope {
+            topic: &batch.topic,
+            transaction: batch.transaction.map(|id| format!("{}", id.hyphenated())),
+            entries: batch.entries.iter().map(EnvelopeEntry::from).collect(),
+        };
+        let body = serde_json::to_vec(&envelope).map_err(|_| (500u16, "failed to serialize batch"))?;
+
+        let headers = vec![(CONTENT_TYPE_HEADER.to_string(), "application/json".to_string())];
+        self.call(Method::Post, &url, address, headers, api_key, &HashMap::new(), &body)
     }
 }
 
 impl SubscriberService {
     pub fn new() -> Self {
         SubscriberService {
-            client: Client::new(rocket::ignite()).expect("valid rocket")
+            client: HttpClient::new(Duration::from_secs(DELIVERY_TIMEOUT_SECS))
         }
     }
 
-    fn call(&self, method: Method, url: String, headers: &HashMap<String, String>,
-            body: Option<&String>) -> Result<&str, CodeReason> {
-        let mut req = match method {
-            Method::Post => Ok(self.client.post(url)),
-            Method::Delete => Ok(self.client.delete(url)),
-            _ => Err((405u16, ("unsupported HTTP method")))
-        }?;
-
-        let hrs = format_headers(&headers);
-        for (k, v) in hrs {
-            req.add_header(Header::new(k, v));
+    // call - a callback that cannot be reached counts as 502
+    fn call(&self, method: Method, url: &str, address: Option<&IpAddr>, mut headers: Vec<(String, String)>,
+            api_key: Option<&String>, msg_headers: &HashMap<String, String>, body: &[u8]) -> Result<&str, CodeReason> {
+        let url = match address {
+            Some(ip) => {
+                let (pinned, host) = pin(url, ip).map_err(|_| (400u16, "invalid callback"))?;
+                headers.push((HOST_HEADER.to_string(), host));
+                pinned
+            }
+            None => url.to_string()
+        };
+        headers.extend(format_headers(msg_headers));
+        if let Some(key) = api_key {
+            headers.push((AUTHORIZATION_HEADER.to_string(), format!("Bearer {}", key)));
         }
 
-        let res = match body {
-            Some(b) => req.body(b),
-            None => req
-        }.dispatch();
-
-        match res.status() {
-            Status::Ok => Ok(res.status().reason),
-            s => Err((s.code, s.reason))
+        match self.client.request(method, &url, headers, body) {
+            Ok((status, _)) if status.is_success() => Ok(status.canonical_reason().unwrap_or("OK")),
+            Ok((status, _)) => Err((status.to_u16(), status.canonical_reason().unwrap_or("unknown status"))),
+            Err(e) => {
+                println!("{}", e);
+                Err((502, "callback unreachable"))
+            }
         }
     }
-}
\ No newline at end of file
+}
+// pin - the URL with its host replaced by the address, and the Host header naming the original host
+fn pin(url: &str, ip: &IpAddr) -> Result<(String, String), ()> {
+    let mut url = Url::parse(url).map_err(|_| ())?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(())
+    };
+    url.set_ip_host(*ip)?;
+    Ok((url.into_string(), host))
+}
@@ -0,0 +1,21 @@
+use rocket::Config;
+use std::time::Duration;
+
+const DEFAULT_TICK_INTERVAL_MS: i64 = 100;
+
+// TickerConfig - how often the background ticker of the server runs, set by tick_interval_ms in
+// Rocket.toml extras or ROCKET_TICK_INTERVAL_MS
+#[derive(Debug, Clone, Copy)]
+pub struct TickerConfig {
+    pub interval: Duration,
+}
+
+impl TickerConfig {
+    pub fn from_config(config: &Config) -> Result<TickerConfig, String> {
+        let ms = config.get_int("tick_interval_ms").unwrap_or(DEFAULT_TICK_INTERVAL_MS);
+        if ms <= 0 {
+            return Err(format!("tick_interval_ms must be greater than zero, got: {}", ms));
+        }
+        Ok(TickerConfig { interval: Duration::from_millis(ms as u64) })
+    }
+}
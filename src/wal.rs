@@ -0,0 +1,184 @@
+use chrono::Duration;
+use chrono::prelude::*;
+use models::{Message, Subject, Subscriber, Topic, TopicConfig};
+use rocket::Config;
+use scheduler::ScheduledMessage;
+use serde_json::{self, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const DEFAULT_COMPACT_AFTER: usize = 10000;
+
+// WalEntry - a single mutation of the server state, one JSON object per line in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WalEntry {
+    AddPublisher {
+        id: Uuid,
+        // token_hash - see auth::hash_token, None for publishers registered without a token
+        #[serde(default)]
+        token_hash: Option<String>,
+    },
+    // SetPublisherToken - a token issued to a publisher registered without a token
+    SetPublisherToken { id: Uuid, token_hash: Option<String> },
+    RemovePublisher { id: Uuid },
+    AddPendingSubscriber { subscriber: Subscriber },
+    ActivateSubscriber { id: Uuid },
+    RemoveSubscriber { id: Uuid },
+    PutMessage { message: Message },
+    RemoveMessage { topic: Topic, publisher: Uuid, subject: Subject },
+    ConfigureTopic { topic: Topic, config: TopicConfig },
+    RemoveTopic { topic: Topic },
+    AutoCreateTopics { enabled: bool },
+    // RegisterSchema - adds the next version of the topic schema and activates it
+    RegisterSchema { topic: Topic, schema: Value },
+    ActivateSchema { topic: Topic, version: u32 },
+    DeactivateSchema { topic: Topic },
+    ScheduleMessage { scheduled: ScheduledMessage },
+    // Unschedule - a scheduled message was published or cancelled
+    Unschedule { id: Uuid },
+}
+
+impl WalEntry {
+    pub fn removal(m: &Message) -> WalEntry {
+        WalEntry::RemoveMessage { topic: m.topic.clone(), publisher: m.publisher, subject: m.subject.clone() }
+    }
+}
+
+// FsyncPolicy - when appended entries are flushed to disk: after every entry, at most every
+// given number of milliseconds, or whenever the OS decides
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    Every(u64),
+    Never,
+}
+
+// WalConfig - the write-ahead log is enabled by setting wal_path in Rocket.toml extras or
+// ROCKET_WAL_PATH, wal_fsync is one of always, never or an interval in milliseconds
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    pub path: PathBuf,
+    pub fsync: FsyncPolicy,
+    // compact_after - number of appended entries after which the log is rewritten from state
+    pub compact_after: usize,
+}
+
+impl WalConfig {
+    pub fn from_config(config: &Config) -> Result<Option<WalConfig>, String> {
+        let path = match config.get_str("wal_path") {
+            Ok(p) => PathBuf::from(p),
+            Err(_) => return Ok(None)
+        };
+        let fsync = match config.get_str("wal_fsync") {
+            Ok("always") | Err(_) => FsyncPolicy::Always,
+            Ok("never") => FsyncPolicy::Never,
+            Ok(ms) => ms.parse::<u64>()
+                .map(FsyncPolicy::Every)
+                .map_err(|_| format!("wal_fsync must be always, never or milliseconds, got: {}", ms))?
+        };
+        let compact_after = config.get_int("wal_compact_after")
+            .map(|v| v as usize)
+            .unwrap_or(DEFAULT_COMPACT_AFTER);
+        Ok(Some(WalConfig { path, fsync, compact_after }))
+    }
+}
+
+pub struct Wal {
+    config: WalConfig,
+    file: File,
+    // appended - entries appended since the last compaction
+    appended: usize,
+    unsynced: bool,
+    last_sync: DateTime<Local>,
+}
+
+impl Wal {
+    // open - reads entries of an existing log and opens it for appending. A torn last line left by
+    // a crash is skipped, the following compaction drops it from the file
+    pub fn open(config: WalConfig, now: DateTime<Local>) -> io::Result<(Wal, Vec<WalEntry>)> {
+        let mut entries = vec![];
+        if config.path.exists() {
+            for (n, line) in BufReader::new(File::open(&config.path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<WalEntry>(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => {
+                        println!("stopping write-ahead log replay at line {}: {}", n + 1, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let wal = Wal { config, file, appended: entries.len(), unsynced: false, last_sync: now };
+        Ok((wal, entries))
+    }
+
+    // append - writes all entries at once, a failed append leaves at most a torn last line
+    pub fn append(&mut self, entries: &[WalEntry], now: DateTime<Local>) -> io::Result<()> {
+        let mut lines = vec![];
+        for entry in entries {
+            lines.extend(serde_json::to_vec(entry)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+            lines.push(b'\n');
+        }
+        self.file.write_all(&lines)?;
+        self.appended += entries.len();
+        self.unsynced = true;
+
+        if self.config.fsync == FsyncPolicy::Always {
+            self.sync(now)?;
+        }
+        Ok(())
+    }
+
+    // sync_due - fsyncs the log if the interval of FsyncPolicy::Every has passed
+    pub fn sync_due(&mut self, now: DateTime<Local>) -> io::Result<()> {
+        match self.config.fsync {
+            FsyncPolicy::Every(ms) if self.unsynced && now >= self.last_sync + Duration::milliseconds(ms as i64) =>
+                self.sync(now),
+            _ => Ok(())
+        }
+    }
+
+    fn sync(&mut self, now: DateTime<Local>) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = false;
+        self.last_sync = now;
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.appended >= self.config.compact_after
+    }
+
+    // compact - replaces the log with the given entries describing the current state. The new log
+    // is written aside and renamed over the old one, so a crash leaves one of them intact
+    pub fn compact(&mut self, entries: &[WalEntry], now: DateTime<Local>) -> io::Result<()> {
+        let tmp = self.config.path.with_extension("compacting");
+        {
+            let mut out = File::create(&tmp)?;
+            for entry in entries {
+                let mut line = serde_json::to_vec(entry)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                line.push(b'\n');
+                out.write_all(&line)?;
+            }
+            out.sync_all()?;
+        }
+        fs::rename(&tmp, &self.config.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.config.path)?;
+        self.appended = 0;
+        self.unsynced = false;
+        self.last_sync = now;
+        Ok(())
+    }
+}
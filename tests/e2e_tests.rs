@@ -1,16 +1,47 @@
+extern crate chrono;
+extern crate hyper;
+extern crate jsonwebtoken;
 extern crate pub_sub_server;
 extern crate rocket;
+#[macro_use]
+extern crate serde_json;
 extern crate uuid;
 
+use chrono::Duration;
+use chrono::prelude::*;
+use jsonwebtoken::Algorithm;
+use pub_sub_server::clock::Clock;
+
 use pub_sub_server::subscribers::CodeReason;
-use pub_sub_server::subscribers::Subscribers;
-use pub_sub_server::mount_routes;
+use pub_sub_server::subscribers::{SubscriberService, Subscribers};
+use pub_sub_server::{mount_routes, mount_routes_with_config};
 use pub_sub_server::server::PubSubServer;
+use rocket::config::{Config, Environment, Table, Value};
+use rocket::http::ContentType;
 use rocket::http::Header;
 use rocket::http::Status;
-use rocket::local::Client;
-use std::sync::RwLock;
-use pub_sub_server::models::Message;
+use rocket::local::{Client, LocalRequest};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time;
+use pub_sub_server::models::{Batch, BatchEntry, Message, Subscriber};
+use pub_sub_server::acl::{Acl, AclRule, Action};
+use pub_sub_server::audit::AuditConfig;
+use pub_sub_server::auth::ApiKeys;
+use pub_sub_server::callbacks::CallbackPolicy;
+use pub_sub_server::cluster::{ClusterConfig, Node, Nodes};
+use pub_sub_server::federation::{Bridge, BridgeDirection, FederationConfig, Hubs};
+use pub_sub_server::jwt::{JwtConfig, JwtKey, JwtKeys};
+use pub_sub_server::replication::{self, Replicas, ReplicationConfig, Role};
+use pub_sub_server::sled_storage::SledStorage;
+use pub_sub_server::snapshot::{Snapshot, SnapshotFormat};
+use pub_sub_server::storage::{MemoryStorage, MessageOp, Storage};
+use pub_sub_server::wal::{FsyncPolicy, WalConfig, WalEntry};
 
 const TOPIC_NAME: &str = "mytopic";
 const SUBJECT_NAME: &str = "mysubject";
@@ -24,7 +55,7 @@ fn subscribe() {
     //when
     let mut res = client
         .get("info/subscribe/topic1")
-        .header(Header::new("Location", "my_location"))
+        .header(Header::new("Location", "http://subscriber1:9000"))
         .dispatch();
 
     //then
@@ -38,11 +69,142 @@ fn subscribe() {
     assert_eq!(id.len(), 36);
 }
 
+#[test]
+fn unsafe_callbacks_are_rejected() {
+    //given
+    let client = new_client();
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_callbacks(CallbackPolicy::default());
+    }
+    let callbacks = vec![
+        "my_location",
+        "http://unresolvable.invalid:9000",
+        "ftp://subscriber1:9000",
+        "http://127.0.0.1:9000",
+        "http://localhost:9000",
+        "http://169.254.169.254/latest/meta-data",
+        "http://10.0.0.8:9000",
+        "http://[::1]:9000",
+        "http://[::ffff:192.168.0.1]:9000",
+    ];
+
+    for callback in callbacks {
+        //when
+        let mut res = client
+            .get("info/subscribe/topic1")
+            .header(Header::new("Location", callback))
+            .dispatch();
+
+        //then
+        assert_eq!(res.status(), Status::BadRequest, "{} was accepted", callback);
+        assert!(res.body_string().unwrap().contains(r#""error":"invalid_callback""#));
+    }
+}
+
+#[test]
+fn callbacks_are_checked_again_on_delivery() {
+    //given
+    let client = new_client();
+    let publisher = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let token = create_publisher(&client, publisher);
+    let id = subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    {
+        // subscriber1 no longer trusted, it doesn't resolve as if it was rebound
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_callbacks(CallbackPolicy::default());
+    }
+
+    //when
+    publish_message(&client, publisher, &token);
+
+    //then
+    assert_eq!(get_mock(&client).pub_vec.read().unwrap().len(), 0);
+    let res = client
+        .put(format!("info/subscribe/{}", id))
+        .dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn deliveries_connect_to_the_checked_address() {
+    //given
+    let (port, requests) = respond_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let callback = format!("http://subscriber.invalid:{}/", port);
+    let address: IpAddr = "127.0.0.1".parse().unwrap();
+
+    //when
+    let res = SubscriberService::new().publish_message(&callback, Some(&address), None, &test_message());
+
+    //then
+    assert_eq!(res, Ok("OK"));
+    let request = requests.recv().unwrap();
+    assert!(request.contains(&format!("Host: subscriber.invalid:{}\r\n", port)), "{}", request);
+}
+
+#[test]
+fn callback_redirects_are_not_followed() {
+    //given
+    let (port, _requests) = respond_once("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:1/\r\n\
+    Content-Length: 0\r\n\r\n");
+    let callback = format!("http://127.0.0.1:{}/", port);
+
+    //when
+    let res = SubscriberService::new().publish_message(&callback, None, None, &test_message());
+
+    //then
+    assert_eq!(res, Err((302, "Found")));
+}
+
+// respond_once - port of a server answering the first request with the response, the request is
+// sent to the receiver
+fn respond_once(response: &'static str) -> (u16, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        // reads the headers and the body, closing with unread data would reset the connection
+        while !request_read(&request) {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(response.as_bytes()).unwrap();
+        sender.send(String::from_utf8_lossy(&request).into_owned()).unwrap();
+    });
+    (port, receiver)
+}
+
+fn request_read(request: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(request);
+    match text.find("\r\n\r\n") {
+        Some(end) => {
+            let length = text[..end].lines()
+                .filter(|line| line.to_lowercase().starts_with("content-length:"))
+                .filter_map(|line| line["content-length:".len()..].trim().parse::<usize>().ok())
+                .next()
+                .unwrap_or(0);
+            text.len() >= end + 4 + length
+        }
+        None => false
+    }
+}
+
+fn test_message() -> Message {
+    Message::new(uuid::Uuid::new_v4(), TOPIC_NAME.to_string(), "subject1".to_string(), HashMap::new(),
+                 b"body".to_vec())
+}
+
 #[test]
 fn unsubscribe() {
     //given
     let client = new_client();
-    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let id = subscribe_and_touch(&client, "topic1", "http://subscriber1:9000");
 
     //when
     let mut res = client
@@ -59,6 +221,23 @@ fn unsubscribe() {
     assert_eq!(returned_id, id);
 }
 
+#[test]
+fn unsubscribe_unknown() {
+    //given
+    let client = new_client();
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+
+    //when
+    let mut res = client
+        .delete(format!("info/subscribe/{}", id))
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::NotFound);
+    assert_eq!(res.content_type(), Some(ContentType::JSON));
+    assert!(res.body_string().unwrap().contains(r#""error":"unknown_subscriber""#));
+}
+
 #[test]
 fn touch_subscriber() {
     //given
@@ -66,7 +245,7 @@ fn touch_subscriber() {
     //when
     let mut subscribed = client
         .get("info/subscribe/topic1")
-        .header(Header::new("Location", "my_location"))
+        .header(Header::new("Location", "http://subscriber1:9000"))
         .dispatch();
     let id = subscribed.body_string().unwrap();
 
@@ -91,7 +270,7 @@ fn touch_subscriber() {
         .head(format!("info/subscribe/{}", id))
         .dispatch();
     //then
-    assert_eq!(touched.status(), Status::Ok);
+    assert_eq!(touched.status(), Status::NotFound);
 }
 
 #[test]
@@ -103,13 +282,22 @@ fn add_publisher() {
     create_publisher(&client, id);
 }
 
-fn create_publisher(client: &Client, id: &str) {
-    let mut added = client
-        .get(format!("info/publish/{}", id))
+fn create_publisher(client: &Client, id: &str) -> String {
+    create_publisher_with(client, id, None)
+}
+
+fn create_publisher_with(client: &Client, id: &str, key: Option<&str>) -> String {
+    let mut added = authorized(client.get(format!("info/publish/{}", id)), key)
         .dispatch();
+    let token = added.headers().get_one("Publisher-Token").unwrap().to_string();
     let res_id = added.body_string().unwrap();
     //then
-    assert_eq!(id, res_id)
+    assert_eq!(id, res_id);
+    token
+}
+
+fn publisher_token(token: &str) -> Header<'static> {
+    Header::new("Publisher-Token", token.to_string())
 }
 
 #[test]
@@ -122,12 +310,14 @@ fn touch_remove_touch_publisher() {
     let mut added = client
         .get(format!("info/publish/{}", id)) // add_publisher
         .dispatch();
+    let token = added.headers().get_one("Publisher-Token").unwrap().to_string();
     let res_id = added.body_string().unwrap();
     //then
     assert_eq!(id, res_id);
 
     let mut touched = client
         .head(format!("info/publish/{}", id))
+        .header(publisher_token(&token))
         .dispatch();
     let body = touched.body_string();
 
@@ -135,7 +325,7 @@ fn touch_remove_touch_publisher() {
     assert!(body.is_none());
 
     //when
-    remove_publisher(&client, id);
+    remove_publisher(&client, id, &token);
 
     let mut touched = client
         .head(format!("info/publish/{}", id))
@@ -149,12 +339,29 @@ fn touch_remove_touch_publisher() {
     assert!(body.is_some());
 
     let text = body.unwrap();
-    assert!(text.contains("Touching unknown publisher "));
+    assert!(text.contains(r#""error":"unknown_publisher""#));
+}
+
+#[test]
+fn add_existing_publisher() {
+    //given
+    let client = new_client();
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    create_publisher(&client, id);
+
+    //when
+    let added = client
+        .get(format!("info/publish/{}", id))
+        .dispatch();
+
+    //then
+    assert_eq!(added.status(), Status::Conflict);
 }
 
-fn remove_publisher(client: &Client, id: &str) {
+fn remove_publisher(client: &Client, id: &str, token: &str) {
     let mut removed = client
         .delete(format!("info/publish/{}", id))
+        .header(publisher_token(token))
         .dispatch();
     let body = removed.body_string();
 
@@ -167,13 +374,85 @@ fn publish() {
     //given
     let client = new_client();
     let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let token = create_publisher(&client, id);
 
     //when
-    publish_message(&client, id);
+    publish_message(&client, id, &token);
 }
 
-fn publish_message(client: &Client, id: &str) {
-    let res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+#[test]
+fn publish_unknown_publisher() {
+    //given
+    let client = new_client();
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+
+    //when
+    let mut res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::NotFound);
+    assert!(res.body_string().unwrap().contains(r#""error":"unknown_publisher""#));
+}
+
+#[test]
+fn publisher_calls_require_its_token() {
+    //given
+    let client = new_client();
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let token = create_publisher(&client, id);
+
+    //when
+    let mut missing = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .body(MSG_BODY)
+        .dispatch();
+    let wrong = client.delete(format!("info/publish/{}", id))
+        .header(publisher_token("not-the-token"))
+        .dispatch();
+    let touched = client.head(format!("info/publish/{}", id))
+        .header(publisher_token(&token))
+        .dispatch();
+
+    //then
+    assert_eq!(missing.status(), Status::Forbidden);
+    assert!(missing.body_string().unwrap().contains(r#""error":"invalid_publisher_token""#));
+    assert_eq!(wrong.status(), Status::Forbidden);
+    assert_eq!(touched.status(), Status::Ok);
+    let mock = get_mock(&client);
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 0);
+}
+
+#[test]
+fn publishers_without_a_token_are_rejected_until_they_register_again() {
+    //given
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let client = new_client();
+    let mut snapshot = Snapshot::new(Local::now());
+    snapshot.publishers = vec![uuid::Uuid::parse_str(id).unwrap()];
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.restore(snapshot).unwrap();
+    }
+
+    //when
+    let rejected = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .body(MSG_BODY)
+        .dispatch();
+    let token = create_publisher(&client, id);
+
+    //then
+    assert_eq!(rejected.status(), Status::Unauthorized);
+    publish_message(&client, id, &token);
+}
+
+fn publish_message(client: &Client, id: &str, token: &str) {
+    publish_message_with(client, id, token, None)
+}
+
+fn publish_message_with(client: &Client, id: &str, token: &str, key: Option<&str>) {
+    let res = authorized(client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME)), key)
+        .header(publisher_token(token))
         .body(MSG_BODY)
         .dispatch();
     let code = res.status();
@@ -186,15 +465,42 @@ fn remove() {
     //given
     let client = new_client();
     let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let token = create_publisher(&client, id);
+    publish_message(&client, id, &token);
 
     //when
     let res = client
         .delete(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .header(publisher_token(&token))
         .dispatch();
     let code = res.status();
 
     //then
     assert_eq!(200, code.code);
+
+    //when
+    let res = client
+        .delete(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn remove_unknown_publisher() {
+    //given
+    let client = new_client();
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+
+    //when
+    let res = client
+        .delete(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::NotFound);
 }
 
 #[test]
@@ -203,8 +509,8 @@ fn publish_subscriber_scenario() {
     let publisher_id = "8dbdd47c-cb61-44b2-8919-bd44a87fcd48";
     let client = new_client();
     //when
-    create_publisher(&client, publisher_id);
-    publish_message(&client, publisher_id);
+    let token = create_publisher(&client, publisher_id);
+    publish_message(&client, publisher_id, &token);
     let location = "http://subscriber1:9000";
 
     let mut subscribed = client
@@ -228,10 +534,10 @@ fn publish_subscriber_scenario() {
     assert_eq!(&location, callback);
     assert_eq!(TOPIC_NAME, msg.topic);
     assert_eq!(SUBJECT_NAME, msg.subject);
-    assert_eq!(MSG_BODY, msg.body);
+    assert_eq!(MSG_BODY.as_bytes(), &msg.body[..]);
 
     //when
-    remove_publisher(&client, &publisher_id);
+    remove_publisher(&client, publisher_id, &token);
     //then
     let removed = mock.remove_vec.read().unwrap();
     assert_eq!(removed.len(), 1);
@@ -240,39 +546,2061 @@ fn publish_subscriber_scenario() {
     assert_eq!(&location, callback);
     assert_eq!(TOPIC_NAME, msg.topic);
     assert_eq!(SUBJECT_NAME, msg.subject);
-    assert_eq!("", msg.body);
+    assert!(msg.body.is_empty());
 }
 
-fn get_mock(client: &Client) -> &MockSubscribers {
+#[test]
+fn message_ttl_expiry() {
+    //given
+    let publisher_id = "0a3b1b4e-6c6e-4c1f-9d0e-6f4b1f3d2a11";
+    let clock = MockClock::new();
+    let client = new_client_with_clock(clock.clone());
+    let token = create_publisher(&client, publisher_id);
+    let location = "http://subscriber1:9000";
+    subscribe_and_touch(&client, TOPIC_NAME, location);
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(Header::new("TTL", "10"))
+        .body(MSG_BODY)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    clock.advance(11);
     let server: &PubSubServer = client.rocket().state().unwrap();
-    let mock = server.subs_service.downcast_ref::<MockSubscribers>();
-    assert_eq!(mock.is_some(), true, "Failed to downcast");
-    mock.unwrap()
-}
+    server.tick();
 
-fn new_client() -> Client {
-    let server = PubSubServer::with_service(Box::new(
-        MockSubscribers { pub_vec: RwLock::new(Vec::new()), remove_vec: RwLock::new(Vec::new()) })
-    );
-    let rocket = mount_routes(server);
-    Client::new(rocket).expect("valid rocket instance")
+    //then
+    let mock = get_mock(&client);
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 1);
+    {
+        let removed = mock.remove_vec.read().unwrap();
+        assert_eq!(removed.len(), 1);
+        let (callback, msg) = &removed[0];
+        assert_eq!(&location, callback);
+        assert_eq!(SUBJECT_NAME, msg.subject);
+    }
+
+    //when
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber2:9000");
+    //then
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 1);
 }
 
-struct MockSubscribers {
-    pub_vec: RwLock<Vec<(String, Message)>>,
-    remove_vec: RwLock<Vec<(String, Message)>>,
+#[test]
+fn invalid_ttl() {
+    //given
+    let client = new_client();
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, id, SUBJECT_NAME))
+        .header(Header::new("TTL", "soon"))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::BadRequest);
 }
 
-impl Subscribers for MockSubscribers {
-    fn publish_message(&self, callback: &String, msg: &Message) -> Result<&str, CodeReason> {
-        println!("test publish_message ==== ");
-        self.pub_vec.write().unwrap().push((callback.clone(), msg.clone()));
-        Ok("ok")
-    }
+#[test]
+fn scheduled_publish() {
+    //given
+    let publisher_id = "5b8a3c9e-2f1d-4e7a-8c6b-9d0e1f2a3b4c";
+    let clock = MockClock::new();
+    let client = new_client_with_clock(clock.clone());
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
 
-    fn remove_message(&self, callback: &String, msg: &Message) -> Result<&str, CodeReason> {
-        println!("test remove_message ==== ");
-        self.remove_vec.write().unwrap().push((callback.clone(), msg.clone()));
-        Ok("ok")
+    //when
+    let mut res = client.put(format!("info/schedule/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(Header::new("Delay", "60"))
+        .body(MSG_BODY)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let scheduled_id = res.body_string().unwrap();
+
+    //then
+    let mut listed = client.get(format!("info/schedule/{}", publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    assert!(listed.body_string().unwrap().contains(&scheduled_id));
+    let mock = get_mock(&client);
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 0);
+
+    //when
+    clock.advance(61);
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    server.tick();
+
+    //then
+    {
+        let published = mock.pub_vec.read().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(SUBJECT_NAME, published[0].1.subject);
     }
-}
\ No newline at end of file
+    let mut listed = client.get(format!("info/schedule/{}", publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    assert_eq!("[]", listed.body_string().unwrap());
+}
+
+#[test]
+fn cancel_scheduled_publish() {
+    //given
+    let publisher_id = "5b8a3c9e-2f1d-4e7a-8c6b-9d0e1f2a3b4c";
+    let clock = MockClock::new();
+    let client = new_client_with_clock(clock.clone());
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    let mut res = client.put(format!("info/schedule/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(Header::new("Delay", "60"))
+        .body(MSG_BODY)
+        .dispatch();
+    let scheduled_id = res.body_string().unwrap();
+
+    //when
+    let cancelled = client.delete(format!("info/schedule/{}/{}", publisher_id, scheduled_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    assert_eq!(cancelled.status(), Status::Ok);
+    let cancelled = client.delete(format!("info/schedule/{}/{}", publisher_id, scheduled_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    assert_eq!(cancelled.status(), Status::NotFound);
+    clock.advance(61);
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    server.tick();
+
+    //then
+    assert_eq!(get_mock(&client).pub_vec.read().unwrap().len(), 0);
+}
+
+#[test]
+fn scheduled_message_is_admitted_again_when_due() {
+    //given
+    let publisher_id = "5b8a3c9e-2f1d-4e7a-8c6b-9d0e1f2a3b4c";
+    let clock = MockClock::new();
+    let client = new_client_with_clock(clock.clone());
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    let res = client.put(format!("info/schedule/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(Header::new("Delay", "60"))
+        .body(MSG_BODY)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    //when
+    client.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"type": "object"}"#)
+        .dispatch();
+    clock.advance(61);
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    server.tick();
+
+    //then
+    assert_eq!(get_mock(&client).pub_vec.read().unwrap().len(), 0);
+    let mut listed = client.get(format!("info/schedule/{}", publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    assert_eq!("[]", listed.body_string().unwrap());
+}
+
+#[test]
+fn transient_message_is_not_retained() {
+    //given
+    let publisher_id = "1c2d3e4f-5a6b-4c7d-8e9f-0a1b2c3d4e5f";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(Header::new("Retain", "false"))
+        .body(MSG_BODY)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber2:9000");
+
+    //then
+    let published = get_mock(&client).pub_vec.read().unwrap();
+    assert_eq!(published.len(), 1);
+    assert_eq!("http://subscriber1:9000", published[0].0);
+}
+
+#[test]
+fn transient_topic_is_not_replayed() {
+    //given
+    let publisher_id = "1c2d3e4f-5a6b-4c7d-8e9f-0a1b2c3d4e5f";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    let res = client.put(format!("info/topic/{}/retention/transient", TOPIC_NAME)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    //when
+    publish_message(&client, publisher_id, &token);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+
+    //then
+    assert_eq!(get_mock(&client).pub_vec.read().unwrap().len(), 0);
+}
+
+#[test]
+fn topic_configuration_limits() {
+    //given
+    let publisher_id = "2d3e4f5a-6b7c-4d8e-9f0a-1b2c3d4e5f6a";
+    let other_id = "3e4f5a6b-7c8d-4e9f-8a1b-2c3d4e5f6a7b";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    let other_token = create_publisher(&client, other_id);
+
+    //when
+    let res = client.put(format!("info/topic/{}", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"max_message_size": 4, "allowed_publishers": ["{}"]}}"#, publisher_id))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    //then
+    let mut config = client.get(format!("info/topic/{}", TOPIC_NAME)).dispatch();
+    assert!(config.body_string().unwrap().contains(r#""max_message_size":4"#));
+
+    let too_big = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body("12345")
+        .dispatch();
+    assert_eq!(too_big.status(), Status::PayloadTooLarge);
+
+    let not_allowed = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, other_id, SUBJECT_NAME))
+        .header(publisher_token(&other_token))
+        .body("1234")
+        .dispatch();
+    assert_eq!(not_allowed.status(), Status::Forbidden);
+
+    let fits = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body("1234")
+        .dispatch();
+    assert_eq!(fits.status(), Status::Ok);
+}
+
+#[test]
+fn invalid_topic_configuration() {
+    //given
+    let client = new_client();
+
+    //when
+    let mut res = client.put(format!("info/topic/{}", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"max_subjects": 0}"#)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::UnprocessableEntity);
+    assert!(res.body_string().unwrap().contains(r#""error":"invalid_topic_config""#));
+}
+
+#[test]
+fn topic_auto_creation_disabled() {
+    //given
+    let publisher_id = "2d3e4f5a-6b7c-4d8e-9f0a-1b2c3d4e5f6a";
+    let client = new_client();
+    let key = admin_key(&client);
+    let token = create_publisher_with(&client, publisher_id, Some(&key));
+    let res = client.put("info/topics/auto_create/false").header(bearer(&key)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    //when
+    let published = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(bearer(&key))
+        .body(MSG_BODY)
+        .dispatch();
+    let subscribed = client.get(format!("info/subscribe/{}", TOPIC_NAME))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&key))
+        .dispatch();
+
+    //then
+    assert_eq!(published.status(), Status::NotFound);
+    assert_eq!(subscribed.status(), Status::NotFound);
+
+    //when
+    client.put(format!("info/topic/{}", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .header(bearer(&key))
+        .body("{}")
+        .dispatch();
+    //then
+    publish_message_with(&client, publisher_id, &token, Some(&key));
+    let removed = client.delete(format!("info/topic/{}", TOPIC_NAME)).header(bearer(&key)).dispatch();
+    assert_eq!(removed.status(), Status::Ok);
+    let config = client.get(format!("info/topic/{}", TOPIC_NAME)).header(bearer(&key)).dispatch();
+    assert_eq!(config.status(), Status::NotFound);
+}
+
+#[test]
+fn binary_payload_with_content_type() {
+    //given
+    let publisher_id = "4f5a6b7c-8d9e-4f0a-9b1c-2d3e4f5a6b7c";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    let payload: Vec<u8> = vec![0x89, 0x50, 0x4e, 0x47, 0x00, 0xff, 0xfe];
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(ContentType::PNG)
+        .header(Header::new("Content-Encoding", "identity"))
+        .body(&payload)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::Ok);
+    let published = get_mock(&client).pub_vec.read().unwrap();
+    assert_eq!(published.len(), 1);
+    let (_, msg) = &published[0];
+    assert_eq!(payload, msg.body);
+    assert_eq!(Some("image/png".to_string()), msg.content_type);
+    assert_eq!(Some("identity".to_string()), msg.content_encoding);
+}
+
+#[test]
+fn content_headers_are_not_forwarded_as_message_headers() {
+    //given
+    let publisher_id = "5a6b7c8d-9e0f-4a1b-8c2d-3e4f5a6b7c8d";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(Header::new("content-type", "text/csv"))
+        .header(Header::new("CONTENT-ENCODING", "gzip"))
+        .header(Header::new("k", "v"))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::Ok);
+    let published = get_mock(&client).pub_vec.read().unwrap();
+    let (_, msg) = &published[0];
+    assert_eq!(Some("text/csv".to_string()), msg.content_type);
+    assert_eq!(Some("gzip".to_string()), msg.content_encoding);
+    assert_eq!(vec!["k"], msg.headers.keys().collect::<Vec<&String>>());
+}
+
+#[test]
+fn oversized_body_is_rejected() {
+    //given
+    let publisher_id = "6b7c8d9e-0f1a-4b2c-8d3e-4f5a6b7c8d9e";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    let body = vec![b'a'; 1024 * 1024 + 1];
+
+    //when
+    let mut res = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(&body)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::PayloadTooLarge);
+    assert_eq!(res.content_type(), Some(ContentType::JSON));
+    assert!(res.body_string().unwrap().contains(r#""error":"payload_too_large""#));
+}
+
+#[test]
+fn invalid_names_and_headers_are_rejected() {
+    //given
+    let publisher_id = "6b7c8d9e-0f1a-4b2c-8d3e-4f5a6b7c8d9e";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+
+    //when
+    let mut bad_topic = client.put(format!("info/publish/{}/{}/{}", "bad!topic", publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(bad_topic.status(), Status::BadRequest);
+    assert!(bad_topic.body_string().unwrap().contains(r#""error":"invalid_name""#));
+
+    //when
+    let mut req = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(MSG_BODY);
+    for i in 0..100 {
+        req.add_header(Header::new(format!("x-header-{}", i), "value"));
+    }
+    let mut too_many = req.dispatch();
+
+    //then
+    assert_eq!(too_many.status(), Status::BadRequest);
+    assert!(too_many.body_string().unwrap().contains(r#""error":"too_many_headers""#));
+}
+
+#[test]
+fn bulk_publish_json() {
+    //given
+    let publisher_id = "7c8d9e0f-1a2b-4c3d-9e4f-5a6b7c8d9e0f";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}", TOPIC_NAME, publisher_id))
+        .header(publisher_token(&token))
+        .header(ContentType::JSON)
+        .body(r#"[
+            {"subject": "s1", "body": "one"},
+            {"subject": "s2", "headers": {"k": "v"}, "body_base64": "AAEC"}
+        ]"#)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::Ok);
+    let mock = get_mock(&client);
+    {
+        let batches = mock.batch_vec.read().unwrap();
+        assert_eq!(batches.len(), 1);
+        let (callback, batch) = &batches[0];
+        assert_eq!("http://subscriber1:9000", callback);
+        let msgs = published_messages(batch);
+        assert_eq!(2, msgs.len());
+        assert!(msgs.iter().any(|m| m.subject == "s1" && m.body == b"one".to_vec()));
+        assert!(msgs.iter().any(|m| m.subject == "s2" && m.body == vec![0u8, 1, 2]));
+    }
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 0);
+
+    //when
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber2:9000");
+    //then
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 2);
+}
+
+#[test]
+fn bulk_publish_ndjson_is_atomic() {
+    //given
+    let publisher_id = "7c8d9e0f-1a2b-4c3d-9e4f-5a6b7c8d9e0f";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    client.put(format!("info/topic/{}", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"max_subjects": 2}"#)
+        .dispatch();
+
+    //when
+    let res = client.put(format!("info/publish/{}/{}", TOPIC_NAME, publisher_id))
+        .header(publisher_token(&token))
+        .header(ContentType::new("application", "x-ndjson"))
+        .body("{\"subject\": \"s1\", \"body\": \"one\"}\n\
+               {\"subject\": \"s2\", \"body\": \"two\"}\n\
+               {\"subject\": \"s3\", \"body\": \"three\"}\n")
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::Conflict);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    assert_eq!(get_mock(&client).pub_vec.read().unwrap().len(), 0);
+}
+
+#[test]
+fn batched_delivery() {
+    //given
+    let publisher_id = "8d9e0f1a-2b3c-4d4e-8f5a-6b7c8d9e0f1a";
+    let clock = MockClock::new();
+    let client = new_client_with_clock(clock.clone());
+    let token = create_publisher(&client, publisher_id);
+    let mut subscribed = client.get(format!("info/subscribe/{}", TOPIC_NAME))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(Header::new("Batch-Size", "2"))
+        .header(Header::new("Batch-Window", "500"))
+        .dispatch();
+    let subscriber_id = subscribed.body_string().unwrap();
+    client.head(format!("info/subscribe/{}", subscriber_id)).dispatch();
+
+    //when
+    for subject in &["s1", "s2", "s3"] {
+        client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, subject))
+            .header(publisher_token(&token))
+            .body(MSG_BODY)
+            .dispatch();
+    }
+    client.delete(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s1"))
+        .header(publisher_token(&token))
+        .dispatch();
+
+    //then
+    let mock = get_mock(&client);
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 0);
+    {
+        let batches = mock.batch_vec.read().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(2, published_messages(&batches[0].1).len());
+    }
+
+    //when
+    clock.advance(1);
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    server.tick();
+
+    //then
+    let batches = mock.batch_vec.read().unwrap();
+    assert_eq!(batches.len(), 2);
+    let entries = &batches[1].1.entries;
+    assert_eq!(2, entries.len());
+    match (&entries[0], &entries[1]) {
+        (BatchEntry::Publish(p), BatchEntry::Remove(r)) => {
+            assert_eq!("s3", p.subject);
+            assert_eq!("s1", r.subject);
+        }
+        _ => panic!("unexpected batch entries: {:?}", entries)
+    }
+}
+
+#[test]
+fn transaction_is_delivered_as_one_batch() {
+    //given
+    let publisher_id = "9e0f1a2b-3c4d-4e5f-8a6b-7c8d9e0f1a2b";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    publish_message(&client, publisher_id, &token);
+
+    //when
+    let mut response = client.post(format!("info/transaction/{}", publisher_id))
+        .header(publisher_token(&token))
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"operations": [
+            {{"op": "publish", "topic": "{0}", "subject": "s2", "body": "two"}},
+            {{"op": "publish", "topic": "{0}", "subject": "s3", "body": "three"}},
+            {{"op": "remove", "topic": "{0}", "subject": "{1}"}}
+        ]}}"#, TOPIC_NAME, SUBJECT_NAME))
+        .dispatch();
+
+    //then
+    assert_eq!(response.status(), Status::Ok);
+    let transaction = response.body_string().unwrap();
+    let mock = get_mock(&client);
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 1);
+    let batches = mock.batch_vec.read().unwrap();
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0].1;
+    assert_eq!(Some(transaction), batch.transaction.map(|id| id.hyphenated().to_string()));
+    assert_eq!(3, batch.entries.len());
+    assert_eq!(2, published_messages(batch).len());
+}
+
+#[test]
+fn failed_transaction_applies_nothing() {
+    //given
+    let publisher_id = "0f1a2b3c-4d5e-4f6a-8b7c-8d9e0f1a2b3c";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+
+    //when
+    let response = client.post(format!("info/transaction/{}", publisher_id))
+        .header(publisher_token(&token))
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"operations": [
+            {{"op": "publish", "topic": "{0}", "subject": "s1", "body": "one"}},
+            {{"op": "remove", "topic": "{0}", "subject": "unknown"}}
+        ]}}"#, TOPIC_NAME))
+        .dispatch();
+
+    //then
+    assert_eq!(response.status(), Status::NotFound);
+    // the topic of the publish is auto-created only by a transaction which is applied
+    assert_eq!(client.get(format!("info/topic/{}", TOPIC_NAME)).dispatch().status(), Status::NotFound);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    let mock = get_mock(&client);
+    assert_eq!(mock.pub_vec.read().unwrap().len(), 0);
+    assert_eq!(mock.batch_vec.read().unwrap().len(), 0);
+}
+
+#[test]
+fn remove_subjects_by_prefix_and_glob() {
+    //given
+    let publisher_id = "1a2b3c4d-5e6f-4a7b-8c8d-9e0f1a2b3c4d";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    for subject in &["eu.eurusd", "eu.eurgbp", "us.usdjpy", "us.usdchf"] {
+        client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, subject))
+            .header(publisher_token(&token))
+            .body(MSG_BODY)
+            .dispatch();
+    }
+
+    //when
+    let mut by_prefix = client.delete(format!("info/publish/{}/{}?prefix=eu.", TOPIC_NAME, publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    let mut by_glob = client.delete(format!("info/publish/{}/{}?glob=us.*jp?", TOPIC_NAME, publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+
+    //then
+    assert_eq!(by_prefix.status(), Status::Ok);
+    assert_eq!(r#"["eu.eurgbp","eu.eurusd"]"#, by_prefix.body_string().unwrap());
+    assert_eq!(by_glob.status(), Status::Ok);
+    assert_eq!(r#"["us.usdjpy"]"#, by_glob.body_string().unwrap());
+    let mock = get_mock(&client);
+    let removed: Vec<String> = mock.remove_vec.read().unwrap().iter()
+        .map(|&(_, ref m)| m.subject.clone())
+        .collect();
+    assert_eq!(vec!["eu.eurgbp", "eu.eurusd", "us.usdjpy"], removed);
+}
+
+#[test]
+fn remove_subjects_requires_one_filter() {
+    //given
+    let publisher_id = "2b3c4d5e-6f7a-4b8c-9d0e-1f2a3b4c5d6e";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+
+    //when
+    let res = client.delete(format!("info/publish/{}/{}?prefix=eu&glob=us*", TOPIC_NAME, publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+#[test]
+fn merge_patch_retained_subject() {
+    //given
+    let publisher_id = "3c4d5e6f-7a8b-4c9d-8e0f-1a2b3c4d5e6f";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    let mut subscribed = client.get(format!("info/subscribe/{}", TOPIC_NAME))
+        .header(Header::new("Location", "http://subscriber2:9000"))
+        .header(Header::new("Delivery", "patch"))
+        .dispatch();
+    let subscriber_id = subscribed.body_string().unwrap();
+    client.head(format!("info/subscribe/{}", subscriber_id)).dispatch();
+    client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(ContentType::JSON)
+        .body(r#"{"bid":1.1,"ask":1.2}"#)
+        .dispatch();
+
+    //when
+    let patch = r#"{"ask":1.3,"bid":null,"mid":1.25}"#;
+    let res = client.patch(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(ContentType::new("application", "merge-patch+json"))
+        .body(patch)
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::Ok);
+    let mock = get_mock(&client);
+    let published = mock.pub_vec.read().unwrap();
+    assert_eq!(4, published.len());
+    let full = &published[2];
+    assert_eq!("http://subscriber1:9000", full.0);
+    assert_eq!(r#"{"ask":1.3,"mid":1.25}"#, String::from_utf8_lossy(&full.1.body));
+    let patched = &published[3];
+    assert_eq!("http://subscriber2:9000", patched.0);
+    assert_eq!(patch, String::from_utf8_lossy(&patched.1.body));
+    assert_eq!(Some("application/merge-patch+json".to_string()), patched.1.content_type);
+}
+
+#[test]
+fn merge_patch_requires_json_document() {
+    //given
+    let publisher_id = "4d5e6f7a-8b9c-4d0e-9f1a-2b3c4d5e6f7a";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    publish_message(&client, publisher_id, &token);
+
+    //when
+    let not_json = client.patch(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(r#"{"ask":1.3}"#)
+        .dispatch();
+    let unknown = client.patch(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "unknown"))
+        .header(publisher_token(&token))
+        .body(r#"{"ask":1.3}"#)
+        .dispatch();
+
+    //then
+    assert_eq!(not_json.status(), Status::UnprocessableEntity);
+    assert_eq!(unknown.status(), Status::NotFound);
+}
+
+#[test]
+fn topic_schema_validation() {
+    //given
+    let publisher_id = "5e6f7a8b-9c0d-4e1f-8a2b-3c4d5e6f7a8b";
+    let client = new_client();
+    let token = create_publisher(&client, publisher_id);
+    subscribe_and_touch(&client, TOPIC_NAME, "http://subscriber1:9000");
+    let mut v1 = client.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"type": "object", "required": ["price"], "properties": {"price": {"type": "number"}}}"#)
+        .dispatch();
+    assert_eq!("1", v1.body_string().unwrap());
+
+    //when
+    let valid = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s1"))
+        .header(publisher_token(&token))
+        .body(r#"{"price": 1.16}"#)
+        .dispatch();
+    let mut invalid = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s2"))
+        .header(publisher_token(&token))
+        .body(r#"{"price": "high"}"#)
+        .dispatch();
+    let not_json = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s3"))
+        .header(publisher_token(&token))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(valid.status(), Status::Ok);
+    assert_eq!(invalid.status(), Status::UnprocessableEntity);
+    let body = invalid.body_string().unwrap();
+    assert!(body.contains(r#""error":"schema_violation""#));
+    assert!(body.contains(r#""violations":["#));
+    assert_eq!(not_json.status(), Status::UnprocessableEntity);
+    assert_eq!(get_mock(&client).pub_vec.read().unwrap().len(), 1);
+
+    //when
+    let mut v2 = client.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"type": "object"}"#)
+        .dispatch();
+    assert_eq!("2", v2.body_string().unwrap());
+    let activated = client.put(format!("info/topic/{}/schema/1", TOPIC_NAME)).dispatch();
+    let mut active = client.get(format!("info/topic/{}/schema", TOPIC_NAME)).dispatch();
+    let deactivated = client.delete(format!("info/topic/{}/schema", TOPIC_NAME)).dispatch();
+    let after = client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s3"))
+        .header(publisher_token(&token))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(activated.status(), Status::Ok);
+    assert!(active.body_string().unwrap().contains(r#""version":1"#));
+    assert_eq!(deactivated.status(), Status::Ok);
+    assert_eq!(after.status(), Status::Ok);
+}
+
+#[test]
+fn invalid_topic_schema_is_rejected() {
+    //given
+    let client = new_client();
+
+    //when
+    let invalid = client.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"type": 42}"#)
+        .dispatch();
+    let unknown = client.get(format!("info/topic/{}/schema/7", TOPIC_NAME)).dispatch();
+
+    //then
+    assert_eq!(invalid.status(), Status::UnprocessableEntity);
+    assert_eq!(unknown.status(), Status::NotFound);
+}
+
+#[test]
+fn state_is_replayed_from_write_ahead_log() {
+    //given
+    let publisher_id = "6f7a8b9c-0d1e-4f2a-8b3c-4d5e6f7a8b9c";
+    let config = WalConfig {
+        path: env::temp_dir().join(format!("pubsub-{}.wal", uuid::Uuid::new_v4())),
+        fsync: FsyncPolicy::Always,
+        compact_after: 1000,
+    };
+    let client = new_client();
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.open_wal(config.clone()).unwrap();
+    }
+    let token = create_publisher(&client, publisher_id);
+    for subject in &["s1", "s2"] {
+        client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, subject))
+            .header(publisher_token(&token))
+            .body(MSG_BODY)
+            .dispatch();
+    }
+    client.delete(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s1"))
+        .header(publisher_token(&token))
+        .dispatch();
+    let mut scheduled = client.put(format!("info/schedule/{}/{}/{}", TOPIC_NAME, publisher_id, "s3"))
+        .header(publisher_token(&token))
+        .header(Header::new("Delay", "60"))
+        .body(MSG_BODY)
+        .dispatch();
+    let scheduled_id = scheduled.body_string().unwrap();
+    client.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .body(r#"{"type": "string"}"#)
+        .dispatch();
+
+    //when
+    let restarted = new_client();
+    {
+        let server: &PubSubServer = restarted.rocket().state().unwrap();
+        server.open_wal(config.clone()).unwrap();
+    }
+
+    //then
+    let existing = restarted.get(format!("info/publish/{}", publisher_id)).dispatch();
+    assert_eq!(existing.status(), Status::Conflict);
+    subscribe_and_touch(&restarted, TOPIC_NAME, "http://subscriber1:9000");
+    {
+        let published = get_mock(&restarted).pub_vec.read().unwrap();
+        assert_eq!(1, published.len());
+        assert_eq!("s2", published[0].1.subject);
+        assert_eq!(MSG_BODY.as_bytes(), &published[0].1.body[..]);
+    }
+    let schema = restarted.get(format!("info/topic/{}/schema", TOPIC_NAME)).dispatch();
+    assert_eq!(schema.status(), Status::Ok);
+    let mut listed = restarted.get(format!("info/schedule/{}", publisher_id))
+        .header(publisher_token(&token))
+        .dispatch();
+    assert!(listed.body_string().unwrap().contains(&scheduled_id));
+    let _ = fs::remove_file(&config.path);
+}
+
+#[test]
+fn snapshot_export_and_restore() {
+    //given
+    let publisher_id = "7a8b9c0d-1e2f-4a3b-9c4d-5e6f7a8b9c0d";
+    let client = new_client();
+    let key = admin_key(&client);
+    let token = create_publisher_with(&client, publisher_id, Some(&key));
+    publish_message_with(&client, publisher_id, &token, Some(&key));
+
+    //when
+    let mut json = client.get("info/admin/snapshot/json").header(bearer(&key)).dispatch();
+    let mut binary = client.get("info/admin/snapshot/binary").header(bearer(&key)).dispatch();
+
+    //then
+    assert_eq!(json.status(), Status::Ok);
+    assert!(json.body_string().unwrap().contains(publisher_id));
+    assert_eq!(binary.status(), Status::Ok);
+    let snapshot = Snapshot::decode(&binary.body_bytes().unwrap(), SnapshotFormat::Binary).unwrap();
+    assert_eq!(1, snapshot.messages.len());
+
+    //when
+    let restored = new_client();
+    {
+        let server: &PubSubServer = restored.rocket().state().unwrap();
+        server.restore(snapshot).unwrap();
+    }
+
+    //then
+    let existing = restored.get(format!("info/publish/{}", publisher_id)).dispatch();
+    assert_eq!(existing.status(), Status::Conflict);
+    subscribe_and_touch(&restored, TOPIC_NAME, "http://subscriber1:9000");
+    let published = get_mock(&restored).pub_vec.read().unwrap();
+    assert_eq!(1, published.len());
+    assert_eq!(SUBJECT_NAME, published[0].1.subject);
+}
+
+#[test]
+fn binary_snapshots_keep_topic_schemas() {
+    //given
+    let publisher_id = "7a8b9c0d-1e2f-4a3b-9c4d-5e6f7a8b9c0d";
+    let client = new_client();
+    let key = admin_key(&client);
+    client.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .header(bearer(&key))
+        .body(r#"{"type": "object", "required": ["price"], "properties": {"price": {"type": "number"}}}"#)
+        .dispatch();
+
+    //when
+    let mut binary = client.get("info/admin/snapshot/binary").header(bearer(&key)).dispatch();
+    let snapshot = Snapshot::decode(&binary.body_bytes().unwrap(), SnapshotFormat::Binary).unwrap();
+    let restored = new_client();
+    {
+        let server: &PubSubServer = restored.rocket().state().unwrap();
+        server.restore(snapshot).unwrap();
+    }
+    let token = create_publisher(&restored, publisher_id);
+    let invalid = restored.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(r#"{"price": "high"}"#)
+        .dispatch();
+
+    //then
+    assert_eq!(invalid.status(), Status::UnprocessableEntity);
+    let mut active = restored.get(format!("info/topic/{}/schema", TOPIC_NAME)).dispatch();
+    assert!(active.body_string().unwrap().contains(r#""version":1"#));
+}
+
+#[test]
+fn snapshot_is_written_on_the_server_only_into_snapshot_dir() {
+    //given
+    let client = new_client();
+    let key = admin_key(&client);
+
+    //when
+    let mut exported = client.post("info/admin/snapshot/state.json").header(bearer(&key)).dispatch();
+
+    //then
+    assert_eq!(exported.status(), Status::Conflict);
+    assert!(exported.body_string().unwrap().contains(r#""error":"snapshot_dir_disabled""#));
+}
+
+#[test]
+fn state_survives_restart_with_on_disk_storage() {
+    //given
+    let publisher_id = "8b9c0d1e-2f3a-4b4c-8d5e-6f7a8b9c0d1e";
+    let path = env::temp_dir().join(format!("pubsub-{}", uuid::Uuid::new_v4()));
+    {
+        let client = new_client_with_storage(Box::new(SledStorage::open(&path).unwrap()));
+        let token = create_publisher(&client, publisher_id);
+        for subject in &["s1", "s2"] {
+            client.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, subject))
+                .header(publisher_token(&token))
+                .body(MSG_BODY)
+                .dispatch();
+        }
+        client.delete(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, "s1"))
+            .header(publisher_token(&token))
+            .dispatch();
+    }
+
+    //when
+    let restarted = new_client_with_storage(Box::new(SledStorage::open(&path).unwrap()));
+
+    //then
+    let existing = restarted.get(format!("info/publish/{}", publisher_id)).dispatch();
+    assert_eq!(existing.status(), Status::Conflict);
+    subscribe_and_touch(&restarted, TOPIC_NAME, "http://subscriber1:9000");
+    {
+        let published = get_mock(&restarted).pub_vec.read().unwrap();
+        assert_eq!(1, published.len());
+        assert_eq!("s2", published[0].1.subject);
+        assert_eq!(MSG_BODY.as_bytes(), &published[0].1.body[..]);
+    }
+    drop(restarted);
+    let _ = fs::remove_dir_all(&path);
+}
+
+#[test]
+fn on_disk_storage_applies_ops_and_removes_by_id() {
+    //given
+    let path = env::temp_dir().join(format!("pubsub-{}", uuid::Uuid::new_v4()));
+    let storage = SledStorage::open(&path).unwrap();
+    let publisher = uuid::Uuid::new_v4();
+    let other = uuid::Uuid::new_v4();
+    let message = |publisher: uuid::Uuid, subject: &str| Message::new(publisher, TOPIC_NAME.to_string(),
+        subject.to_string(), HashMap::new(), MSG_BODY.as_bytes().to_vec());
+    storage.add_publisher(publisher, None).unwrap();
+    storage.add_publisher(other, None).unwrap();
+    let subscriber = Subscriber::new("http://subscriber1:9000".to_string(), TOPIC_NAME.to_string());
+    storage.add_pending_subscriber(subscriber.clone()).unwrap();
+
+    //when
+    let removed = storage.apply(vec![
+        MessageOp::Put(message(publisher, "s1")),
+        MessageOp::Put(message(publisher, "s2")),
+        MessageOp::Put(message(other, "s1")),
+        MessageOp::Remove { topic: TOPIC_NAME.to_string(), publisher, subject: "s2".to_string() },
+    ]).unwrap();
+    storage.activate_subscriber(&subscriber.id).unwrap();
+
+    //then
+    assert_eq!(1, removed.len());
+    assert_eq!(2, storage.subjects_count(&TOPIC_NAME.to_string()).unwrap());
+    assert!(storage.touch_publisher(&publisher).unwrap());
+    assert!(storage.is_active_subscriber(&subscriber.id).unwrap());
+
+    //when
+    let removed = storage.remove_publisher(&publisher).unwrap().unwrap();
+    let unsubscribed = storage.remove_subscriber(&subscriber.id).unwrap();
+
+    //then
+    assert_eq!(vec!["s1"], removed.iter().map(|m| m.subject.as_str()).collect::<Vec<&str>>());
+    assert_eq!(1, storage.subjects_count(&TOPIC_NAME.to_string()).unwrap());
+    assert!(!storage.touch_publisher(&publisher).unwrap());
+    assert!(unsubscribed);
+    assert!(!storage.is_active_subscriber(&subscriber.id).unwrap());
+    assert!(storage.topic_subscribers(&TOPIC_NAME.to_string()).unwrap().is_empty());
+    drop(storage);
+    let _ = fs::remove_dir_all(&path);
+}
+
+#[test]
+fn topic_configuration_survives_restart_with_on_disk_storage() {
+    //given
+    let publisher_id = "2d3e4f5a-6b7c-4d8e-9f0a-1b2c3d4e5f6a";
+    let other_id = "3e4f5a6b-7c8d-4e9f-8a1b-2c3d4e5f6a7b";
+    let path = env::temp_dir().join(format!("pubsub-{}", uuid::Uuid::new_v4()));
+    let (token, other_token) = {
+        let client = new_client_with_storage(Box::new(SledStorage::open(&path).unwrap()));
+        let key = admin_key(&client);
+        let token = create_publisher_with(&client, publisher_id, Some(&key));
+        let other_token = create_publisher_with(&client, other_id, Some(&key));
+        client.put(format!("info/topic/{}", TOPIC_NAME))
+            .header(ContentType::JSON)
+            .header(bearer(&key))
+            .body(format!(r#"{{"allowed_publishers": ["{}"]}}"#, publisher_id))
+            .dispatch();
+        client.put("info/topics/auto_create/false").header(bearer(&key)).dispatch();
+        (token, other_token)
+    };
+
+    //when
+    let restarted = new_client_with_storage(Box::new(SledStorage::open(&path).unwrap()));
+
+    //then
+    let not_allowed = restarted.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, other_id, SUBJECT_NAME))
+        .header(publisher_token(&other_token))
+        .body(MSG_BODY)
+        .dispatch();
+    assert_eq!(not_allowed.status(), Status::Forbidden);
+    let unknown_topic = restarted.put(format!("info/publish/{}/{}/{}", "other-topic", publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(MSG_BODY)
+        .dispatch();
+    assert_eq!(unknown_topic.status(), Status::NotFound);
+    drop(restarted);
+    let _ = fs::remove_dir_all(&path);
+}
+
+#[test]
+fn replica_follows_primary_until_promoted() {
+    //given
+    let publisher_id = "9c0d1e2f-3a4b-4c5d-9e6f-7a8b9c0d1e2f";
+    let replica = new_client();
+    let key = admin_key(&replica);
+    {
+        let server: &PubSubServer = replica.rocket().state().unwrap();
+        server.configure_replication(ReplicationConfig { role: Role::Replica, replicas: vec![], api_key: None });
+    }
+    let primary = new_primary_client(replica, &key);
+    let token = create_publisher(&primary, publisher_id);
+    for subject in &["s1", "s2"] {
+        primary.put(format!("info/publish/{}/{}/{}", TOPIC_NAME, publisher_id, subject))
+            .header(publisher_token(&token))
+            .body(MSG_BODY)
+            .dispatch();
+    }
+
+    //when
+    {
+        let server: &PubSubServer = primary.rocket().state().unwrap();
+        server.replicate();
+    }
+
+    //then
+    let replica = get_replica(&primary);
+    let mut status = replica.get("info/replication").header(bearer(&key)).dispatch();
+    assert!(status.body_string().unwrap().contains("\"role\":\"replica\""));
+    let rejected = replica.get(format!("info/publish/{}", "0d1e2f3a-4b5c-4d6e-8f7a-8b9c0d1e2f3a"))
+        .header(bearer(&key))
+        .dispatch();
+    assert_eq!(rejected.status(), Status::ServiceUnavailable);
+
+    //when
+    let promoted = replica.post("info/replication/promote").header(bearer(&key)).dispatch();
+
+    //then
+    assert_eq!(promoted.status(), Status::Ok);
+    let existing = replica.get(format!("info/publish/{}", publisher_id)).header(bearer(&key)).dispatch();
+    assert_eq!(existing.status(), Status::Conflict);
+    subscribe_and_touch_with(replica, TOPIC_NAME, "http://subscriber1:9000", Some(&key));
+    let published = get_mock(replica).pub_vec.read().unwrap();
+    assert_eq!(2, published.len());
+}
+
+#[test]
+fn primary_replicates_to_a_bound_replica_over_http() {
+    //given
+    let publisher_id = "4f5a6b7c-8d9e-4f0a-9b1c-2d3e4f5a6b7c";
+    let replica_key = "5a6b7c8d9e0f";
+    let replica = new_server(MockClock::new(), Box::new(MemoryStorage::new()));
+    replica.configure_replication(ReplicationConfig { role: Role::Replica, replicas: vec![], api_key: None });
+    let replica_url = launch_bound(replica, replica_key);
+    let primary = new_client();
+    let key = admin_key(&primary);
+    {
+        let server: &PubSubServer = primary.rocket().state().unwrap();
+        server.configure_replication(ReplicationConfig {
+            role: Role::Primary,
+            replicas: vec![replica_url.clone()],
+            api_key: Some(replica_key.to_string()),
+        });
+    }
+    let token = create_publisher_with(&primary, publisher_id, Some(&key));
+    publish_message_with(&primary, publisher_id, &token, Some(&key));
+    assert_eq!(404, http_status(&format!("{}topic/{}", replica_url, TOPIC_NAME), replica_key));
+
+    //when
+    {
+        let server: &PubSubServer = primary.rocket().state().unwrap();
+        server.replicate();
+    }
+
+    //then
+    assert_eq!(200, http_status(&format!("{}topic/{}", replica_url, TOPIC_NAME), replica_key));
+    let mut status = primary.get("info/replication").header(bearer(&key)).dispatch();
+    assert!(status.body_string().unwrap().contains(r#""synced":true"#));
+
+    //when
+    primary.put(format!("info/publish/{}/{}/{}", "other-topic", publisher_id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(bearer(&key))
+        .body(MSG_BODY)
+        .dispatch();
+    {
+        let server: &PubSubServer = primary.rocket().state().unwrap();
+        server.replicate();
+    }
+
+    //then
+    assert_eq!(200, http_status(&format!("{}topic/{}", replica_url, "other-topic"), replica_key));
+}
+
+#[test]
+fn replica_receives_topic_schemas() {
+    //given
+    let replica_key = "6b7c8d9e0f1a";
+    let replica = new_server(MockClock::new(), Box::new(MemoryStorage::new()));
+    replica.configure_replication(ReplicationConfig { role: Role::Replica, replicas: vec![], api_key: None });
+    let replica_url = launch_bound(replica, replica_key);
+    let primary = new_client();
+    let key = admin_key(&primary);
+    {
+        let server: &PubSubServer = primary.rocket().state().unwrap();
+        server.configure_replication(ReplicationConfig {
+            role: Role::Primary,
+            replicas: vec![replica_url.clone()],
+            api_key: Some(replica_key.to_string()),
+        });
+    }
+    let registered = primary.put(format!("info/topic/{}/schema", TOPIC_NAME))
+        .header(ContentType::JSON)
+        .header(bearer(&key))
+        .body(r#"{"type": "object", "required": ["price"]}"#)
+        .dispatch();
+    assert_eq!(registered.status(), Status::Ok);
+
+    //when
+    {
+        let server: &PubSubServer = primary.rocket().state().unwrap();
+        server.replicate();
+    }
+
+    //then
+    let mut status = primary.get("info/replication").header(bearer(&key)).dispatch();
+    assert!(status.body_string().unwrap().contains(r#""synced":true"#));
+    assert_eq!(200, http_status(&format!("{}topic/{}/schema", replica_url, TOPIC_NAME), replica_key));
+}
+
+#[test]
+fn bridge_republishes_remote_messages_without_loops() {
+    //given
+    let publisher_id = "0d1e2f3a-4b5c-4d6e-8f7a-8b9c0d1e2f3a";
+    let hub_a = new_federated_client(new_client());
+    subscribe_and_touch(&hub_a, TOPIC_NAME, "http://subscriber1:9000");
+
+    //when
+    {
+        let server: &PubSubServer = hub_a.rocket().state().unwrap();
+        server.tick();
+    }
+    let hub_b = get_remote(&hub_a);
+    let token = create_publisher(hub_b, publisher_id);
+    publish_message(hub_b, publisher_id, &token);
+
+    //then
+    {
+        let delivered = get_mock(hub_b).pub_vec.read().unwrap();
+        assert!(delivered.iter().any(|&(ref callback, _)| callback == "http://hub-a/info/bridge/hub-b/"));
+    }
+
+    //when
+    let received = hub_a.post(format!("info/bridge/hub-b/receive/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .body(MSG_BODY)
+        .dispatch();
+    let looped = hub_a.post(format!("info/bridge/hub-b/receive/{}/{}/{}", TOPIC_NAME, publisher_id, "s2"))
+        .header(Header::new("info-Federation-Origin", "hub-a"))
+        .body(MSG_BODY)
+        .dispatch();
+    let too_far = hub_a.post(format!("info/bridge/hub-b/receive/{}/{}/{}", TOPIC_NAME, publisher_id, "s3"))
+        .header(Header::new("info-Federation-Hops", "2"))
+        .body(MSG_BODY)
+        .dispatch();
+    let unknown = hub_a.post(format!("info/bridge/hub-c/receive/{}/{}/{}", TOPIC_NAME, publisher_id, "s4"))
+        .body(MSG_BODY)
+        .dispatch();
+
+    //then
+    assert_eq!(received.status(), Status::Ok);
+    assert_eq!(looped.status(), Status::Ok);
+    assert_eq!(too_far.status(), Status::Ok);
+    assert_eq!(unknown.status(), Status::NotFound);
+    let published = get_mock(&hub_a).pub_vec.read().unwrap();
+    let local: Vec<&Message> = published.iter()
+        .filter(|&&(ref callback, _)| callback == "http://subscriber1:9000")
+        .map(|&(_, ref m)| m)
+        .collect();
+    assert_eq!(1, local.len());
+    assert_eq!(SUBJECT_NAME, local[0].subject);
+    assert_eq!(Some(&"hub-b".to_string()), local[0].headers.get("Federation-Origin"));
+    assert_eq!(Some(&"1".to_string()), local[0].headers.get("Federation-Hops"));
+    // the outbound bridge forwards the republished message to hub-b with its route headers
+    assert!(published.iter().any(|&(ref callback, _)| callback == "http://hub-b/info/bridge/hub-a/"));
+}
+
+#[test]
+fn bridge_forwards_messages_to_a_bound_hub_over_http() {
+    //given
+    let publisher_id = "1e2f3a4b-5c6d-4e7f-8a9b-0c1d2e3f4a5b";
+    let hub_b_key = "7c8d9e0f1a2b";
+    let hub_b = new_server(MockClock::new(), Box::new(MemoryStorage::new()));
+    hub_b.configure_federation(FederationConfig {
+        instance: "hub-b".to_string(),
+        url: "http://hub-b/info/".to_string(),
+        max_hops: 2,
+        bridges: vec![Bridge {
+            name: "hub-a".to_string(),
+            url: "http://hub-a/info/".to_string(),
+            topics: vec![TOPIC_NAME.to_string()],
+            direction: BridgeDirection::Inbound,
+            api_key: None,
+        }],
+    });
+    let hub_b_url = launch_bound(hub_b, hub_b_key);
+    // hub-a delivers with the production subscriber service
+    let hub_a = Client::new(mount_routes(PubSubServer::new().with_clock(Box::new(MockClock::new()))))
+        .expect("valid rocket instance");
+    {
+        let server: &PubSubServer = hub_a.rocket().state().unwrap();
+        server.configure_federation(FederationConfig {
+            instance: "hub-a".to_string(),
+            url: "http://hub-a/info/".to_string(),
+            max_hops: 2,
+            bridges: vec![Bridge {
+                name: "hub-b".to_string(),
+                url: hub_b_url.clone(),
+                topics: vec![TOPIC_NAME.to_string()],
+                direction: BridgeDirection::Outbound,
+                api_key: Some(hub_b_key.to_string()),
+            }],
+        });
+        server.tick();
+    }
+    let token = create_publisher(&hub_a, publisher_id);
+    assert_eq!(404, http_status(&format!("{}topic/{}", hub_b_url, TOPIC_NAME), hub_b_key));
+
+    //when
+    publish_message(&hub_a, publisher_id, &token);
+
+    //then
+    assert_eq!(200, http_status(&format!("{}topic/{}", hub_b_url, TOPIC_NAME), hub_b_key));
+}
+
+#[test]
+fn federation_requires_absolute_urls() {
+    //given
+    let mut bridge = Table::new();
+    bridge.insert("name".to_string(), Value::from("hub-b"));
+    bridge.insert("url".to_string(), Value::from("info/"));
+    bridge.insert("topics".to_string(), Value::Array(vec![Value::from(TOPIC_NAME)]));
+    let federation = |url: &str, bridges: Vec<Value>| Config::build(Environment::Development)
+        .extra("federation_instance", "hub-a")
+        .extra("federation_url", url)
+        .extra("federation_bridges", bridges)
+        .finalize()
+        .unwrap();
+
+    //when
+    let relative_hub = fails_to_launch(federation("info/", vec![]));
+    let relative_bridge = fails_to_launch(federation("http://hub-a/info/", vec![Value::Table(bridge)]));
+    let absolute = fails_to_launch(federation("http://hub-a/info/", vec![]));
+
+    //then
+    assert!(relative_hub);
+    assert!(relative_bridge);
+    assert!(!absolute);
+}
+
+#[test]
+fn topics_of_other_nodes_are_redirected() {
+    //given
+    let client = new_client();
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    server.configure_cluster(ClusterConfig {
+        node: "node-a".to_string(),
+        nodes: vec![
+            Node { name: "node-a".to_string(), url: "http://127.0.0.1:8001".to_string() },
+            Node { name: "node-b".to_string(), url: "http://127.0.0.1:8002".to_string() },
+        ],
+        api_key: None,
+    }).unwrap();
+    let topics: Vec<String> = (0..100).map(|i| format!("topic{}", i)).collect();
+    let local = topics.iter().find(|t| server.topic_owner(t).is_none()).unwrap();
+    let remote = topics.iter().find(|t| server.topic_owner(t).is_some()).unwrap();
+
+    //when
+    let subscribed = client.get(format!("info/subscribe/{}", local))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .dispatch();
+    let redirected = client.get(format!("info/subscribe/{}", remote))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .dispatch();
+    let mut owner = client.get(format!("info/cluster/owner/{}", remote)).dispatch();
+
+    //then
+    assert_eq!(subscribed.status(), Status::Ok);
+    assert_eq!(redirected.status(), Status::TemporaryRedirect);
+    assert_eq!(Some(format!("http://127.0.0.1:8002/info/subscribe/{}", remote).as_str()),
+               redirected.headers().get_one("Location"));
+    assert!(owner.body_string().unwrap().contains("\"name\":\"node-b\""));
+}
+
+#[test]
+fn publishers_and_subscribers_work_across_nodes() {
+    //given
+    let node_b = new_node_client("node-b");
+    let key = admin_key(&node_b);
+    let client = new_clustered_client(node_b, &key);
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    let remote = get_node(&client);
+    let id = "355f2e4f-554b-47d7-aca8-122a6cec9f26";
+    let topics: Vec<String> = (0..100).map(|i| format!("topic{}", i)).collect();
+    let topic = topics.iter().find(|t| server.topic_owner(t).is_some()).unwrap();
+    let token = create_publisher(&client, id);
+
+    //when
+    let redirected = client.put(format!("info/publish/{}/{}/{}", topic, id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .body(MSG_BODY)
+        .dispatch();
+    let published = remote.put(format!("info/publish/{}/{}/{}", topic, id, SUBJECT_NAME))
+        .header(publisher_token(&token))
+        .header(bearer(&key))
+        .body(MSG_BODY)
+        .dispatch();
+    let mut subscribed = remote.get(format!("info/subscribe/{}", topic))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&key))
+        .dispatch();
+    let subscriber = subscribed.body_string().unwrap();
+    let touched_elsewhere = client.head(format!("info/subscribe/{}", subscriber)).dispatch();
+    let touched = remote.head(format!("info/subscribe/{}", subscriber)).header(bearer(&key)).dispatch();
+    remove_publisher(&client, id, &token);
+    let removed = remote.head(format!("info/publish/{}", id))
+        .header(publisher_token(&token))
+        .header(bearer(&key))
+        .dispatch();
+
+    //then
+    assert_eq!(redirected.status(), Status::TemporaryRedirect);
+    assert_eq!(published.status(), Status::Ok);
+    assert_eq!(touched_elsewhere.status(), Status::TemporaryRedirect);
+    assert_eq!(Some(format!("http://node-b/info/subscribe/{}", subscriber).as_str()),
+               touched_elsewhere.headers().get_one("Location"));
+    assert_eq!(touched.status(), Status::Ok);
+    assert_eq!(removed.status(), Status::NotFound);
+}
+
+#[test]
+fn api_keys_guard_routes() {
+    //given
+    let client = new_client();
+    let keys: &ApiKeys = client.rocket().state().unwrap();
+    let admin = keys.create("ops".to_string(), true).unwrap();
+    let mut created = client.post("info/admin/keys")
+        .header(ContentType::JSON)
+        .header(bearer(&admin.key))
+        .body(r#"{"name": "publisher1"}"#)
+        .dispatch();
+    let created: serde_json::Value = serde_json::from_str(&created.body_string().unwrap()).unwrap();
+    let publisher_key = created["key"].as_str().unwrap().to_string();
+
+    //when
+    let anonymous = client.get(format!("info/topic/{}", TOPIC_NAME)).dispatch();
+    let invalid = client.get(format!("info/topic/{}", TOPIC_NAME))
+        .header(bearer("invalid"))
+        .dispatch();
+    let subscribed = client.get(format!("info/subscribe/{}", TOPIC_NAME))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&publisher_key))
+        .dispatch();
+    let forbidden = client.get("info/admin/keys")
+        .header(bearer(&publisher_key))
+        .dispatch();
+    let revoked = client.delete("info/admin/keys/publisher1")
+        .header(bearer(&admin.key))
+        .dispatch();
+    let after_revoke = client.get(format!("info/topic/{}", TOPIC_NAME))
+        .header(bearer(&publisher_key))
+        .dispatch();
+
+    //then
+    assert_eq!(anonymous.status(), Status::Unauthorized);
+    assert_eq!(Some("Bearer"), anonymous.headers().get_one("WWW-Authenticate"));
+    assert_eq!(invalid.status(), Status::Unauthorized);
+    assert_eq!(subscribed.status(), Status::Ok);
+    assert_eq!(forbidden.status(), Status::Forbidden);
+    assert_eq!(revoked.status(), Status::Ok);
+    assert_eq!(after_revoke.status(), Status::Unauthorized);
+}
+
+#[test]
+fn admin_routes_are_closed_without_keys() {
+    //given
+    let client = new_client();
+
+    //when
+    let keys = client.get("info/admin/keys").dispatch();
+    let created = client.post("info/admin/keys")
+        .header(ContentType::JSON)
+        .body(r#"{"name": "ops", "admin": true}"#)
+        .dispatch();
+    let snapshot = client.put("info/replication/snapshot").body("").dispatch();
+    let subscribed = client.get(format!("info/subscribe/{}", TOPIC_NAME))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .dispatch();
+
+    //then
+    assert_eq!(keys.status(), Status::Forbidden);
+    assert_eq!(created.status(), Status::Forbidden);
+    assert_eq!(snapshot.status(), Status::Forbidden);
+    assert_eq!(subscribed.status(), Status::Ok);
+}
+
+#[test]
+fn api_keys_created_through_the_api_survive_restart() {
+    //given
+    let path = env::temp_dir().join(format!("pubsub-{}.keys", uuid::Uuid::new_v4()));
+    let keys = ApiKeys::new(vec![]).with_file(path.clone()).unwrap();
+    let admin = keys.create("ops".to_string(), true).unwrap();
+    let publisher = keys.create("publisher1".to_string(), false).unwrap();
+    keys.revoke("publisher1").unwrap();
+
+    //when
+    let restarted = ApiKeys::new(vec![]).with_file(path.clone()).unwrap();
+
+    //then
+    assert_eq!("ops", restarted.authenticate(&admin.key).unwrap().name);
+    assert!(restarted.authenticate(&publisher.key).is_err());
+    assert!(!fs::read_to_string(&path).unwrap().contains(&admin.key));
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn bridge_deliveries_need_the_key_of_the_bridge() {
+    //given
+    let publisher_id = "1e2f3a4b-5c6d-4e7f-8a9b-0c1d2e3f4a5b";
+    let hub_a = new_federated_client(new_client());
+    let hub_b_key;
+    let service_key;
+    {
+        let keys: &ApiKeys = hub_a.rocket().state().unwrap();
+        keys.create("ops".to_string(), true).unwrap();
+        hub_b_key = keys.create("hub-b".to_string(), false).unwrap().key;
+        service_key = keys.create("orders-service".to_string(), false).unwrap().key;
+    }
+
+    //when
+    let forbidden = hub_a.post(format!("info/bridge/hub-b/receive/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(bearer(&service_key))
+        .body(MSG_BODY)
+        .dispatch();
+    let received = hub_a.post(format!("info/bridge/hub-b/receive/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(bearer(&hub_b_key))
+        .body(MSG_BODY)
+        .dispatch();
+    let removed = hub_a.delete(format!("info/bridge/hub-b/remove/{}/{}/{}", TOPIC_NAME, publisher_id, SUBJECT_NAME))
+        .header(bearer(&service_key))
+        .dispatch();
+
+    //then
+    assert_eq!(forbidden.status(), Status::Forbidden);
+    assert_eq!(received.status(), Status::Ok);
+    assert_eq!(removed.status(), Status::Forbidden);
+}
+
+#[test]
+fn acl_restricts_topics_of_principals() {
+    //given
+    let client = new_client();
+    let keys: &ApiKeys = client.rocket().state().unwrap();
+    keys.create("ops".to_string(), true).unwrap();
+    let orders = keys.create("orders-service".to_string(), false).unwrap();
+    let acl: &Acl = client.rocket().state().unwrap();
+    acl.configure(vec![
+        AclRule { principal: "orders-*".to_string(), topics: "orders.*".to_string(), actions: vec![Action::Subscribe] },
+        AclRule { principal: "*".to_string(), topics: "public".to_string(), actions: vec![Action::Read] },
+    ]);
+
+    //when
+    let allowed = client.get("info/subscribe/orders.created")
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&orders.key))
+        .dispatch();
+    let other_topic = client.get("info/subscribe/payments")
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&orders.key))
+        .dispatch();
+    let other_action = client.delete("info/topic/orders.created")
+        .header(bearer(&orders.key))
+        .dispatch();
+    let public = client.get("info/topic/public")
+        .header(bearer(&orders.key))
+        .dispatch();
+
+    //then
+    assert_eq!(allowed.status(), Status::Ok);
+    assert_eq!(other_topic.status(), Status::Forbidden);
+    assert_eq!(other_action.status(), Status::Forbidden);
+    assert_ne!(public.status(), Status::Forbidden);
+}
+
+#[test]
+fn acl_guards_calls_by_subscriber_and_publisher_id() {
+    //given
+    let publisher_id = "6b7c8d9e-0f1a-4b2c-9d3e-4f5a6b7c8d9e";
+    let client = new_client();
+    let keys: &ApiKeys = client.rocket().state().unwrap();
+    let ops = keys.create("ops".to_string(), true).unwrap();
+    let orders = keys.create("orders-service".to_string(), false).unwrap();
+    let acl: &Acl = client.rocket().state().unwrap();
+    acl.configure(vec![
+        AclRule { principal: "orders-*".to_string(), topics: "orders.*".to_string(), actions: vec![Action::Subscribe, Action::Publish] },
+    ]);
+    let subscriber = subscribe_and_touch_with(&client, "payments", "http://subscriber1:9000", Some(&ops.key));
+    let token = create_publisher_with(&client, publisher_id, Some(&ops.key));
+    publish_message_with(&client, publisher_id, &token, Some(&ops.key));
+
+    //when
+    let touched = client.head(format!("info/subscribe/{}", subscriber))
+        .header(bearer(&orders.key))
+        .dispatch();
+    let unsubscribed = client.delete(format!("info/subscribe/{}", subscriber))
+        .header(bearer(&orders.key))
+        .dispatch();
+    let publisher_touched = client.head(format!("info/publish/{}", publisher_id))
+        .header(publisher_token(&token))
+        .header(bearer(&orders.key))
+        .dispatch();
+    let publisher_removed = client.delete(format!("info/publish/{}", publisher_id))
+        .header(publisher_token(&token))
+        .header(bearer(&orders.key))
+        .dispatch();
+    let owner_touched = client.head(format!("info/publish/{}", publisher_id))
+        .header(publisher_token(&token))
+        .header(bearer(&ops.key))
+        .dispatch();
+
+    //then
+    assert_eq!(touched.status(), Status::Forbidden);
+    assert_eq!(unsubscribed.status(), Status::Forbidden);
+    assert_eq!(publisher_touched.status(), Status::Forbidden);
+    assert_eq!(publisher_removed.status(), Status::Forbidden);
+    assert_eq!(owner_touched.status(), Status::Ok);
+}
+
+#[test]
+fn jwt_claims_drive_principal() {
+    //given
+    let client = new_client();
+    let jwt_keys: &JwtKeys = client.rocket().state().unwrap();
+    jwt_keys.configure(JwtConfig {
+        keys: vec![JwtKey {
+            kid: "platform".to_string(),
+            algorithm: "HS256".to_string(),
+            secret: Some("platform-secret".to_string()),
+            public_key: None,
+        }],
+        issuer: Some("platform".to_string()),
+        audience: None,
+    }).unwrap();
+    let exp = Utc::now().timestamp() + 3600;
+    let service = jwt("platform-secret", json!({
+        "sub": "orders-service", "iss": "platform", "exp": exp,
+        "topics": [{"topics": "orders.*", "actions": ["subscribe"]}]
+    }));
+    let operator = jwt("platform-secret", json!({"sub": "ops", "iss": "platform", "exp": exp, "roles": ["admin"]}));
+    let expired_token = jwt("platform-secret", json!({"sub": "ops", "iss": "platform", "exp": exp - 7200}));
+    let forged_token = jwt("other-secret", json!({"sub": "ops", "iss": "platform", "exp": exp, "roles": ["admin"]}));
+
+    //when
+    let allowed = client.get("info/subscribe/orders.created")
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&service))
+        .dispatch();
+    let other_topic = client.get("info/subscribe/payments")
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&service))
+        .dispatch();
+    let not_admin = client.get("info/replication").header(bearer(&service)).dispatch();
+    let admin = client.get("info/replication").header(bearer(&operator)).dispatch();
+    let expired = client.get("info/replication").header(bearer(&expired_token)).dispatch();
+    let forged = client.get("info/replication").header(bearer(&forged_token)).dispatch();
+
+    //then
+    assert_eq!(allowed.status(), Status::Ok);
+    assert_eq!(other_topic.status(), Status::Forbidden);
+    assert_eq!(not_admin.status(), Status::Forbidden);
+    assert_eq!(admin.status(), Status::Ok);
+    assert_eq!(expired.status(), Status::Unauthorized);
+    assert_eq!(forged.status(), Status::Unauthorized);
+}
+
+#[test]
+fn audit_log_records_security_events() {
+    //given
+    let path = env::temp_dir().join(format!("pubsub-{}.audit", uuid::Uuid::new_v4()));
+    let client = new_client();
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_audit(AuditConfig { path: path.clone() }).unwrap();
+    }
+    let keys: &ApiKeys = client.rocket().state().unwrap();
+    let admin = keys.create("ops".to_string(), true).unwrap();
+    let mut subscribed = client.get(format!("info/subscribe/{}", TOPIC_NAME))
+        .header(Header::new("Location", "http://subscriber1:9000"))
+        .header(bearer(&admin.key))
+        .remote("10.1.2.3:40000".parse().unwrap())
+        .dispatch();
+    let subscriber_id = subscribed.body_string().unwrap();
+    client.delete(format!("info/subscribe/{}", subscriber_id))
+        .header(bearer(&admin.key))
+        .dispatch();
+    client.get(format!("info/topic/{}", TOPIC_NAME))
+        .header(bearer("invalid"))
+        .dispatch();
+
+    //when
+    let mut all = client.get("info/admin/audit").header(bearer(&admin.key)).dispatch();
+    let mut subscriptions = client.get("info/admin/audit?action=subscribe&principal=ops")
+        .header(bearer(&admin.key))
+        .dispatch();
+    let invalid_query = client.get("info/admin/audit?action=unknown")
+        .header(bearer(&admin.key))
+        .dispatch();
+
+    //then
+    assert_eq!(all.status(), Status::Ok);
+    let all: serde_json::Value = serde_json::from_str(&all.body_string().unwrap()).unwrap();
+    let actions: Vec<&str> = all.as_array().unwrap().iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(vec!["subscribe", "unsubscribe", "auth_failure"], actions);
+    assert_eq!(subscriber_id, all[1]["target"]);
+    let subscriptions: serde_json::Value = serde_json::from_str(&subscriptions.body_string().unwrap()).unwrap();
+    assert_eq!(1, subscriptions.as_array().unwrap().len());
+    assert_eq!("ops", subscriptions[0]["principal"]);
+    assert_eq!("10.1.2.3", subscriptions[0]["source"]);
+    assert_eq!(invalid_query.status(), Status::BadRequest);
+    assert_eq!(3, fs::read_to_string(&path).unwrap().lines().count());
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn failed_authentication_is_audited_once() {
+    //given
+    let client = new_client();
+    let keys: &ApiKeys = client.rocket().state().unwrap();
+    let admin = keys.create("ops".to_string(), true).unwrap();
+
+    //when
+    let res = client.get("info/admin/audit?action=auth_failure")
+        .header(bearer("invalid"))
+        .dispatch();
+
+    //then
+    assert_eq!(res.status(), Status::Unauthorized);
+    let mut failures = client.get("info/admin/audit?action=auth_failure")
+        .header(bearer(&admin.key))
+        .dispatch();
+    let failures: serde_json::Value = serde_json::from_str(&failures.body_string().unwrap()).unwrap();
+    assert_eq!(1, failures.as_array().unwrap().len());
+}
+
+fn published_messages(batch: &Batch) -> Vec<&Message> {
+    batch.entries.iter()
+        .filter_map(|e| match e {
+            BatchEntry::Publish(m) => Some(m),
+            BatchEntry::Remove(_) => None
+        })
+        .collect()
+}
+
+fn subscribe_and_touch(client: &Client, topic: &str, location: &str) -> String {
+    subscribe_and_touch_with(client, topic, location, None)
+}
+
+fn subscribe_and_touch_with(client: &Client, topic: &str, location: &str, key: Option<&str>) -> String {
+    let mut subscribed = authorized(client.get(format!("info/subscribe/{}", topic)), key)
+        .header(Header::new("Location", location.to_string()))
+        .dispatch();
+    let subscriber_id = subscribed.body_string().unwrap();
+
+    let touched = authorized(client.head(format!("info/subscribe/{}", subscriber_id)), key)
+        .dispatch();
+    assert_eq!(touched.status(), Status::Ok);
+    subscriber_id
+}
+
+fn jwt(secret: &str, claims: serde_json::Value) -> String {
+    let mut header = jsonwebtoken::Header::new(Algorithm::HS256);
+    header.kid = Some("platform".to_string());
+    jsonwebtoken::encode(&header, &claims, secret.as_bytes()).unwrap()
+}
+
+fn bearer(key: &str) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", key))
+}
+
+fn authorized<'c>(mut req: LocalRequest<'c>, key: Option<&str>) -> LocalRequest<'c> {
+    if let Some(key) = key {
+        req.add_header(bearer(key));
+    }
+    req
+}
+
+// admin_key - enables authentication of the client, administrative endpoints need the key
+fn admin_key(client: &Client) -> String {
+    let keys: &ApiKeys = client.rocket().state().unwrap();
+    keys.create("ops".to_string(), true).unwrap().key
+}
+
+fn get_mock(client: &Client) -> &MockSubscribers {
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    let mock = server.subs_service.downcast_ref::<MockSubscribers>();
+    assert_eq!(mock.is_some(), true, "Failed to downcast");
+    mock.unwrap()
+}
+
+fn new_client() -> Client {
+    new_client_with_clock(MockClock::new())
+}
+
+fn new_client_with_clock(clock: MockClock) -> Client {
+    new_client_with(clock, Box::new(MemoryStorage::new()))
+}
+
+fn new_client_with_storage(storage: Box<Storage + 'static>) -> Client {
+    new_client_with(MockClock::new(), storage)
+}
+
+fn new_client_with(clock: MockClock, storage: Box<Storage + 'static>) -> Client {
+    let rocket = mount_routes(new_server(clock, storage));
+    trusting_test_hosts(Client::new(rocket).expect("valid rocket instance"))
+}
+
+// trusting_test_hosts - test callback hosts don't resolve, they are allowed so subscribers can be
+// added
+fn trusting_test_hosts(client: Client) -> Client {
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_callbacks(test_callback_policy());
+    }
+    client
+}
+
+fn test_callback_policy() -> CallbackPolicy {
+    CallbackPolicy { allowed_hosts: vec!["subscriber*".to_string(), "hub-*".to_string()], ..CallbackPolicy::default() }
+}
+
+// new_primary_client - instance replicating to the replica client with its admin key, replica URL
+// is "info/"
+fn new_primary_client(replica: Client, key: &str) -> Client {
+    let server = new_server(MockClock::new(), Box::new(MemoryStorage::new()))
+        .with_replicas(Box::new(LocalReplicas { replica }));
+    let client = trusting_test_hosts(Client::new(mount_routes(server)).expect("valid rocket instance"));
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_replication(ReplicationConfig { role: Role::Primary, replicas: vec!["info/".to_string()], api_key: Some(key.to_string()) });
+    }
+    client
+}
+
+// new_federated_client - hub-a bridging TOPIC_NAME in both directions with the remote client as
+// hub-b, hub-b URL is "http://hub-b/info/"
+fn new_federated_client(remote: Client) -> Client {
+    let server = new_server(MockClock::new(), Box::new(MemoryStorage::new()))
+        .with_hubs(Box::new(LocalHubs { remote }));
+    let client = trusting_test_hosts(Client::new(mount_routes(server)).expect("valid rocket instance"));
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_federation(FederationConfig {
+            instance: "hub-a".to_string(),
+            url: "http://hub-a/info/".to_string(),
+            max_hops: 2,
+            bridges: vec![Bridge {
+                name: "hub-b".to_string(),
+                url: "http://hub-b/info/".to_string(),
+                topics: vec![TOPIC_NAME.to_string()],
+                direction: BridgeDirection::Both,
+                api_key: None,
+            }],
+        });
+    }
+    client
+}
+
+// new_node_client - the node of a two node cluster of node-a and node-b
+fn new_node_client(node: &str) -> Client {
+    let client = new_client();
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_cluster(cluster_of(node)).unwrap();
+    }
+    client
+}
+
+// new_clustered_client - node-a of a cluster with the remote client as node-b at http://node-b,
+// calling node-b with its admin key
+fn new_clustered_client(remote: Client, key: &str) -> Client {
+    let server = new_server(MockClock::new(), Box::new(MemoryStorage::new()))
+        .with_nodes(Box::new(LocalNodes { remote }));
+    let client = trusting_test_hosts(Client::new(mount_routes(server)).expect("valid rocket instance"));
+    {
+        let server: &PubSubServer = client.rocket().state().unwrap();
+        server.configure_cluster(ClusterConfig { api_key: Some(key.to_string()), ..cluster_of("node-a") }).unwrap();
+    }
+    client
+}
+
+fn cluster_of(node: &str) -> ClusterConfig {
+    ClusterConfig {
+        node: node.to_string(),
+        nodes: vec![
+            Node { name: "node-a".to_string(), url: "http://node-a".to_string() },
+            Node { name: "node-b".to_string(), url: "http://node-b".to_string() },
+        ],
+        api_key: None,
+    }
+}
+
+fn get_node(client: &Client) -> &Client {
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    let nodes = server.nodes_service.downcast_ref::<LocalNodes>();
+    assert_eq!(nodes.is_some(), true, "Failed to downcast");
+    &nodes.unwrap().remote
+}
+
+fn get_remote(client: &Client) -> &Client {
+    let server: &PubSubServer = client.rocket().state().unwrap();
+    let hubs = server.hubs_service.downcast_ref::<LocalHubs>();
+    assert_eq!(hubs.is_some(), true, "Failed to downcast");
+    &hubs.unwrap().remote
+}
+
+fn new_server(clock: MockClock, storage: Box<Storage + 'static>) -> PubSubServer {
+    PubSubServer::with_service(Box::new(
+        MockSubscribers {
+            pub_vec: RwLock::new(Vec::new()),
+            remove_vec: RwLock::new(Vec::new()),
+            batch_vec: RwLock::new(Vec::new()),
+        })
+    ).with_clock(Box::new(clock))
+        .with_storage(storage)
+}
+
+// launch_bound - serves the server on a free local port in the background, returns its base URL
+fn launch_bound(server: PubSubServer, admin_key: &str) -> String {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut key = Table::new();
+    key.insert("name".to_string(), Value::from("ops"));
+    key.insert("key".to_string(), Value::from(admin_key));
+    key.insert("admin".to_string(), Value::from(true));
+    thread::spawn(move || {
+        let config = Config::build(Environment::Development)
+            .address("127.0.0.1")
+            .port(port)
+            .extra("api_keys", Value::Array(vec![Value::Table(key)]))
+            .finalize()
+            .unwrap();
+        mount_routes_with_config(server, config).launch();
+    });
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    format!("http://127.0.0.1:{}/info/", port)
+}
+
+// fails_to_launch - whether a fairing rejects the configuration, the launch error is handled
+fn fails_to_launch(config: Config) -> bool {
+    let rocket = mount_routes_with_config(new_server(MockClock::new(), Box::new(MemoryStorage::new())), config);
+    match Client::new(rocket) {
+        Ok(_) => false,
+        Err(e) => {
+            println!("launch failed: {}", e.kind());
+            true
+        }
+    }
+}
+
+fn http_status(url: &str, key: &str) -> u16 {
+    let mut headers = hyper::header::Headers::new();
+    headers.set_raw("Authorization", vec![format!("Bearer {}", key).into_bytes()]);
+    hyper::Client::new().get(url).headers(headers).send().unwrap().status.to_u16()
+}
+
+fn get_replica(primary: &Client) -> &Client {
+    let server: &PubSubServer = primary.rocket().state().unwrap();
+    let replicas = server.replicas_service.downcast_ref::<LocalReplicas>();
+    assert_eq!(replicas.is_some(), true, "Failed to downcast");
+    &replicas.unwrap().replica
+}
+
+#[derive(Clone)]
+struct MockClock {
+    now: Arc<RwLock<DateTime<Local>>>,
+}
+
+impl MockClock {
+    fn new() -> Self {
+        MockClock { now: Arc::new(RwLock::new(Local::now())) }
+    }
+
+    fn advance(&self, seconds: i64) {
+        let mut now = self.now.write().unwrap();
+        *now = *now + Duration::seconds(seconds);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.read().unwrap()
+    }
+}
+
+struct MockSubscribers {
+    pub_vec: RwLock<Vec<(String, Message)>>,
+    remove_vec: RwLock<Vec<(String, Message)>>,
+    batch_vec: RwLock<Vec<(String, Batch)>>,
+}
+
+impl Subscribers for MockSubscribers {
+    fn publish_message(&self, callback: &String, _address: Option<&IpAddr>, _api_key: Option<&String>,
+                       msg: &Message) -> Result<&str, CodeReason> {
+        println!("test publish_message ==== ");
+        self.pub_vec.write().unwrap().push((callback.clone(), msg.clone()));
+        Ok("ok")
+    }
+
+    fn remove_message(&self, callback: &String, _address: Option<&IpAddr>, _api_key: Option<&String>,
+                      msg: &Message) -> Result<&str, CodeReason> {
+        println!("test remove_message ==== ");
+        self.remove_vec.write().unwrap().push((callback.clone(), msg.clone()));
+        Ok("ok")
+    }
+
+    fn deliver_batch(&self, callback: &String, _address: Option<&IpAddr>, _api_key: Option<&String>,
+                     batch: &Batch) -> Result<&str, CodeReason> {
+        println!("test deliver_batch ==== ");
+        self.batch_vec.write().unwrap().push((callback.clone(), batch.clone()));
+        Ok("ok")
+    }
+}
+
+// LocalReplicas - sends replication requests to the routes of an in-process replica
+struct LocalReplicas {
+    replica: Client,
+}
+
+impl Replicas for LocalReplicas {
+    fn send_snapshot(&self, replica: &String, api_key: Option<&String>, snapshot: &Snapshot) -> Result<(), String> {
+        let res = authorized(self.replica.put(format!("{}replication/snapshot", replica)), api_key.map(|k| k.as_str()))
+            .body(snapshot.encode(replication::SNAPSHOT_FORMAT)?)
+            .dispatch();
+        match res.status() {
+            Status::Ok => Ok(()),
+            s => Err(format!("{}", s))
+        }
+    }
+
+    fn send_entries(&self, replica: &String, api_key: Option<&String>, entries: &[WalEntry]) -> Result<(), String> {
+        let res = authorized(self.replica.post(format!("{}replication/entries", replica)), api_key.map(|k| k.as_str()))
+            .header(ContentType::JSON)
+            .body(serde_json::to_vec(entries).unwrap())
+            .dispatch();
+        match res.status() {
+            Status::Ok => Ok(()),
+            s => Err(format!("{}", s))
+        }
+    }
+}
+
+// LocalNodes - sends publisher registrations to the routes of an in-process node-b
+struct LocalNodes {
+    remote: Client,
+}
+
+impl Nodes for LocalNodes {
+    fn add_publisher(&self, _node: &Node, api_key: Option<&String>, id: &uuid::Uuid, token: &String) -> Result<(), String> {
+        match authorized(self.remote.put(format!("info/cluster/publisher/{}", id.hyphenated())), api_key.map(|k| k.as_str()))
+            .header(publisher_token(token))
+            .dispatch()
+            .status() {
+            Status::Ok => Ok(()),
+            s => Err(format!("{}", s))
+        }
+    }
+
+    fn remove_publisher(&self, _node: &Node, api_key: Option<&String>, id: &uuid::Uuid) -> Result<(), String> {
+        match authorized(self.remote.delete(format!("info/cluster/publisher/{}", id.hyphenated())), api_key.map(|k| k.as_str()))
+            .dispatch()
+            .status() {
+            Status::Ok => Ok(()),
+            s => Err(format!("{}", s))
+        }
+    }
+
+    fn clear_publisher_token(&self, _node: &Node, api_key: Option<&String>, id: &uuid::Uuid) -> Result<(), String> {
+        match authorized(self.remote.delete(format!("info/cluster/publisher/{}/token", id.hyphenated())), api_key.map(|k| k.as_str()))
+            .dispatch()
+            .status() {
+            Status::Ok => Ok(()),
+            s => Err(format!("{}", s))
+        }
+    }
+}
+
+// LocalHubs - sends subscribe requests to the routes of an in-process remote hub at http://hub-b/
+struct LocalHubs {
+    remote: Client,
+}
+
+fn local_path(url: &String) -> &str {
+    url.trim_left_matches("http://hub-b/")
+}
+
+impl Hubs for LocalHubs {
+    fn subscribe(&self, hub: &String, _api_key: Option<&String>, topic: &String, callback: &String) -> Result<String, String> {
+        let mut res = self.remote.get(format!("{}subscribe/{}", local_path(hub), topic))
+            .header(Header::new("Location", callback.clone()))
+            .dispatch();
+        match res.status() {
+            Status::Ok => res.body_string().ok_or("no subscription id".to_string()),
+            s => Err(format!("{}", s))
+        }
+    }
+
+    fn touch_subscriber(&self, hub: &String, _api_key: Option<&String>, id: &String) -> Result<(), String> {
+        match self.remote.head(format!("{}subscribe/{}", local_path(hub), id)).dispatch().status() {
+            Status::Ok => Ok(()),
+            s => Err(format!("{}", s))
+        }
+    }
+}